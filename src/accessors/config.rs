@@ -0,0 +1,220 @@
+use std::{fmt, io, path::PathBuf, sync::Arc, thread, time::Duration};
+use crate::util::{NCDAccessError, NCDAccessErrorKind};
+
+/* Settings shared by the HTTP accessors. Not every accessor supports every setting. */
+#[derive(Clone)]
+pub struct NCDHttpConfig {
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    low_speed_limit: u32,
+    low_speed_time: Duration,
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    retry_codes: Vec<u32>,
+    headers: Vec<(String,String)>,
+    user_agent: Option<String>,
+    basic_auth: Option<(String,String)>,
+    bearer_token: Option<NCDTokenSource>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    client_key_password: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    proxy: Option<String>
+}
+
+/* Called before each request so that short-lived tokens can be refreshed */
+#[derive(Clone)]
+pub struct NCDTokenSource(Arc<dyn Fn() -> io::Result<String> + Send + Sync>);
+
+impl NCDTokenSource {
+    pub fn new<F>(callback: F) -> NCDTokenSource where F: Fn() -> io::Result<String> + Send + Sync + 'static {
+        NCDTokenSource(Arc::new(callback))
+    }
+
+    pub fn fixed(token: &str) -> NCDTokenSource {
+        let token = token.to_string();
+        NCDTokenSource::new(move || Ok(token.clone()))
+    }
+
+    fn token(&self) -> io::Result<String> { (self.0)() }
+}
+
+impl fmt::Debug for NCDTokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"NCDTokenSource")
+    }
+}
+
+/* Outcome of a single attempt at a range read. The flag says whether a transport error is worth retrying. */
+pub(crate) type AttemptResult = Result<Vec<u8>,(NCDAccessErrorKind,bool)>;
+
+impl Default for NCDHttpConfig {
+    fn default() -> NCDHttpConfig { NCDHttpConfig::new() }
+}
+
+impl NCDHttpConfig {
+    pub fn new() -> NCDHttpConfig {
+        NCDHttpConfig {
+            connect_timeout: Duration::new(2,0),
+            timeout: Some(Duration::new(60,0)),
+            low_speed_limit: 1024,
+            low_speed_time: Duration::new(10,0),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::new(5,0),
+            retry_codes: vec![408,429,500,502,503,504],
+            headers: vec![],
+            user_agent: None,
+            basic_auth: None,
+            bearer_token: None,
+            client_cert: None,
+            client_key: None,
+            client_key_password: None,
+            ca_bundle: None,
+            proxy: None
+        }
+    }
+
+    /* headers sent with every request, other than those controlling the range */
+    pub(crate) fn request_headers(&self) -> io::Result<Vec<(String,String)>> {
+        let mut out = self.headers.clone();
+        if let Some(token) = &self.bearer_token {
+            out.push(("Authorization".to_string(),format!("Bearer {}",token.token()?)));
+        }
+        Ok(out)
+    }
+
+    /* exponential, capped at max_backoff, with jitter in the upper half to avoid thundering herds */
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        delay.mul_f64(0.5+rand::random::<f64>()/2.)
+    }
+
+    fn retryable(&self, kind: &NCDAccessErrorKind, transient: bool) -> bool {
+        match kind {
+            NCDAccessErrorKind::HttpStatus(code) => self.retry_codes.contains(code),
+            NCDAccessErrorKind::ShortRead(_,_) => true,
+            NCDAccessErrorKind::Transport(_) => transient,
+            _ => false
+        }
+    }
+
+    /* Range reads are idempotent, so are safe to retry. Errors from the closure itself are not retried. */
+    pub(crate) fn with_retries<F>(&self, mut attempt: F) -> io::Result<Vec<u8>> where F: FnMut() -> io::Result<AttemptResult> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (kind,transient) = match attempt()? {
+                Ok(data) => { return Ok(data); },
+                Err(e) => e
+            };
+            if attempts > self.retries || !self.retryable(&kind,transient) {
+                return Err(NCDAccessError::new(kind,attempts).into());
+            }
+            thread::sleep(self.backoff_delay(attempts));
+        }
+    }
+
+    chain!(connect_timeout,get_connect_timeout,Duration,NCDHttpConfig);
+    chain!(timeout,get_timeout,Option<Duration>,NCDHttpConfig);
+    chain!(low_speed_limit,get_low_speed_limit,u32,NCDHttpConfig);
+    chain!(low_speed_time,get_low_speed_time,Duration,NCDHttpConfig);
+    chain!(retries,get_retries,u32,NCDHttpConfig);
+    chain!(backoff,get_backoff,Duration,NCDHttpConfig);
+    chain!(max_backoff,get_max_backoff,Duration,NCDHttpConfig);
+    chain!(retry_codes,get_retry_codes,Vec<u32>,NCDHttpConfig);
+    chain!(headers,get_headers,Vec<(String,String)>,NCDHttpConfig);
+    chain!(user_agent,get_user_agent,Option<String>,NCDHttpConfig);
+    chain!(basic_auth,get_basic_auth,Option<(String,String)>,NCDHttpConfig);
+    chain!(bearer_token,get_bearer_token,Option<NCDTokenSource>,NCDHttpConfig);
+    chain!(client_cert,get_client_cert,Option<PathBuf>,NCDHttpConfig);
+    chain!(client_key,get_client_key,Option<PathBuf>,NCDHttpConfig);
+    chain!(client_key_password,get_client_key_password,Option<String>,NCDHttpConfig);
+    chain!(ca_bundle,get_ca_bundle,Option<PathBuf>,NCDHttpConfig);
+    chain!(proxy,get_proxy,Option<String>,NCDHttpConfig);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, time::Duration};
+
+    use crate::{NCDAccessError, NCDAccessErrorKind};
+
+    use super::{NCDHttpConfig, NCDTokenSource};
+
+    #[test]
+    fn test_backoff() {
+        let config = NCDHttpConfig::new().backoff(Duration::from_millis(100)).max_backoff(Duration::from_millis(1000));
+        for (attempt,max) in [(1,100),(2,200),(3,400),(4,800),(5,1000),(20,1000)] {
+            for _ in 0..10 {
+                let delay = config.backoff_delay(attempt);
+                assert!(delay <= Duration::from_millis(max));
+                assert!(delay >= Duration::from_millis(max/2));
+            }
+        }
+        let config = config.backoff(Duration::from_millis(0));
+        assert_eq!(Duration::from_millis(0),config.backoff_delay(3));
+    }
+
+    fn do_test_request_headers() -> Result<(),io::Error> {
+        let config = NCDHttpConfig::new()
+            .headers(vec![("X-Test".to_string(),"yes".to_string())])
+            .bearer_token(Some(NCDTokenSource::fixed("secret")));
+        let headers = config.request_headers()?;
+        assert_eq!(vec![
+            ("X-Test".to_string(),"yes".to_string()),
+            ("Authorization".to_string(),"Bearer secret".to_string())
+        ],headers);
+        let config = config.bearer_token(Some(NCDTokenSource::new(|| Err(io::Error::other("expired")))));
+        assert!(config.request_headers().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_headers() {
+        do_test_request_headers().unwrap();
+    }
+
+    fn retry_run(config: &NCDHttpConfig, failures: &[(NCDAccessErrorKind,bool)]) -> (io::Result<Vec<u8>>,usize) {
+        let mut calls = 0;
+        let out = config.with_retries(|| {
+            calls += 1;
+            Ok(match failures.get(calls-1) {
+                Some(failure) => Err(failure.clone()),
+                None => Ok(vec![1,2,3])
+            })
+        });
+        (out,calls)
+    }
+
+    fn attempts(result: &io::Result<Vec<u8>>) -> u32 {
+        let error = result.as_ref().err().unwrap();
+        NCDAccessError::from_io_error(error).unwrap().attempts()
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let config = NCDHttpConfig::new().retries(2).backoff(Duration::from_millis(1));
+        let (out,calls) = retry_run(&config,&[(NCDAccessErrorKind::HttpStatus(503),false)]);
+        assert_eq!(vec![1,2,3],out.unwrap());
+        assert_eq!(2,calls);
+        let (out,calls) = retry_run(&config,&[(NCDAccessErrorKind::HttpStatus(404),false)]);
+        assert_eq!(1,calls);
+        assert_eq!(1,attempts(&out));
+        assert!(out.unwrap_err().to_string().contains("404"));
+        let (out,calls) = retry_run(&config,&vec![(NCDAccessErrorKind::ShortRead(2,1),false);5]);
+        assert_eq!(3,calls);
+        assert_eq!(3,attempts(&out));
+        let (out,calls) = retry_run(&config,&[(NCDAccessErrorKind::Transport("reset".to_string()),true)]);
+        assert!(out.is_ok());
+        assert_eq!(2,calls);
+        let (out,calls) = retry_run(&config,&[(NCDAccessErrorKind::Transport("bad url".to_string()),false)]);
+        assert!(out.is_err());
+        assert_eq!(1,calls);
+        let (out,calls) = retry_run(&config,&[(NCDAccessErrorKind::RangeUnsupported(200),false)]);
+        assert!(out.is_err());
+        assert_eq!(1,calls);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{io, thread, time::Duration};
+use crate::{NCDAccessStats, read::{NCDReadAccessor, NCDReadKind}};
+
+/* SplitMix64: tiny, and reproducible whatever version of rand is in use */
+struct FaultRng(u64);
+
+impl FaultRng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /* uniform in [0,1) */
+    fn unit(&mut self) -> f64 { (self.next() >> 11) as f64 / (1_u64 << 53) as f64 }
+
+    fn chance(&mut self, rate: f64) -> bool { rate > 0. && self.unit() < rate }
+
+    fn below(&mut self, limit: u64) -> u64 { if limit == 0 { 0 } else { self.next() % limit } }
+}
+
+#[derive(Clone)]
+pub struct NCDFaultConfig {
+    seed: u64,
+    latency: Duration,
+    jitter: Duration,
+    error_rate: f64,
+    truncate_rate: f64,
+    bit_flip_rate: f64
+}
+
+impl Default for NCDFaultConfig {
+    fn default() -> NCDFaultConfig { NCDFaultConfig::new() }
+}
+
+impl NCDFaultConfig {
+    pub fn new() -> NCDFaultConfig {
+        NCDFaultConfig {
+            seed: 0,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            error_rate: 0.,
+            truncate_rate: 0.,
+            bit_flip_rate: 0.
+        }
+    }
+
+    chain!(seed,get_seed,u64,NCDFaultConfig);
+    chain!(latency,get_latency,Duration,NCDFaultConfig);
+    chain!(jitter,get_jitter,Duration,NCDFaultConfig);
+    chain!(error_rate,get_error_rate,f64,NCDFaultConfig);
+    chain!(truncate_rate,get_truncate_rate,f64,NCDFaultConfig);
+    chain!(bit_flip_rate,get_bit_flip_rate,f64,NCDFaultConfig);
+}
+
+/* Wraps an accessor to misbehave in the ways real storage does, for testing. The same seed gives the same
+ * faults for the same sequence of reads. A replacement accessor can be swapped in part way through, as though
+ * the file had been rebuilt under the reader.
+ */
+pub struct NCDFaultAccessor<'a,T> where T: NCDReadAccessor {
+    inner: T,
+    config: NCDFaultConfig,
+    rng: FaultRng,
+    reads: u64,
+    replacement: Option<(u64,Box<dyn NCDReadAccessor + 'a>)>,
+    swapped: bool
+}
+
+impl<'a,T> NCDFaultAccessor<'a,T> where T: NCDReadAccessor {
+    pub fn new(config: &NCDFaultConfig, inner: T) -> NCDFaultAccessor<'a,T> {
+        NCDFaultAccessor {
+            inner,
+            config: config.clone(),
+            rng: FaultRng(config.seed),
+            reads: 0,
+            replacement: None,
+            swapped: false
+        }
+    }
+
+    /* reads after the first `after` go to the replacement */
+    pub fn swap_after(mut self, after: u64, replacement: Box<dyn NCDReadAccessor + 'a>) -> NCDFaultAccessor<'a,T> {
+        self.replacement = Some((after,replacement));
+        self
+    }
+
+    pub fn reads(&self) -> u64 { self.reads }
+    pub fn swapped(&self) -> bool { self.swapped }
+    pub fn into_inner(self) -> T { self.inner }
+
+    fn delay(&mut self) {
+        let jitter = self.config.jitter.mul_f64(self.rng.unit());
+        let delay = self.config.latency + jitter;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl<'a,T> NCDReadAccessor for NCDFaultAccessor<'a,T> where T: NCDReadAccessor {
+    fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.read_kind(NCDReadKind::Other,offset,length)
+    }
+
+    fn read_kind(&mut self, kind: NCDReadKind, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.reads += 1;
+        self.delay();
+        if self.rng.chance(self.config.error_rate) {
+            return Err(io::Error::other(format!("injected fault reading {} bytes at {}",length,offset)));
+        }
+        let mut data = match &mut self.replacement {
+            Some((after,replacement)) if self.reads > *after => {
+                self.swapped = true;
+                replacement.read_kind(kind,offset,length)?
+            },
+            _ => self.inner.read_kind(kind,offset,length)?
+        };
+        if !data.is_empty() && self.rng.chance(self.config.truncate_rate) {
+            let keep = self.rng.below(data.len() as u64);
+            data.truncate(keep as usize);
+        }
+        if !data.is_empty() && self.rng.chance(self.config.bit_flip_rate) {
+            let bit = self.rng.below(data.len() as u64 * 8);
+            data[(bit/8) as usize] ^= 1 << (bit%8);
+        }
+        Ok(data)
+    }
+
+    fn stats(&self) -> Option<NCDAccessStats> { self.inner.stats() }
+    fn reset_stats(&mut self) { self.inner.reset_stats() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::{Duration, Instant}};
+
+    use crate::{NCDError, NCDReadAccessor, NCDReader, StdNCDReadAccessor, test::{SMOKE_FILE, example_file}, wrap_io_error};
+
+    use super::{FaultRng, NCDFaultAccessor, NCDFaultConfig};
+
+    fn smoke() -> StdNCDReadAccessor<Cursor<Vec<u8>>> {
+        StdNCDReadAccessor::new(Cursor::new(SMOKE_FILE.to_vec())).unwrap()
+    }
+
+    fn run(config: &NCDFaultConfig) -> Vec<Option<Vec<u8>>> {
+        let mut accessor = NCDFaultAccessor::new(config,smoke());
+        (0..50).map(|i| accessor.read(i,20).ok()).collect()
+    }
+
+    #[test]
+    fn test_fault_rng() {
+        let mut rng = FaultRng(1);
+        for _ in 0..1000 {
+            let x = rng.unit();
+            assert!((0. ..1.).contains(&x));
+            assert!(rng.below(7) < 7);
+        }
+        assert!(!FaultRng(1).chance(0.));
+        assert!(FaultRng(1).chance(1.));
+    }
+
+    #[test]
+    fn test_fault_seeded() {
+        let config = NCDFaultConfig::new().seed(42).error_rate(0.2).truncate_rate(0.2).bit_flip_rate(0.2);
+        let first = run(&config);
+        assert_eq!(first,run(&config));
+        assert_ne!(first,run(&config.seed(43)));
+        assert!(first.iter().any(|x| x.is_none()));
+        assert!(first.iter().any(|x| x.as_ref().map(|x| x.len() < 20).unwrap_or(false)));
+        assert!(first.iter().enumerate().any(|(i,x)| x.as_ref().map(|x| x.len() == 20 && x.as_slice() != &SMOKE_FILE[i..i+20]).unwrap_or(false)));
+        let clean = run(&NCDFaultConfig::new());
+        assert!(clean.iter().enumerate().all(|(i,x)| x.as_deref() == Some(&SMOKE_FILE[i..i+20])));
+    }
+
+    #[test]
+    fn test_fault_latency() {
+        let config = NCDFaultConfig::new().latency(Duration::from_millis(20)).jitter(Duration::from_millis(10));
+        let mut accessor = NCDFaultAccessor::new(&config,smoke());
+        let start = Instant::now();
+        accessor.read(0,4).unwrap();
+        accessor.read(0,4).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    /* the reader notices the rebuilt file through the stamp and starts afresh */
+    fn do_test_fault_swap() -> Result<(),NCDError> {
+        let replacement = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(example_file()?)))?;
+        let accessor = NCDFaultAccessor::new(&NCDFaultConfig::new(),smoke()).swap_after(2,Box::new(replacement));
+        let mut reader = NCDReader::new(accessor)?;
+        assert_eq!(Some(b"World".to_vec()),reader.get(b"Hello")?);
+        assert_eq!(Some(b"999".to_vec()),reader.get(b"1")?);
+        assert_eq!(None,reader.get(b"Hello")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_swap() {
+        do_test_fault_swap().unwrap()
+    }
+}
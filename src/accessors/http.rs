@@ -1,25 +1,41 @@
-use std::{io::{self, ErrorKind}, time::Duration};
-use curl::easy::Easy;
-use crate::NCDReadAccessor;
+use std::{io::{self, ErrorKind}, sync::Arc};
+use curl::easy::{Auth, Easy, List};
+use crate::{NCDReadAccessor, accessors::{config::{AttemptResult, NCDHttpConfig}, range::{ResponseHeaders, Validator, check_response}}, util::NCDAccessErrorKind};
 
-#[derive(Clone)]
-pub struct CurlConfig {
-    connect_timeout: Duration,
-}
+/* retained for compatibility: the settings are shared by all HTTP accessors */
+pub type CurlConfig = NCDHttpConfig;
 
-impl CurlConfig {
-    pub fn new() -> CurlConfig {
-        CurlConfig {
-            connect_timeout: Duration::new(2,0),
-        }
+fn configure_easy(config: &NCDHttpConfig, easy: &mut Easy) -> Result<(),curl::Error> {
+    easy.connect_timeout(*config.get_connect_timeout())?;
+    if let Some(timeout) = config.get_timeout() {
+        easy.timeout(*timeout)?;
     }
-
-    fn configure_easy(&self, easy: &mut Easy) -> Result<(),curl::Error> {
-        easy.connect_timeout(self.connect_timeout)?;
-        Ok(())
+    easy.low_speed_limit(*config.get_low_speed_limit())?;
+    easy.low_speed_time(*config.get_low_speed_time())?;
+    if let Some(user_agent) = config.get_user_agent() {
+        easy.useragent(user_agent)?;
     }
-
-    chain!(connect_timeout,get_connect_timeout,Duration,CurlConfig);
+    if let Some((username,password)) = config.get_basic_auth() {
+        easy.username(username)?;
+        easy.password(password)?;
+        easy.http_auth(Auth::new().basic(true))?;
+    }
+    if let Some(cert) = config.get_client_cert() {
+        easy.ssl_cert(cert)?;
+    }
+    if let Some(key) = config.get_client_key() {
+        easy.ssl_key(key)?;
+    }
+    if let Some(password) = config.get_client_key_password() {
+        easy.key_password(password)?;
+    }
+    if let Some(ca_bundle) = config.get_ca_bundle() {
+        easy.cainfo(ca_bundle)?;
+    }
+    if let Some(proxy) = config.get_proxy() {
+        easy.proxy(proxy)?;
+    }
+    Ok(())
 }
 
 fn wrap_curl_error<T>(value: Result<T,curl::Error>) -> Result<T,io::Error> {
@@ -27,29 +43,48 @@ fn wrap_curl_error<T>(value: Result<T,curl::Error>) -> Result<T,io::Error> {
 }
 
 pub struct CurlNCDReadAccessor {
-    curl: Easy
-}
-
-enum ReadResponse {
-    Data(Vec<u8>),
-    HttpError(u32)
+    curl: Easy,
+    config: Arc<NCDHttpConfig>,
+    validator: Option<Validator>
 }
 
 impl CurlNCDReadAccessor {
-    pub fn new(config: &CurlConfig, url: &str) -> io::Result<CurlNCDReadAccessor> {
+    pub fn new(config: &NCDHttpConfig, url: &str) -> io::Result<CurlNCDReadAccessor> {
         let mut easy = Easy::new();
         wrap_curl_error(easy.url(url))?;
-        wrap_curl_error(config.configure_easy(&mut easy))?;
+        wrap_curl_error(configure_easy(config,&mut easy))?;
         Ok(CurlNCDReadAccessor {
-            curl: easy
+            curl: easy,
+            config: Arc::new(config.clone()),
+            validator: None
         })
     }
 
-    fn read_curl(&mut self, offset: u64, length: u64) -> Result<ReadResponse,curl::Error> {
+    fn request_headers(&self) -> io::Result<List> {
+        let mut headers = self.config.request_headers()?;
+        if let Some(validator) = &self.validator {
+            for (key,value) in validator.request_headers() {
+                headers.push((key.to_string(),value));
+            }
+        }
+        let mut list = List::new();
+        for (key,value) in headers {
+            wrap_curl_error(list.append(&format!("{}: {}",key,value)))?;
+        }
+        Ok(list)
+    }
+
+    fn read_curl(&mut self, offset: u64, length: u64, request_headers: List) -> Result<Result<Vec<u8>,NCDAccessErrorKind>,curl::Error> {
         let mut data = vec![];
-        if length == 0 { return Ok(ReadResponse::Data(data)); }
+        if length == 0 { return Ok(Ok(data)); }
+        let mut headers = ResponseHeaders::default();
+        self.curl.http_headers(request_headers)?;
         self.curl.range(&format!("{}-{}",offset,offset+length-1))?;
         let mut transfer = self.curl.transfer();
+        transfer.header_function(|line| {
+            headers.add_line(&String::from_utf8_lossy(line));
+            true
+        })?;
         transfer.write_function(|more| {
             data.extend_from_slice(more);
             Ok(more.len())
@@ -57,21 +92,29 @@ impl CurlNCDReadAccessor {
         transfer.perform()?;
         drop(transfer);
         let code = self.curl.response_code()?;
-        if code > 299 {
-            return Ok(ReadResponse::HttpError(code));
+        Ok(check_response(&mut self.validator,offset,length,code,&headers,data))
+    }
+
+    fn attempt(&mut self, offset: u64, length: u64, request_headers: List) -> AttemptResult {
+        match self.read_curl(offset,length,request_headers) {
+            Ok(result) => result.map_err(|kind| (kind,false)),
+            Err(e) => Err((NCDAccessErrorKind::Transport(e.to_string()),is_transient(&e)))
         }
-        Ok(ReadResponse::Data(data))
     }
 }
 
+fn is_transient(error: &curl::Error) -> bool {
+    error.is_couldnt_connect() || error.is_operation_timedout() || error.is_send_error() ||
+    error.is_recv_error() || error.is_got_nothing() || error.is_partial_file()
+}
+
 impl NCDReadAccessor for CurlNCDReadAccessor {
     fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
-        match wrap_curl_error(self.read_curl(offset,length))? {
-            ReadResponse::Data(d) => Ok(d),
-            ReadResponse::HttpError(e) => {
-                Err(io::Error::new(ErrorKind::Other,format!("HTTP error code={}",e)))
-            }
-        }
+        let config = self.config.clone();
+        config.with_retries(|| {
+            let request_headers = self.request_headers()?;
+            Ok(self.attempt(offset,length,request_headers))
+        })
     }
 }
 
@@ -79,18 +122,13 @@ impl NCDReadAccessor for CurlNCDReadAccessor {
 mod test {
     use std::{io, time::Duration};
 
-    use crate::{NCDError, NCDReadAccessor, test::SMOKE_FILE, wrap_io_error};
+    use crate::{NCDAccessError, NCDAccessErrorKind, NCDError, NCDReadAccessor, NCDServerConfig, test::{SMOKE_FILE, smoke_server}, wrap_io_error};
 
     use super::{CurlNCDReadAccessor, CurlConfig};
 
-    const URL : &str = "https://raw.githubusercontent.com/ens-ds23/ncd/main/testdata/smoke.ncd";
-    const BAD_URLS : &[(&str,&str)] = &[
-        ("https://192.168.255.255:12345/does-not-exist.ncd","Timeout was reached"),
-        ("https://raw.githubusercontent.com/ens-ds23/ncd/main/testdata/404.ncd","404")
-    ];
-
     fn do_test_curl() -> Result<(),NCDError> {
-        let mut curl = wrap_io_error(CurlNCDReadAccessor::new(&CurlConfig::new(),URL))?;
+        let (_dir,server) = wrap_io_error(smoke_server(&NCDServerConfig::new()))?;
+        let mut curl = wrap_io_error(CurlNCDReadAccessor::new(&CurlConfig::new(),&server.url("smoke.ncd")))?;
         for offset in [0_usize,7,9,12] {
             for length in [0_usize,5,8,14,24] {
                 let read = wrap_io_error(curl.read(offset as u64,length as u64))?;
@@ -109,20 +147,27 @@ mod test {
         do_test_curl().unwrap()
     }
 
-    fn try_url(url: &str) -> Result<(),io::Error> {
-        let config = CurlConfig::new().connect_timeout(Duration::new(2,0));
-        let mut curl = CurlNCDReadAccessor::new(&config,url)?;
-        curl.read(0,8)?;
-        Ok(())
+    fn try_server(server_config: &NCDServerConfig, path: &str) -> Result<Vec<u8>,io::Error> {
+        let (_dir,server) = smoke_server(server_config)?;
+        let config = CurlConfig::new()
+            .timeout(Some(Duration::from_millis(500)))
+            .backoff(Duration::from_millis(1));
+        let mut curl = CurlNCDReadAccessor::new(&config,&server.url(path))?;
+        curl.read(0,8)
     }
 
     fn do_test_curl_bad_url() -> Result<(),NCDError> {
-        for (bad_url,find) in BAD_URLS {
-            match try_url(bad_url) {
-                Ok(()) => { assert!(false); },
+        let cases = [
+            (NCDServerConfig::new().delay(Duration::from_secs(2)),"smoke.ncd","Timeout was reached"),
+            (NCDServerConfig::new(),"404.ncd","404")
+        ];
+        for (server_config,path,find) in &cases {
+            match try_server(server_config,path) {
+                Ok(_) => { assert!(false); },
                 Err(e) => { 
                     println!("{}",e.to_string());
                     assert!(e.to_string().contains(find)); 
+                    assert!(e.to_string().contains("attempt"));
                 }
             }
         }
@@ -133,4 +178,22 @@ mod test {
     fn test_curl_bad_url() {
         do_test_curl_bad_url().unwrap()
     }
+
+    fn error_kind(result: io::Result<Vec<u8>>) -> (NCDAccessErrorKind,u32) {
+        let error = result.err().unwrap();
+        let error = NCDAccessError::from_io_error(&error).unwrap();
+        (error.kind().clone(),error.attempts())
+    }
+
+    #[test]
+    fn test_curl_faults() {
+        let data = try_server(&NCDServerConfig::new().fail_first(2),"smoke.ncd").unwrap();
+        assert_eq!(&SMOKE_FILE[0..8],data.as_slice());
+        let out = try_server(&NCDServerConfig::new().fail_first(3),"smoke.ncd");
+        assert_eq!((NCDAccessErrorKind::HttpStatus(503),3),error_kind(out));
+        let out = try_server(&NCDServerConfig::new().ignore_range(true),"smoke.ncd");
+        assert_eq!((NCDAccessErrorKind::RangeUnsupported(200),1),error_kind(out));
+        let out = try_server(&NCDServerConfig::new().short_read_rate(1.),"smoke.ncd");
+        assert_eq!((NCDAccessErrorKind::ShortRead(8,4),3),error_kind(out));
+    }
 }
@@ -0,0 +1,181 @@
+use std::{fmt, io, time::{Duration, Instant}};
+use crate::read::{NCDReadAccessor, NCDReadKind};
+
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct NCDKindStats {
+    reads: u64,
+    errors: u64,
+    bytes: u64,
+    time: Duration,
+    max_time: Duration
+}
+
+impl NCDKindStats {
+    pub fn reads(&self) -> u64 { self.reads }
+    pub fn errors(&self) -> u64 { self.errors }
+    pub fn bytes(&self) -> u64 { self.bytes }
+    pub fn time(&self) -> Duration { self.time }
+    pub fn max_time(&self) -> Duration { self.max_time }
+
+    pub fn mean_time(&self) -> Duration {
+        let count = self.reads + self.errors;
+        if count == 0 { Duration::ZERO } else { Duration::from_nanos((self.time.as_nanos() / count as u128) as u64) }
+    }
+
+    fn record(&mut self, result: &io::Result<Vec<u8>>, time: Duration) {
+        match result {
+            Ok(data) => { self.reads += 1; self.bytes += data.len() as u64; },
+            Err(_) => { self.errors += 1; }
+        }
+        self.time += time;
+        self.max_time = self.max_time.max(time);
+    }
+
+    fn add(&mut self, other: &NCDKindStats) {
+        self.reads += other.reads;
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+        self.time += other.time;
+        self.max_time = self.max_time.max(other.max_time);
+    }
+}
+
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct NCDAccessStats {
+    header: NCDKindStats,
+    page: NCDKindStats,
+    external: NCDKindStats,
+    other: NCDKindStats
+}
+
+impl NCDAccessStats {
+    pub fn get(&self, kind: NCDReadKind) -> &NCDKindStats {
+        match kind {
+            NCDReadKind::Header => &self.header,
+            NCDReadKind::Page => &self.page,
+            NCDReadKind::External => &self.external,
+            NCDReadKind::Other => &self.other
+        }
+    }
+
+    fn get_mut(&mut self, kind: NCDReadKind) -> &mut NCDKindStats {
+        match kind {
+            NCDReadKind::Header => &mut self.header,
+            NCDReadKind::Page => &mut self.page,
+            NCDReadKind::External => &mut self.external,
+            NCDReadKind::Other => &mut self.other
+        }
+    }
+
+    pub fn total(&self) -> NCDKindStats {
+        let mut out = NCDKindStats::default();
+        for kind in &[NCDReadKind::Header,NCDReadKind::Page,NCDReadKind::External,NCDReadKind::Other] {
+            out.add(self.get(*kind));
+        }
+        out
+    }
+}
+
+impl fmt::Display for NCDAccessStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name,kind) in &[("header",NCDReadKind::Header),("page",NCDReadKind::Page),("external",NCDReadKind::External),("other",NCDReadKind::Other)] {
+            let stats = self.get(*kind);
+            writeln!(f,"{}: reads={} errors={} bytes={} mean={:?} max={:?}",
+                name,stats.reads,stats.errors,stats.bytes,stats.mean_time(),stats.max_time)?;
+        }
+        Ok(())
+    }
+}
+
+/* Counts the reads made through it, by kind, so that remote access costs can be measured. */
+pub struct NCDInstrumentedAccessor<T> where T: NCDReadAccessor {
+    inner: T,
+    stats: NCDAccessStats
+}
+
+impl<T> NCDInstrumentedAccessor<T> where T: NCDReadAccessor {
+    pub fn new(inner: T) -> NCDInstrumentedAccessor<T> {
+        NCDInstrumentedAccessor { inner, stats: NCDAccessStats::default() }
+    }
+
+    pub fn into_inner(self) -> T { self.inner }
+}
+
+impl<T> NCDReadAccessor for NCDInstrumentedAccessor<T> where T: NCDReadAccessor {
+    fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.read_kind(NCDReadKind::Other,offset,length)
+    }
+
+    fn read_kind(&mut self, kind: NCDReadKind, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let start = Instant::now();
+        let out = self.inner.read_kind(kind,offset,length);
+        self.stats.get_mut(kind).record(&out,start.elapsed());
+        out
+    }
+
+    fn stats(&self) -> Option<NCDAccessStats> { Some(self.stats.clone()) }
+    fn reset_stats(&mut self) { self.stats = NCDAccessStats::default(); }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::Duration};
+
+    use crate::{NCDError, NCDReadAccessor, NCDReadKind, NCDReader, StdNCDReadAccessor, header::NCDHeader, test::{SMOKE_FILE, example_file}, wrap_io_error};
+
+    use super::{NCDInstrumentedAccessor, NCDKindStats};
+
+    /* the spec promises one page read per lookup, plus one more for external values */
+    fn do_test_instrumented() -> Result<(),NCDError> {
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(example_file()?)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        let stats = reader.stats().unwrap();
+        assert_eq!(1,stats.get(NCDReadKind::Header).reads());
+        assert_eq!(0,stats.get(NCDReadKind::Page).reads());
+        reader.reset_stats();
+        for i in 0..100 {
+            assert!(reader.get(format!("{}",i).as_bytes())?.is_some());
+        }
+        assert!(reader.get(b"missing")?.is_none());
+        let stats = reader.stats().unwrap();
+        assert_eq!(0,stats.get(NCDReadKind::Header).reads());
+        assert_eq!(101,stats.get(NCDReadKind::Page).reads());
+        assert_eq!(101*reader.header().page_size() as u64,stats.get(NCDReadKind::Page).bytes());
+        assert_eq!(0,stats.get(NCDReadKind::Other).reads());
+        assert_eq!(101+stats.get(NCDReadKind::External).reads(),stats.total().reads());
+        reader.accessor().read(0,4).ok();
+        assert_eq!(1,reader.stats().unwrap().get(NCDReadKind::Other).reads());
+        Ok(())
+    }
+
+    #[test]
+    fn test_instrumented() {
+        do_test_instrumented().unwrap()
+    }
+
+    fn do_test_instrumented_short() -> Result<(),NCDError> {
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(SMOKE_FILE[..20].to_vec())))?;
+        let mut instrumented = NCDInstrumentedAccessor::new(std);
+        assert!(NCDHeader::read(&mut instrumented).is_err());
+        let stats = instrumented.stats().unwrap();
+        assert_eq!(1,stats.get(NCDReadKind::Header).reads());
+        assert_eq!(20,stats.get(NCDReadKind::Header).bytes());
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(SMOKE_FILE.to_vec())))?;
+        assert!(NCDReader::new(std)?.stats().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_instrumented_short() {
+        do_test_instrumented_short().unwrap()
+    }
+
+    #[test]
+    fn test_mean_time() {
+        assert_eq!(Duration::ZERO,NCDKindStats::default().mean_time());
+        let stats = NCDKindStats { reads: 3, errors: 1, time: Duration::from_millis(10), ..Default::default() };
+        assert_eq!(Duration::from_micros(2500),stats.mean_time());
+        let stats = NCDKindStats { reads: 1<<32, time: Duration::from_secs(1<<32), ..Default::default() };
+        assert_eq!(Duration::from_secs(1),stats.mean_time());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::util::NCDAccessErrorKind;
+
+#[cfg_attr(debug_assertions,derive(Debug,PartialEq,Eq))]
+pub(crate) struct ContentRange {
+    start: u64,
+    end: u64,
+    total: Option<u64>
+}
+
+/* bytes START-END/TOTAL, where TOTAL may be * */
+pub(crate) fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let value = value.trim();
+    let (unit,rest) = value.split_at(value.find(' ')?);
+    if !unit.eq_ignore_ascii_case("bytes") { return None; }
+    let (range,total) = rest.trim().split_once('/')?;
+    let (start,end) = range.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?;
+    let total = match total.trim() {
+        "*" => None,
+        x => Some(x.parse::<u64>().ok()?)
+    };
+    if end < start { return None; }
+    Some(ContentRange { start, end, total })
+}
+
+pub(crate) fn header_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (key,value) = line.split_once(':')?;
+    if key.trim().eq_ignore_ascii_case(name) {
+        Some(value.trim())
+    } else {
+        None
+    }
+}
+
+/* Identifies a version of a remote file. Weak ETags can't be used for If-Match/If-Range so are ignored. */
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub(crate) enum Validator {
+    ETag(String),
+    LastModified(String)
+}
+
+impl Validator {
+    pub(crate) fn new(etag: Option<&str>, last_modified: Option<&str>) -> Option<Validator> {
+        match (etag,last_modified) {
+            (Some(etag),_) if !etag.starts_with("W/") => Some(Validator::ETag(etag.to_string())),
+            (_,Some(date)) => Some(Validator::LastModified(date.to_string())),
+            _ => None
+        }
+    }
+
+    pub(crate) fn request_headers(&self) -> Vec<(&'static str,String)> {
+        match self {
+            Validator::ETag(etag) => vec![("If-Match",etag.clone()),("If-Range",etag.clone())],
+            Validator::LastModified(date) => vec![("If-Unmodified-Since",date.clone()),("If-Range",date.clone())]
+        }
+    }
+}
+
+/* Records the validator from the first successful response and checks later ones against it. On a change the
+ * validator is forgotten so that the caller can start afresh (typically by reloading the header).
+ */
+pub(crate) fn check_validator(current: &mut Option<Validator>, code: u32, response: Option<Validator>) -> Result<(),NCDAccessErrorKind> {
+    if code == 412 {
+        *current = None;
+        return Err(NCDAccessErrorKind::ResourceChanged);
+    }
+    if !(200..=299).contains(&code) { return Ok(()); }
+    match (&current,response) {
+        (None,response) => { *current = response; },
+        (Some(old),Some(new)) if *old != new => {
+            *current = None;
+            return Err(NCDAccessErrorKind::ResourceChanged);
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct ResponseHeaders {
+    content_range: Option<String>,
+    content_length: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<String>
+}
+
+impl ResponseHeaders {
+    pub(crate) fn add_line(&mut self, line: &str) {
+        if line.starts_with("HTTP/") {
+            /* new response, eg after redirect */
+            *self = ResponseHeaders::default();
+        } else if let Some(value) = header_value(line,"Content-Range") {
+            self.content_range = Some(value.to_string());
+        } else if let Some(value) = header_value(line,"Content-Length") {
+            self.content_length = value.parse::<u64>().ok();
+        } else if let Some(value) = header_value(line,"ETag") {
+            self.etag = Some(value.to_string());
+        } else if let Some(value) = header_value(line,"Last-Modified") {
+            self.last_modified = Some(value.to_string());
+        }
+    }
+}
+
+pub(crate) fn check_response(validator: &mut Option<Validator>, offset: u64, length: u64, code: u32, headers: &ResponseHeaders, data: Vec<u8>) -> Result<Vec<u8>,NCDAccessErrorKind> {
+    let response_validator = Validator::new(headers.etag.as_deref(),headers.last_modified.as_deref());
+    check_validator(validator,code,response_validator)?;
+    validate_range_response(offset,length,code,headers.content_range.as_deref(),headers.content_length,data)
+}
+
+/* A response is only accepted if it is exactly the requested range, or that range truncated by the end of the
+ * file (to match reads beyond EOF on local files). A 200 is only acceptable where the whole file was requested
+ * anyway, and is short only if its Content-Length says that is all there is. Code 0 means a non-HTTP protocol,
+ * which we trust. The range comes from the server, so its arithmetic is checked.
+ */
+pub(crate) fn validate_range_response(offset: u64, length: u64, code: u32, content_range: Option<&str>, content_length: Option<u64>, mut data: Vec<u8>) -> Result<Vec<u8>,NCDAccessErrorKind> {
+    match code {
+        0 => Ok(data),
+        416 => Ok(vec![]),
+        206 => {
+            let range = content_range.and_then(parse_content_range).ok_or(NCDAccessErrorKind::RangeUnsupported(code))?;
+            if range.start != offset {
+                return Err(NCDAccessErrorKind::RangeUnsupported(code));
+            }
+            /* exclusive ends */
+            let sent_end = range.end.checked_add(1).ok_or(NCDAccessErrorKind::RangeUnsupported(code))?;
+            let wanted_end = offset.checked_add(length).ok_or(NCDAccessErrorKind::RangeUnsupported(code))?;
+            let sent = sent_end - range.start;
+            if data.len() as u64 != sent {
+                return Err(NCDAccessErrorKind::ShortRead(sent,data.len() as u64));
+            }
+            if sent_end < wanted_end && range.total != Some(sent_end) {
+                return Err(NCDAccessErrorKind::ShortRead(length,data.len() as u64));
+            }
+            data.truncate(length as usize);
+            Ok(data)
+        },
+        200..=299 => {
+            if offset != 0 || data.len() as u64 > length {
+                return Err(NCDAccessErrorKind::RangeUnsupported(code));
+            }
+            if (data.len() as u64) < length && content_length != Some(data.len() as u64) {
+                return Err(NCDAccessErrorKind::ShortRead(length,data.len() as u64));
+            }
+            Ok(data)
+        },
+        _ => Err(NCDAccessErrorKind::HttpStatus(code))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::NCDAccessErrorKind;
+
+    use super::{ContentRange, Validator, check_validator, header_value, parse_content_range, validate_range_response};
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(Some(ContentRange { start: 0, end: 27, total: Some(101) }),parse_content_range("bytes 0-27/101"));
+        assert_eq!(Some(ContentRange { start: 5, end: 5, total: None }),parse_content_range(" Bytes 5-5/* "));
+        assert_eq!(None,parse_content_range("bytes */101"));
+        assert_eq!(None,parse_content_range("bytes 7-5/101"));
+        assert_eq!(None,parse_content_range("items 0-5/101"));
+        assert_eq!(None,parse_content_range(""));
+        assert_eq!(Some("bytes 0-1/2"),header_value("content-range: bytes 0-1/2\r\n","Content-Range"));
+        assert_eq!(None,header_value("Content-Length: 2\r\n","Content-Range"));
+    }
+
+    #[test]
+    fn test_validate_range_response() {
+        let data = vec![0_u8;10];
+        assert_eq!(Ok(data.clone()),validate_range_response(5,10,206,Some("bytes 5-14/100"),None,data.clone()));
+        assert_eq!(Ok(data.clone()),validate_range_response(5,20,206,Some("bytes 5-14/15"),None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::ShortRead(20,10)),validate_range_response(5,20,206,Some("bytes 5-14/100"),None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::ShortRead(12,10)),validate_range_response(5,12,206,Some("bytes 5-16/100"),None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(206)),validate_range_response(6,10,206,Some("bytes 5-14/100"),None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(206)),validate_range_response(5,10,206,None,None,data.clone()));
+        assert_eq!(Ok(data.clone()),validate_range_response(0,10,200,None,None,data.clone()));
+        assert_eq!(Ok(data.clone()),validate_range_response(0,28,200,None,Some(10),data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::ShortRead(28,10)),validate_range_response(0,28,200,None,None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::ShortRead(28,10)),validate_range_response(0,28,200,None,Some(100),data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(200)),validate_range_response(0,8,200,None,None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(200)),validate_range_response(5,10,200,None,None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(206)),validate_range_response(0,10,206,Some("bytes 0-18446744073709551615/*"),None,data.clone()));
+        assert_eq!(Err(NCDAccessErrorKind::RangeUnsupported(206)),validate_range_response(u64::MAX-4,10,206,Some("bytes 18446744073709551611-18446744073709551614/*"),None,vec![0;4]));
+        assert_eq!(Ok(vec![]),validate_range_response(5,0,206,Some("bytes 5-14/100"),None,data.clone()));
+        assert_eq!(Ok(vec![]),validate_range_response(500,10,416,Some("bytes */100"),None,vec![]));
+        assert_eq!(Err(NCDAccessErrorKind::HttpStatus(404)),validate_range_response(0,10,404,None,None,vec![]));
+    }
+
+    #[test]
+    fn test_validator() {
+        let etag = Validator::new(Some("\"abc\""),Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(Some(Validator::ETag("\"abc\"".to_string())),etag);
+        let date = Validator::new(Some("W/\"abc\""),Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(Some(Validator::LastModified("Wed, 21 Oct 2015 07:28:00 GMT".to_string())),date);
+        assert_eq!(None,Validator::new(Some("W/\"abc\""),None));
+        assert_eq!("If-Match",etag.as_ref().unwrap().request_headers()[0].0);
+        let mut current = None;
+        assert!(check_validator(&mut current,404,etag.clone()).is_ok());
+        assert_eq!(None,current);
+        assert!(check_validator(&mut current,206,etag.clone()).is_ok());
+        assert_eq!(etag,current);
+        assert!(check_validator(&mut current,206,etag.clone()).is_ok());
+        assert!(check_validator(&mut current,206,None).is_ok());
+        assert_eq!(etag,current);
+        let other = Validator::new(Some("\"def\""),None);
+        assert_eq!(Err(NCDAccessErrorKind::ResourceChanged),check_validator(&mut current,200,other.clone()));
+        assert_eq!(None,current);
+        assert!(check_validator(&mut current,206,other.clone()).is_ok());
+        assert_eq!(Err(NCDAccessErrorKind::ResourceChanged),check_validator(&mut current,412,None));
+        assert_eq!(None,current);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{io::{self, Read}, sync::Arc};
+use ureq::{Agent, AgentBuilder, ErrorKind, Proxy, Response};
+use crate::{NCDReadAccessor, accessors::{config::{AttemptResult, NCDHttpConfig}, range::{ResponseHeaders, Validator, check_response}}, util::NCDAccessErrorKind};
+
+const BASE64_ALPHABET : &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let mut group = [0_u8;3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let value = ((group[0] as u32) << 16) | ((group[1] as u32) << 8) | (group[2] as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[((value >> (18-6*i)) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn wrap_ureq_error<T>(value: Result<T,ureq::Error>) -> Result<T,io::Error> {
+    value.map_err(|e| io::Error::other(e.to_string()))
+}
+
+/* TLS client certificates and CA bundles are only supported by the curl accessor */
+fn make_agent(config: &NCDHttpConfig) -> io::Result<Agent> {
+    if config.get_client_cert().is_some() || config.get_client_key().is_some() || config.get_ca_bundle().is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported,"TLS certificate settings need the curl accessor"));
+    }
+    let mut builder = AgentBuilder::new()
+        .timeout_connect(*config.get_connect_timeout())
+        .timeout_read(*config.get_low_speed_time());
+    if let Some(timeout) = config.get_timeout() {
+        builder = builder.timeout(*timeout);
+    }
+    if let Some(user_agent) = config.get_user_agent() {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(proxy) = config.get_proxy() {
+        builder = builder.proxy(wrap_ureq_error(Proxy::new(proxy))?);
+    }
+    Ok(builder.build())
+}
+
+fn is_transient(kind: ErrorKind) -> bool {
+    matches!(kind,ErrorKind::ConnectionFailed | ErrorKind::Io | ErrorKind::ProxyConnect)
+}
+
+pub struct UreqNCDReadAccessor {
+    agent: Agent,
+    url: String,
+    config: Arc<NCDHttpConfig>,
+    validator: Option<Validator>
+}
+
+impl UreqNCDReadAccessor {
+    pub fn new(config: &NCDHttpConfig, url: &str) -> io::Result<UreqNCDReadAccessor> {
+        Ok(UreqNCDReadAccessor {
+            agent: make_agent(config)?,
+            url: url.to_string(),
+            config: Arc::new(config.clone()),
+            validator: None
+        })
+    }
+
+    fn request_headers(&self) -> io::Result<Vec<(String,String)>> {
+        let mut headers = self.config.request_headers()?;
+        if let Some((username,password)) = self.config.get_basic_auth() {
+            let credentials = base64_encode(format!("{}:{}",username,password).as_bytes());
+            headers.push(("Authorization".to_string(),format!("Basic {}",credentials)));
+        }
+        if let Some(validator) = &self.validator {
+            for (key,value) in validator.request_headers() {
+                headers.push((key.to_string(),value));
+            }
+        }
+        Ok(headers)
+    }
+
+    /* A server ignoring the range could send the whole file, so don't read much more than requested. */
+    fn read_response(&mut self, offset: u64, length: u64, response: Response) -> AttemptResult {
+        let code = response.status() as u32;
+        let mut headers = ResponseHeaders::default();
+        for name in response.headers_names() {
+            for value in response.all(&name) {
+                headers.add_line(&format!("{}: {}",name,value));
+            }
+        }
+        let limit = if code == 206 { u64::MAX } else { length+1 };
+        let mut data = vec![];
+        if let Err(e) = response.into_reader().take(limit).read_to_end(&mut data) {
+            return Err((NCDAccessErrorKind::Transport(e.to_string()),true));
+        }
+        check_response(&mut self.validator,offset,length,code,&headers,data).map_err(|kind| (kind,false))
+    }
+
+    fn attempt(&mut self, offset: u64, length: u64, request_headers: Vec<(String,String)>) -> AttemptResult {
+        if length == 0 { return Ok(vec![]); }
+        let mut request = self.agent.get(&self.url).set("Range",&format!("bytes={}-{}",offset,offset+length-1));
+        for (key,value) in &request_headers {
+            request = request.set(key,value);
+        }
+        match request.call() {
+            Ok(response) => self.read_response(offset,length,response),
+            Err(ureq::Error::Status(_,response)) => self.read_response(offset,length,response),
+            Err(e) => Err((NCDAccessErrorKind::Transport(e.to_string()),is_transient(e.kind())))
+        }
+    }
+}
+
+impl NCDReadAccessor for UreqNCDReadAccessor {
+    fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let config = self.config.clone();
+        config.with_retries(|| {
+            let request_headers = self.request_headers()?;
+            Ok(self.attempt(offset,length,request_headers))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io, time::Duration};
+
+    use crate::{NCDAccessError, NCDAccessErrorKind, NCDError, NCDHttpConfig, NCDReadAccessor, NCDServerConfig, test::{SMOKE_FILE, smoke_server}, wrap_io_error};
+
+    use super::{UreqNCDReadAccessor, base64_encode};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!("",base64_encode(b""));
+        assert_eq!("Zg==",base64_encode(b"f"));
+        assert_eq!("Zm8=",base64_encode(b"fo"));
+        assert_eq!("Zm9v",base64_encode(b"foo"));
+        assert_eq!("dXNlcjpwYXNz",base64_encode(b"user:pass"));
+    }
+
+    fn do_test_ureq() -> Result<(),NCDError> {
+        let (_dir,server) = wrap_io_error(smoke_server(&NCDServerConfig::new()))?;
+        let mut http = wrap_io_error(UreqNCDReadAccessor::new(&NCDHttpConfig::new(),&server.url("smoke.ncd")))?;
+        for offset in [0_usize,7,9,12] {
+            for length in [0_usize,5,8,14,24] {
+                let read = wrap_io_error(http.read(offset as u64,length as u64))?;
+                assert_eq!(&SMOKE_FILE[offset..(offset+length)],read);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ureq() {
+        do_test_ureq().unwrap()
+    }
+
+    #[test]
+    fn test_ureq_unsupported() {
+        let config = NCDHttpConfig::new().ca_bundle(Some("ca.pem".into()));
+        let error = UreqNCDReadAccessor::new(&config,"http://localhost/smoke.ncd").err().unwrap();
+        assert_eq!(io::ErrorKind::Unsupported,error.kind());
+    }
+
+    fn error_kind(result: io::Result<Vec<u8>>) -> NCDAccessErrorKind {
+        let error = result.err().unwrap();
+        NCDAccessError::from_io_error(&error).unwrap().kind().clone()
+    }
+
+    fn do_test_ureq_faults() -> io::Result<()> {
+        let config = NCDHttpConfig::new().backoff(Duration::from_millis(1));
+        let (_dir,server) = smoke_server(&NCDServerConfig::new().fail_first(1))?;
+        let mut http = UreqNCDReadAccessor::new(&config,&server.url("smoke.ncd"))?;
+        assert_eq!(&SMOKE_FILE[4..12],http.read(4,8)?.as_slice());
+        let (_dir,server) = smoke_server(&NCDServerConfig::new().ignore_range(true))?;
+        let mut http = UreqNCDReadAccessor::new(&config,&server.url("smoke.ncd"))?;
+        assert_eq!(NCDAccessErrorKind::RangeUnsupported(200),error_kind(http.read(4,8)));
+        let (_dir,server) = smoke_server(&NCDServerConfig::new().short_read_rate(1.))?;
+        let mut http = UreqNCDReadAccessor::new(&config,&server.url("smoke.ncd"))?;
+        assert_eq!(NCDAccessErrorKind::ShortRead(8,4),error_kind(http.read(4,8)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ureq_faults() {
+        do_test_ureq_faults().unwrap()
+    }
+
+    /* replacing the file changes its ETag, so the conditional request fails */
+    fn do_test_ureq_changed() -> io::Result<()> {
+        let (dir,server) = smoke_server(&NCDServerConfig::new())?;
+        let mut http = UreqNCDReadAccessor::new(&NCDHttpConfig::new(),&server.url("smoke.ncd"))?;
+        http.read(0,8)?;
+        fs::write(dir.path().join("smoke.ncd"),&SMOKE_FILE[..20])?;
+        assert_eq!(NCDAccessErrorKind::ResourceChanged,error_kind(http.read(0,8)));
+        assert_eq!(&SMOKE_FILE[0..8],http.read(0,8)?.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ureq_changed() {
+        do_test_ureq_changed().unwrap()
+    }
+}
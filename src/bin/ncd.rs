@@ -0,0 +1,167 @@
+use std::{env, fmt::Display, fs::File, io, net::TcpListener, path::{Path, PathBuf}, process, str::FromStr, time::Duration};
+
+use ncd::{NCDGateway, NCDGatewayConfig, NCDInstrumentedAccessor, NCDRangeServer, NCDReadAccessor, NCDReader, NCDServerConfig, StdNCDReadAccessor};
+
+const USAGE : &str = "usage:
+  ncd serve [options] DIRECTORY
+    --listen ADDRESS        address to listen on (default 127.0.0.1:8080)
+    --delay MS              delay every response
+    --error-rate P          fail this proportion of requests
+    --fail-first N          fail the first N requests
+    --error-code CODE       HTTP code for injected failures (default 503)
+    --short-read-rate P     truncate this proportion of range responses
+    --ignore-range          always respond with the whole file
+  ncd gateway [options] [NAME=]FILE_OR_URL...
+    --listen ADDRESS        address to listen on (default 127.0.0.1:8080)
+    --reload SECONDS        how often to check files for rebuilds, 0 to never (default 10)
+    --max-batch N           most keys in one POST /keys (default 1000)
+  ncd header FILE_OR_URL
+    prints the header descriptor, for NCDReader::with_header
+  ncd metadata FILE_OR_URL
+    prints the metadata written when the file was built
+  ncd info FILE_OR_URL
+    prints the header, and any build statistics and metadata";
+
+fn die<E: Display>(value: E) -> ! {
+    eprintln!("{}",value);
+    process::exit(1);
+}
+
+fn die_on_error<T,E: Display>(value: Result<T,E>) -> T {
+    match value {
+        Ok(v) => v,
+        Err(e) => die(e)
+    }
+}
+
+fn option_value<T: FromStr>(args: &mut dyn Iterator<Item=String>, name: &str) -> T {
+    let value = args.next().unwrap_or_else(|| die(format!("missing value for {}\n{}",name,USAGE)));
+    value.parse().unwrap_or_else(|_| die(format!("bad value for {}: {}",name,value)))
+}
+
+fn serve(mut args: impl Iterator<Item=String>) {
+    let mut config = NCDServerConfig::new();
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut root = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => { listen = option_value(&mut args,&arg); },
+            "--delay" => { config = config.delay(Duration::from_millis(option_value(&mut args,&arg))); },
+            "--error-rate" => { config = config.error_rate(option_value(&mut args,&arg)); },
+            "--fail-first" => { config = config.fail_first(option_value(&mut args,&arg)); },
+            "--error-code" => { config = config.error_code(option_value(&mut args,&arg)); },
+            "--short-read-rate" => { config = config.short_read_rate(option_value(&mut args,&arg)); },
+            "--ignore-range" => { config = config.ignore_range(true); },
+            x if x.starts_with("--") => die(format!("unknown option {}\n{}",x,USAGE)),
+            _ if root.is_none() => { root = Some(PathBuf::from(arg)); },
+            _ => die(USAGE)
+        }
+    }
+    let root = root.unwrap_or_else(|| die(USAGE));
+    let server = die_on_error(NCDRangeServer::new(&config,&root).start(listen.as_str()));
+    eprintln!("serving {} on http://{}/",root.display(),server.address());
+    server.wait();
+}
+
+#[cfg(feature="curl")]
+fn url_accessor(url: &str) -> io::Result<Box<dyn NCDReadAccessor>> {
+    Ok(Box::new(ncd::CurlNCDReadAccessor::new(&ncd::NCDHttpConfig::new(),url)?))
+}
+
+#[cfg(all(feature="ureq",not(feature="curl")))]
+fn url_accessor(url: &str) -> io::Result<Box<dyn NCDReadAccessor>> {
+    Ok(Box::new(ncd::UreqNCDReadAccessor::new(&ncd::NCDHttpConfig::new(),url)?))
+}
+
+#[cfg(not(any(feature="curl",feature="ureq")))]
+fn url_accessor(_url: &str) -> io::Result<Box<dyn NCDReadAccessor>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,"built without HTTP support"))
+}
+
+fn accessor(location: &str) -> io::Result<Box<dyn NCDReadAccessor>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        url_accessor(location)
+    } else {
+        Ok(Box::new(StdNCDReadAccessor::new(File::open(location)?)?))
+    }
+}
+
+fn gateway(mut args: impl Iterator<Item=String>) {
+    let mut config = NCDGatewayConfig::new();
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => { listen = option_value(&mut args,&arg); },
+            "--reload" => {
+                let seconds : u64 = option_value(&mut args,&arg);
+                config = config.reload_interval(if seconds > 0 { Some(Duration::new(seconds,0)) } else { None });
+            },
+            "--max-batch" => { config = config.max_batch(option_value(&mut args,&arg)); },
+            x if x.starts_with("--") => die(format!("unknown option {}\n{}",x,USAGE)),
+            _ => { files.push(arg); }
+        }
+    }
+    if files.is_empty() { die(USAGE); }
+    let mut gateway = NCDGateway::new(&config);
+    for file in &files {
+        let (name,location) = match file.split_once('=') {
+            Some((name,location)) if !name.contains('/') => (name.to_string(),location),
+            _ => {
+                let stem = Path::new(file.trim_end_matches('/')).file_stem().map(|x| x.to_string_lossy().to_string());
+                (stem.unwrap_or_else(|| file.clone()),file.as_str())
+            }
+        };
+        die_on_error(gateway.add_file(&name,Box::new(NCDInstrumentedAccessor::new(die_on_error(accessor(location))))));
+        eprintln!("serving {} as {}",location,name);
+    }
+    let listener = die_on_error(TcpListener::bind(listen.as_str()));
+    eprintln!("gateway on http://{}/",die_on_error(listener.local_addr()));
+    die_on_error(gateway.serve(listener));
+}
+
+fn header(mut args: impl Iterator<Item=String>) {
+    let location = args.next().unwrap_or_else(|| die(USAGE));
+    let reader = die_on_error(NCDReader::new_box(die_on_error(accessor(&location))));
+    println!("{}",reader.header().descriptor());
+}
+
+fn metadata(mut args: impl Iterator<Item=String>) {
+    let location = args.next().unwrap_or_else(|| die(USAGE));
+    let mut reader = die_on_error(NCDReader::new_box(die_on_error(accessor(&location))));
+    let metadata = die_on_error(reader.metadata()).unwrap_or_else(|| die(format!("{} has no metadata",location)));
+    for (key,value) in metadata {
+        println!("{}={}",key,value);
+    }
+}
+
+fn info(mut args: impl Iterator<Item=String>) {
+    let location = args.next().unwrap_or_else(|| die(USAGE));
+    let mut reader = die_on_error(NCDReader::new_box(die_on_error(accessor(&location))));
+    let header = reader.header().clone();
+    println!("header: {}",header.descriptor());
+    println!("pages: {} of {} bytes",header.number_of_pages(),header.page_size());
+    if let Some(stats) = die_on_error(reader.build_stats()) {
+        println!("records: {}",stats.records());
+        println!("total length: {}",stats.total_length());
+        println!("external entries: {}",stats.external_entries());
+        println!("max probe: {}",stats.max_probe());
+        println!("entries per page: {} to {}",stats.min_page_entries(),stats.max_page_entries());
+        println!("heap used per page: {} to {} of {}",stats.min_heap_used(),stats.max_heap_used(),header.heap_size());
+    }
+    for (key,value) in die_on_error(reader.metadata()).unwrap_or_default() {
+        println!("{}={}",key,value);
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("serve") => serve(args),
+        Some("gateway") => gateway(args),
+        Some("header") => header(args),
+        Some("metadata") => metadata(args),
+        Some("info") => info(args),
+        _ => die(USAGE)
+    }
+}
@@ -2,6 +2,8 @@ use std::io::{BufReader, Cursor};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use murmur3::murmur3_x64_128;
+use siphasher::sip128::SipHasher24;
+use xxhash_rust::xxh3::xxh3_128_with_seed;
 use crate::util::{NCDError, wrap_io_error};
 
 pub const MAX_LESQLITE2_BYTES : usize = 9;
@@ -69,7 +71,11 @@ pub(crate) fn write_u16(bytes: &mut [u8], offset: &mut usize, value: u16) -> Res
     Ok(())
 }
 
+/* a value too big for the pointer means the layout was too small, so the build can retry with a larger one */
 pub(crate) fn write_uvar(bytes: &mut [u8], offset: &mut usize, value: u64, len: usize) -> Result<(),NCDError> {
+    if len < 8 && value >> (len*8) != 0 {
+        return Err(NCDError::HeapFull);
+    }
     match len {
         8 => write_u64(bytes,offset,value),
         4 => write_u32(bytes,offset,value as u32),
@@ -164,27 +170,90 @@ pub(crate) fn lesqlite2_write(data: &mut[u8], start: &mut usize, mut value: u64)
     Ok(())
 }
 
+pub(crate) fn lesqlite2_size(value: u64) -> Result<usize,NCDError> {
+    let mut bytes = [0;MAX_LESQLITE2_BYTES];
+    let mut start = 0;
+    lesqlite2_write(&mut bytes,&mut start,value)?;
+    Ok(start)
+}
+
 pub(crate) fn bounds_check(heap: &[u8], offset: usize, length: usize) -> Result<(),NCDError> {
-    if heap.len() < offset+length {
+    if offset.checked_add(length).map(|end| heap.len() < end).unwrap_or(true) {
         return Err(NCDError::CorruptNCDFile(format!("bad heap reference")));
     } else {
         return Ok(())
     }
 }
 
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum NCDHashAlgorithm {
+    /* x64 128-bit variant, seeded from the low 32 bits of the seed */
+    Murmur3,
+    /* 128-bit variant, seeded from the low 64 bits of the seed */
+    Xxh3,
+    /* SipHash-2-4 128-bit, keyed with the low and high halves of the seed */
+    SipHash
+}
+
+impl NCDHashAlgorithm {
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            NCDHashAlgorithm::Murmur3 => 0,
+            NCDHashAlgorithm::Xxh3 => 1,
+            NCDHashAlgorithm::SipHash => 2
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Result<NCDHashAlgorithm,NCDError> {
+        Ok(match id {
+            0 => NCDHashAlgorithm::Murmur3,
+            1 => NCDHashAlgorithm::Xxh3,
+            2 => NCDHashAlgorithm::SipHash,
+            x => { return Err(NCDError::CorruptNCDFile(format!("Unsupported hash algorithm {}",x))); }
+        })
+    }
+}
+
+/* The high half chooses page and slot, the low half is only used for external fingerprints */
+pub(crate) fn compute_full_hash(algorithm: NCDHashAlgorithm, seed: u128, key: &[u8]) -> Result<u128,NCDError> {
+    Ok(match algorithm {
+        NCDHashAlgorithm::Murmur3 => {
+            let mut hash_key = BufReader::new(key);
+            wrap_io_error(murmur3_x64_128(&mut hash_key,seed as u32))?
+        },
+        NCDHashAlgorithm::Xxh3 => xxh3_128_with_seed(key,seed as u64),
+        NCDHashAlgorithm::SipHash => SipHasher24::new_with_keys(seed as u64,(seed >> 64) as u64).hash(key).as_u128()
+    })
+}
+
+/* with the original, and default, algorithm and seed */
+#[cfg(test)]
 pub(crate) fn compute_hash(key: &[u8]) -> Result<u64,NCDError> {
-    let mut hash_key = BufReader::new(key);
-    let value = wrap_io_error(murmur3_x64_128(&mut hash_key,0))?;
-    Ok((value >> 64) as u64)
+    Ok((compute_full_hash(NCDHashAlgorithm::Murmur3,0,key)? >> 64) as u64)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{bitbash::{MAX_LESQLITE2_BYTES, all_set, bounds_check, compute_hash, lesqlite2_read, lesqlite2_write, read_bytes, read_u16, read_u32, read_u64, read_uvar, write_bytes, write_u16, write_u32, write_u64, write_uvar}, util::NCDError};
+    use crate::{bitbash::{MAX_LESQLITE2_BYTES, NCDHashAlgorithm, all_set, bounds_check, compute_full_hash, compute_hash, lesqlite2_read, lesqlite2_size, lesqlite2_write, read_bytes, read_u16, read_u32, read_u64, read_uvar, write_bytes, write_u16, write_u32, write_u64, write_uvar}, util::NCDError};
 
     fn do_test_hash() -> Result<(),NCDError> {
         assert_eq!(0x5b1e906a48ae1d19,compute_hash(b"hello")?);
         assert_eq!(0,compute_hash(b"")?);
+        assert_eq!(0x5b1e906a48ae1d19,(compute_full_hash(NCDHashAlgorithm::Murmur3,0,b"hello")? >> 64) as u64);
+        let mut seen = vec![];
+        for algorithm in [NCDHashAlgorithm::Murmur3,NCDHashAlgorithm::Xxh3,NCDHashAlgorithm::SipHash] {
+            assert_eq!(algorithm,NCDHashAlgorithm::from_id(algorithm.id())?);
+            for seed in [0,1,1<<64] {
+                let hash = compute_full_hash(algorithm,seed,b"hello")?;
+                assert_eq!(hash,compute_full_hash(algorithm,seed,b"hello")?);
+                seen.push(hash);
+            }
+        }
+        /* murmur3 and xxh3 ignore the high bits of the seed */
+        seen.sort();
+        seen.dedup();
+        assert_eq!(7,seen.len());
+        assert!(NCDHashAlgorithm::from_id(3).is_err());
         Ok(())
     }
  
@@ -208,6 +277,7 @@ mod test {
         let mut start = 0;
         let out = lesqlite2_read(bytes, &mut start)?;
         assert_eq!(value,out);
+        assert_eq!(start,lesqlite2_size(value)?);
         Ok(())
     }
 
@@ -268,7 +338,10 @@ mod test {
         let mut bytes = vec![0;8];
         let mut offset = 0;
         let value = 0x123456789ABCDEF;
-        write_uvar(&mut bytes,&mut offset,value,size)?;
+        if size < 8 {
+            assert!(matches!(write_uvar(&mut [0;8],&mut 0,value,size),Err(NCDError::HeapFull)));
+        }
+        write_uvar(&mut bytes,&mut offset,value & all_set(size)?,size)?;
         assert_eq!(size,offset);
         for b in 0..8 {
             let cmp = ((value >> (b*8)) & 0xFF) as u8;
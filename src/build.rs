@@ -1,6 +1,6 @@
-use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::{BTreeMap, HashSet}, convert::TryFrom, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{header::{HEADER_SIZE, NCDHeader}, util::{NCDError, wrap_io_error}, write::{ NCDValueSource, NCDWriteAttempt }};
+use crate::{bitbash::{NCDHashAlgorithm, compute_full_hash}, compress::{NCDCompression, NCDCompressor, make_dictionary}, metadata::encode_metadata, set::NCDSetMode, header::{FLAG_EXTERNAL_KEYS, FLAG_FINGERPRINTS_ONLY, FLAG_MULTI_VALUED, FLAG_SET, FLAG_SHARED_VALUES, HEADER_SIZE, MAX_HEADER_SIZE, NCDHeader, fingerprint_flags}, util::{NCDError, wrap_io_error}, write::{ NCDDuplicatePolicy, NCDMerge, NCDOverflow, NCDValueSource, NCDWriteAttempt }};
 
 const KB : u32 = 1024;
 
@@ -12,7 +12,28 @@ pub struct NCDBuildConfig {
     min_entries_per_page: u64,
     external_trheshold: f64,
     rebuild_page_factor: f64,
-    force_header_size: Option<u32>
+    force_header_size: Option<u32>,
+    external_keys: bool,
+    fingerprint_bits: Option<u32>,
+    hash_algorithm: NCDHashAlgorithm,
+    hash_seed: Option<u128>,
+    reseed_factor: Option<f64>,
+    max_reseeds: u32,
+    compression: Option<NCDCompression>,
+    dictionary_size: usize,
+    dictionary_samples: usize,
+    page_compression: Option<NCDCompression>,
+    metadata: bool,
+    metadata_entries: BTreeMap<String,String>,
+    build_stats: bool,
+    dedup: bool,
+    dedup_in_pages: bool,
+    duplicates: NCDDuplicatePolicy,
+    multi_valued: bool,
+    set: Option<NCDSetMode>,
+    perfect_hash: bool,
+    page_alignment: Option<u32>,
+    align_external: bool
 }
 
 impl NCDBuildConfig {
@@ -24,7 +45,28 @@ impl NCDBuildConfig {
             min_entries_per_page: 100,
             external_trheshold: 0.1,
             rebuild_page_factor: 1.2,
-            force_header_size: None
+            force_header_size: None,
+            external_keys: false,
+            fingerprint_bits: None,
+            hash_algorithm: NCDHashAlgorithm::Murmur3,
+            hash_seed: None,
+            reseed_factor: None,
+            max_reseeds: 4,
+            compression: None,
+            dictionary_size: 0,
+            dictionary_samples: 1000,
+            page_compression: None,
+            metadata: false,
+            metadata_entries: BTreeMap::new(),
+            build_stats: false,
+            dedup: false,
+            dedup_in_pages: true,
+            duplicates: NCDDuplicatePolicy::KeepFirst,
+            multi_valued: false,
+            set: None,
+            perfect_hash: false,
+            page_alignment: None,
+            align_external: false
         }
     }
 
@@ -35,6 +77,172 @@ impl NCDBuildConfig {
     chain!(external_trheshold,get_external_trheshold,f64,NCDBuildConfig);
     chain!(rebuild_page_factor,get_rebuild_page_factor,f64,NCDBuildConfig);
     chain!(force_header_size,get_force_header_size,Option<u32>,NCDBuildConfig);
+    chain!(external_keys,get_external_keys,bool,NCDBuildConfig);
+    chain!(fingerprint_bits,get_fingerprint_bits,Option<u32>,NCDBuildConfig);
+    chain!(hash_algorithm,get_hash_algorithm,NCDHashAlgorithm,NCDBuildConfig);
+    /* None is 0, except for SipHash which gets a random key */
+    chain!(hash_seed,get_hash_seed,Option<u128>,NCDBuildConfig);
+    /* Reseed rather than add pages when the page which overflowed had more than this many times its share of
+     * the keys added so far, suggesting keys which clump under the current seed. None to never reseed.
+     */
+    chain!(reseed_factor,get_reseed_factor,Option<f64>,NCDBuildConfig);
+    chain!(max_reseeds,get_max_reseeds,u32,NCDBuildConfig);
+    chain!(compression,get_compression,Option<NCDCompression>,NCDBuildConfig);
+    /* Most bytes of shared dictionary, trained on the first dictionary_samples values. 0 for none. */
+    chain!(dictionary_size,get_dictionary_size,usize,NCDBuildConfig);
+    chain!(dictionary_samples,get_dictionary_samples,usize,NCDBuildConfig);
+    /* Compresses each page as a whole, for smaller transfers at the cost of CPU when reading */
+    chain!(page_compression,get_page_compression,Option<NCDCompression>,NCDBuildConfig);
+    /* Writes a metadata block describing the build, along with any entries given here */
+    chain!(metadata,get_metadata,bool,NCDBuildConfig);
+    chain!(metadata_entries,get_metadata_entries,BTreeMap<String,String>,NCDBuildConfig);
+    /* Records counts and page fill in the file, for NCDReader::len and build_stats */
+    chain!(build_stats,get_build_stats,bool,NCDBuildConfig);
+    /* Stores each distinct large value once, however many keys it has. Implies external keys. */
+    chain!(dedup,get_dedup,bool,NCDBuildConfig);
+    /* With dedup, also shares smaller values between entries in the same page */
+    chain!(dedup_in_pages,get_dedup_in_pages,bool,NCDBuildConfig);
+    /* KeepFirst by default, which is what lookups found before there was a choice */
+    chain!(duplicates,get_duplicates,NCDDuplicatePolicy,NCDBuildConfig);
+    /* Groups every value given for a key into a list, for NCDReader::get_all. Replaces the duplicate policy. */
+    chain!(multi_valued,get_multi_valued,bool,NCDBuildConfig);
+    /* Stores keys alone, or only their fingerprints, for NCDSetReader. Values from the source are ignored, and
+     * so are repeated keys.
+     */
+    chain!(set,get_set,Option<NCDSetMode>,NCDBuildConfig);
+    /* Finds entries through a perfect hash of each page's keys rather than an open-addressed table, so that no
+     * table slots are left empty and every lookup takes one probe. Builds take longer.
+     */
+    chain!(perfect_hash,get_perfect_hash,bool,NCDBuildConfig);
+    /* Rounds pages up to a multiple of this many bytes, eg the block size of the storage or the chunk size of a
+     * cache, so that each page read is of whole blocks. Not with page compression.
+     */
+    chain!(page_alignment,get_page_alignment,Option<u32>,NCDBuildConfig);
+    /* With page_alignment, moves external values which would straddle more blocks than they need to the start of
+     * the next.
+     */
+    chain!(align_external,get_align_external,bool,NCDBuildConfig);
+
+    pub fn metadata_entry(&self, key: &str, value: &str) -> NCDBuildConfig {
+        let mut out = self.clone();
+        out.metadata = true;
+        out.metadata_entries.insert(key.to_string(),value.to_string());
+        out
+    }
+
+    fn describe(&self) -> String {
+        format!("target_page_size={} target_load_factor={} heap_wiggle_room={} min_entries_per_page={} external_threshold={} external_keys={} fingerprint_bits={:?} hash_algorithm={:?} compression={:?} dictionary_size={} page_compression={:?} dedup={} perfect_hash={} page_alignment={:?}",
+            self.target_page_size,self.target_load_factor,self.heap_wiggle_room,self.min_entries_per_page,self.external_trheshold,
+            self.external_keys,self.fingerprint_bits,self.hash_algorithm,self.compression,self.dictionary_size,self.page_compression,
+            self.dedup,self.perfect_hash,self.page_alignment)
+    }
+
+    /* entries under ncd. describe the build, and those given in the config are added over them */
+    fn make_metadata(&self, stats: &NCDStats) -> Result<Option<Vec<u8>>,NCDError> {
+        if !self.metadata {
+            return Ok(None);
+        }
+        let build_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut metadata = BTreeMap::new();
+        metadata.insert("ncd.version".to_string(),env!("CARGO_PKG_VERSION").to_string());
+        metadata.insert("ncd.build_time".to_string(),build_time.to_string());
+        metadata.insert("ncd.records".to_string(),stats.number_of_keys.to_string());
+        metadata.insert("ncd.config".to_string(),self.describe());
+        metadata.extend(self.metadata_entries.clone());
+        let block = encode_metadata(&metadata)?;
+        if u32::try_from(block.len()).is_err() {
+            return Err(NCDError::BadConfiguration("metadata too large".to_string()));
+        }
+        Ok(Some(block))
+    }
+
+    /* the shortest values dedup shares between pages, roughly: those big enough to go external */
+    fn shared_from(&self) -> Option<usize> {
+        match (self.dedup,self.dedup_in_pages) {
+            (false,_) => None,
+            (true,true) => Some(0),
+            (true,false) => Some((self.external_trheshold * self.target_page_size as f64) as usize)
+        }
+    }
+
+    fn duplicate_policy(&self) -> NCDDuplicatePolicy {
+        if self.set.is_some() {
+            NCDDuplicatePolicy::KeepFirst
+        } else if self.multi_valued {
+            NCDDuplicatePolicy::Merge(NCDMerge::new(|_,earlier,later| [earlier,later].concat()))
+        } else {
+            self.duplicates.clone()
+        }
+    }
+
+    fn initial_seed(&self) -> u128 {
+        match (self.hash_seed,self.hash_algorithm) {
+            (Some(seed),_) => seed,
+            (None,NCDHashAlgorithm::SipHash) => rand::random(),
+            (None,_) => 0
+        }
+    }
+
+    fn flags(&self) -> Result<u32,NCDError> {
+        let external_keys = if self.external_keys { FLAG_EXTERNAL_KEYS } else { 0 };
+        let dedup = if self.dedup { FLAG_EXTERNAL_KEYS | FLAG_SHARED_VALUES } else { 0 };
+        let multi_valued = if self.multi_valued { FLAG_MULTI_VALUED } else { 0 };
+        if self.set.is_some() && (self.compression.is_some() || self.dedup || self.multi_valued) {
+            return Err(NCDError::BadConfiguration("sets have no values to compress, share or group".to_string()));
+        }
+        match self.page_alignment {
+            Some(0) => { return Err(NCDError::BadConfiguration("page alignment of 0".to_string())); },
+            Some(_) if self.page_compression.is_some() => {
+                return Err(NCDError::BadConfiguration("compressed pages can't be aligned".to_string()));
+            },
+            _ => {}
+        }
+        if self.perfect_hash && self.set == Some(NCDSetMode::Fingerprints) {
+            return Err(NCDError::BadConfiguration("sets of fingerprints only keep no keys to hash perfectly".to_string()));
+        }
+        let set = match self.set {
+            Some(NCDSetMode::Keys) => FLAG_SET,
+            Some(NCDSetMode::Fingerprints) => FLAG_SET | FLAG_FINGERPRINTS_ONLY,
+            None => 0
+        };
+        Ok(external_keys | dedup | multi_valued | set | fingerprint_flags(self.fingerprint_bits)?)
+    }
+
+    /* the header extensions this config asks for. The dictionary length is filled in once it is known. */
+    fn extend_header(&self, header: NCDHeader, seed: u128) -> Result<NCDHeader,NCDError> {
+        let mut header = header.with_flags(self.flags()?).with_hash(self.hash_algorithm,seed);
+        if self.compression.is_some() {
+            header = header.with_compression(0);
+        }
+        if let Some(compression) = self.page_compression {
+            header = header.with_page_compression(compression.codec());
+        }
+        if self.metadata {
+            header = header.with_metadata(0,0);
+        }
+        if self.build_stats {
+            header = header.with_build_stats(0);
+        }
+        Ok(header)
+    }
+
+    fn make_compressor(&self, source: &dyn NCDValueSource) -> Result<Option<NCDCompressor>,NCDError> {
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => { return Ok(None); }
+        };
+        let mut samples = vec![];
+        if self.dictionary_size > 0 {
+            for key_value in wrap_io_error(source.iter())?.take(self.dictionary_samples) {
+                samples.push(wrap_io_error(key_value)?.1);
+            }
+        }
+        let dictionary = if samples.is_empty() { vec![] } else { make_dictionary(compression,&samples,self.dictionary_size) };
+        if u32::try_from(dictionary.len()).is_err() {
+            return Err(NCDError::BadConfiguration("dictionary too large".to_string()));
+        }
+        Ok(Some(NCDCompressor::new(compression,dictionary)?))
+    }
 }
 
 /* Parameters:
@@ -58,21 +266,49 @@ impl NCDBuildConfig {
  *
  * If we run out of either heap or table, we increase p by 50%.
  * N/pf must be at least 100 to avoid full tables, S can increase if nessesary.
+ *
+ * With perfect hashing the table takes about (k+1/2)N/p, a pointer per entry and a displacement per four, in
+ * place of kN/pf.
  */
 
+/* bytes a repeated value is guessed to take, with dedup */
+const REPEAT_GUESS : usize = 16;
+
 struct NCDStats {
     number_of_keys: u64,
     total_length: u64
 }
 
 impl NCDStats {
-    fn new(source: &dyn NCDValueSource) -> Result<NCDStats,NCDError> {
+    /* with a compressor, values are measured as they will be stored. With dedup, repeats of values which would be
+     * shared are guessed to cost about as much as a reference to them. Too low a guess is put right by the usual
+     * rebuilds. Sets measure only what they store of the keys.
+     */
+    fn new(config: &NCDBuildConfig, source: &dyn NCDValueSource, mut compressor: Option<&mut NCDCompressor>) -> Result<NCDStats,NCDError> {
+        let shared_from = config.shared_from();
         let mut number_of_keys = 0;
         let mut total_length = 0;
+        let mut seen = HashSet::new();
         for key_value in wrap_io_error(source.iter())? {
             let (key,value) = wrap_io_error(key_value)?;
+            if let Some(mode) = config.set {
+                number_of_keys += 1;
+                total_length += match mode {
+                    NCDSetMode::Keys => key.len() as u64 + 2,
+                    NCDSetMode::Fingerprints => config.fingerprint_bits.unwrap_or(32) as u64/8
+                };
+                continue;
+            }
+            let mut value_len = match &mut compressor {
+                Some(compressor) => compressor.encode(&value)?.len(),
+                None => value.len()
+            };
+            let shared = shared_from.map(|min| value_len >= min).unwrap_or(false);
+            if shared && !seen.insert(compute_full_hash(NCDHashAlgorithm::Xxh3,0,&value)?) {
+                value_len = value_len.min(REPEAT_GUESS);
+            }
             number_of_keys += 1;
-            total_length += (key.len() + value.len() + 6) as u64;
+            total_length += (key.len() + value_len + 6) as u64;
         }
         Ok(NCDStats { number_of_keys, total_length })
     }
@@ -93,7 +329,8 @@ fn make_stamp() -> u32 {
 
 fn guess_number_of_pages(config: &NCDBuildConfig, stats: &NCDStats) -> u64 {
     let pointer_size_k = if config.target_page_size < 65536 { 2. } else { 4. };
-    let total_table_space_needed = pointer_size_k * stats.number_of_keys as f64 / config.target_load_factor;
+    let table_space_per_key = if config.perfect_hash { pointer_size_k + 0.5 } else { pointer_size_k / config.target_load_factor };
+    let total_table_space_needed = table_space_per_key * stats.number_of_keys as f64;
     let total_heap_space_needed = config.heap_wiggle_room * stats.total_length as f64;
     let total_space_needed = total_table_space_needed + total_heap_space_needed;
     let reduced_page_size = config.target_page_size - (HEADER_SIZE as u32); // to ensure header space always available
@@ -106,18 +343,30 @@ fn guess_entries_par_page(stats: &NCDStats, number_of_pages: u64) -> u64 {
     entries_per_page
 }
 
-fn initial_header_guess(config: &NCDBuildConfig, stats: &NCDStats, stamp: u32) -> Result<(NCDHeader,u64),NCDError> {
+fn initial_header_guess(config: &NCDBuildConfig, stats: &NCDStats, stamp: u32, seed: u128) -> Result<(NCDHeader,u64),NCDError> {
     if stats.number_of_keys == 0 {
-        return Ok((NCDHeader::new(1,HEADER_SIZE as u32,0,None,stamp)?,0));
+        let heap_size = config.extend_header(NCDHeader::new(1,HEADER_SIZE as u32,0,None,stamp)?,seed)?.size() as u32;
+        return Ok((config.extend_header(NCDHeader::new(1,heap_size,0,None,stamp)?,seed)?,0));
     }
     let pointer_size_k = if config.target_page_size < 65536 { 2. } else { 4. };
     let number_of_pages = guess_number_of_pages(config,stats);
     let entries_per_page = guess_entries_par_page(stats,number_of_pages);
     let table_size_entries = (entries_per_page as f64 / config.target_load_factor as f64) as u32 + 1;
     let table_size_bytes = table_size_entries * (pointer_size_k as u32);
-    let heap_size = (config.target_page_size - table_size_bytes - 4).max(HEADER_SIZE as u32);
+    /* room for the header should a reseed add hash parameters, and for any other extensions */
+    let heap_size = (config.target_page_size - table_size_bytes - 4).max(MAX_HEADER_SIZE as u32);
     let external_minimum = config.external_trheshold * (heap_size as f64);
-    Ok((NCDHeader::new(number_of_pages,heap_size,table_size_entries,config.force_header_size,stamp)?,(external_minimum as u64).max(16)))
+    let mut header = NCDHeader::new(number_of_pages,heap_size,table_size_entries,config.force_header_size,stamp)?;
+    if let Some(alignment) = config.page_alignment.filter(|alignment| *alignment > 0) {
+        /* padding the heap can take pages past 64k and so to longer pointers, which is settled second time round */
+        for _ in 0..2 {
+            let padding = (alignment - header.page_size() % alignment) % alignment;
+            let force_header_size = Some(header.pointer_length() as u32);
+            header = NCDHeader::new(number_of_pages,header.heap_size()+padding,table_size_entries,force_header_size,stamp)?;
+        }
+    }
+    let header = config.extend_header(header,seed)?;
+    Ok((header,(external_minimum as u64).max(16)))
 }
 
 pub struct NCDBuild<'a> {
@@ -126,7 +375,12 @@ pub struct NCDBuild<'a> {
     header: NCDHeader,
     threshold: u64,
     filename: PathBuf,
-    failure_reason: String
+    failure_reason: String,
+    reseeds: u32,
+    compressor: Option<NCDCompressor>,
+    metadata: Option<Vec<u8>>,
+    /* with perfect hashing, of the tables used while adding */
+    scratch_slots: u32
 }
 
 impl<'a> NCDBuild<'a> {
@@ -138,13 +392,29 @@ impl<'a> NCDBuild<'a> {
 
     fn crank_page_count(&self) -> Result<NCDHeader,NCDError> {
         let new_pages = self.header.number_of_pages() as f64 * self.config.rebuild_page_factor;
-        NCDHeader::new(new_pages as u64,self.header.heap_size(),self.header.table_size_entries(),self.config.force_header_size,self.header.stamp())
+        Ok(self.header.clone().with_number_of_pages(new_pages as u64))
     }
 
     #[cfg(test)]
     #[allow(unused)]
     pub(super) fn testharness_header(&mut self) -> &mut NCDHeader { &mut self.header }
 
+    fn is_pathological(&self, overflow: Option<NCDOverflow>) -> bool {
+        match (self.config.reseed_factor,overflow) {
+            (Some(factor),Some(overflow)) if self.reseeds < self.config.max_reseeds => {
+                let share = overflow.keys_added as f64 / self.header.number_of_pages() as f64;
+                overflow.page_entries as f64 > factor * share.max(1.)
+            },
+            _ => false
+        }
+    }
+
+    fn reseed(&mut self) {
+        self.header = self.header.clone().with_hash(self.header.hash_algorithm(),rand::random());
+        self.reseeds += 1;
+        self.failure_reason = "page overflow from clumped keys, reseeding".to_string();
+    }
+
     fn fix_table_full(&mut self) -> Result<(),NCDError> {
         self.header = self.crank_page_count()?;
         Ok(())
@@ -157,7 +427,35 @@ impl<'a> NCDBuild<'a> {
 
     pub fn attempt<F>(&mut self, progress: F) -> Result<bool,NCDError> where F: Fn(usize,f64) + 'static {
         let mut writer = NCDWriteAttempt::new(&self.header,&self.filename,self.threshold,progress)?;
-        match writer.add_all(self.source) {
+        if let Some(compressor) = &mut self.compressor {
+            writer.compress_with(compressor)?;
+        }
+        if let Some(block) = &self.metadata {
+            writer.write_metadata(block)?;
+        }
+        if self.config.build_stats {
+            writer.record_build_stats()?;
+        }
+        if let Some(compression) = self.config.page_compression {
+            writer.compress_pages_with(compression)?;
+        }
+        if self.config.dedup {
+            writer.share_values(self.config.dedup_in_pages)?;
+        }
+        if self.config.perfect_hash {
+            writer.perfect_hash(self.scratch_slots)?;
+        }
+        if let (Some(alignment),true) = (self.config.page_alignment,self.config.align_external) {
+            writer.align_external(alignment as u64);
+        }
+        writer.on_duplicate(self.config.duplicate_policy());
+        let result = writer.add_all(self.source);
+        let overflow = writer.overflow();
+        match result {
+            Err(NCDError::TableFull) | Err(NCDError::HeapFull) if self.is_pathological(overflow) => {
+                self.reseed();
+                Ok(false)
+            },
             Err(NCDError::TableFull) => {
                 self.failure_reason = "table overflow".to_string();
                 self.fix_table_full()?;
@@ -179,11 +477,28 @@ impl<'a> NCDBuild<'a> {
     }
 
     pub fn new(config: &NCDBuildConfig, source: &'a dyn NCDValueSource, filename: &Path) -> Result<NCDBuild<'a>,NCDError> {
-        let stats = NCDStats::new(source)?;
-        let (header,threshold) = initial_header_guess(config,&stats,make_stamp())?;
+        let mut compressor = config.make_compressor(source)?;
+        let stats = NCDStats::new(config,source,compressor.as_mut())?;
+        let (mut header,threshold) = initial_header_guess(config,&stats,make_stamp(),config.initial_seed())?;
+        let scratch_slots = header.table_size_entries();
+        if config.perfect_hash {
+            header = header.with_perfect_hash();
+        }
+        let dictionary_len = compressor.as_ref().map(|c| c.dictionary().len() as u32).unwrap_or(0);
+        if compressor.is_some() {
+            header = header.with_compression(dictionary_len);
+        }
+        let metadata = config.make_metadata(&stats)?;
+        let metadata_len = metadata.as_ref().map(|block| block.len()).unwrap_or(0);
+        if metadata.is_some() {
+            header = header.with_metadata(dictionary_len as u64,metadata_len as u32);
+        }
+        if config.build_stats {
+            header = header.with_build_stats(dictionary_len as u64+metadata_len as u64);
+        }
         Ok(NCDBuild { 
             source, header, threshold, config: config.clone(), filename: filename.to_path_buf(), 
-            failure_reason: "uninitialized".to_string() })
+            failure_reason: "uninitialized".to_string(), reseeds: 0, compressor, metadata, scratch_slots })
     }
 }
 
@@ -198,8 +513,16 @@ mod tests {
     use crate::header::NCDHeader;
     use crate::read::{ NCDReader };
     use crate::sources::hashmap::NCDHashMapValueSource;
-    use crate::test::{numeric_key_values, temporary_path};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::{self, Cursor};
+    use std::rc::Rc;
+
+    use crate::{NCDHashAlgorithm, NCDReadAccessor, NCDReadKind, StdNCDReadAccessor};
+    use crate::bitbash::compute_hash;
+    use crate::test::{ListSource, build_file, build_source, numeric_key_values, temporary_path};
     use crate::util::{NCDError, wrap_io_error};
+    use crate::write::{NCDDuplicatePolicy, NCDMerge};
 
     use super::{NCDStats, initial_header_guess};
     
@@ -340,7 +663,7 @@ mod tests {
 
     fn header_test(config: &NCDBuildConfig, number_of_keys: u64, length_each: u64) -> Result<(u64,u32,u32,u64),NCDError> {
         let stats = NCDStats::new_values(number_of_keys,number_of_keys*length_each);
-        let (header,threshold) = initial_header_guess(config,&stats,0)?;
+        let (header,threshold) = initial_header_guess(config,&stats,0,0)?;
         if header.table_size_entries() > 0 {
             assert_eq!(32768,header.page_size());
         }
@@ -363,4 +686,176 @@ mod tests {
     fn test_header() {
         do_test_header().unwrap()
     }
+
+    fn check_all(file: Vec<u8>, data: &HashMap<Vec<u8>,Vec<u8>>) -> Result<NCDHeader,NCDError> {
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+        for (key,value) in data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        assert_eq!(None,reader.get(b"missing")?);
+        Ok(reader.header().clone())
+    }
+
+    fn do_test_hash_algorithms() -> Result<(),NCDError> {
+        let data = numeric_key_values(COUNT);
+        for algorithm in [NCDHashAlgorithm::Murmur3,NCDHashAlgorithm::Xxh3,NCDHashAlgorithm::SipHash] {
+            for seed in [None,Some(0),Some(0x0123456789ABCDEF_FEDCBA9876543210)] {
+                let config = NCDBuildConfig::new().target_page_size(1024).hash_algorithm(algorithm).hash_seed(seed);
+                let header = check_all(build_file(data.clone(),&config)?,&data)?;
+                assert_eq!(algorithm,header.hash_algorithm());
+                match (algorithm,seed) {
+                    (NCDHashAlgorithm::SipHash,None) => { assert_ne!(0,header.hash_seed()); },
+                    (_,seed) => { assert_eq!(seed.unwrap_or(0),header.hash_seed()); }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        do_test_hash_algorithms().unwrap();
+    }
+
+    /* keys which all land in the first page with seed 0 are spread by reseeding, without adding pages */
+    fn do_test_reseed() -> Result<(),NCDError> {
+        const KEYS : u64 = 200;
+        let config = NCDBuildConfig::new().target_page_size(1024).reseed_factor(Some(3.));
+        let (header,_) = initial_header_guess(&config,&NCDStats::new_values(KEYS,KEYS*15),0,0)?;
+        assert!(header.number_of_pages() > 1);
+        let mut data = HashMap::new();
+        for i in 0.. {
+            let key = format!("{:08}",i).into_bytes();
+            if header.hash_page_index(compute_hash(&key)?) == 0 {
+                data.insert(key,b"v".to_vec());
+                if data.len() as u64 == KEYS { break; }
+            }
+        }
+        let path = wrap_io_error(temporary_path())?;
+        let source = NCDHashMapValueSource::new(data.clone());
+        let mut builder = NCDBuild::new(&config,&source,&path)?;
+        assert_eq!(header.number_of_pages(),builder.testharness_header().number_of_pages());
+        assert!(!builder.attempt(|_,_| {})?);
+        assert!(builder.result().contains("reseeding"));
+        let reseeded = builder.testharness_header().clone();
+        assert_eq!(header.number_of_pages(),reseeded.number_of_pages());
+        assert_ne!(0,reseeded.hash_seed());
+        while !builder.attempt(|_,_| {})? {}
+        let file = wrap_io_error(std::fs::read(&path))?;
+        check_all(file,&data)?;
+        Ok(())
+    }
+
+    /* each key is given three times, some of them with values big enough to go external */
+    fn duplicated_source() -> ListSource {
+        let mut list = vec![];
+        for round in 0..3 {
+            for i in 0..300 {
+                let value = if i%10 == 0 { format!("{}:{}",round,"x".repeat(500)) } else { format!("{}:{}",round,i) };
+                list.push((format!("{}",i).into_bytes(),value.into_bytes()));
+            }
+        }
+        list.push((b"once".to_vec(),b"only".to_vec()));
+        ListSource(list)
+    }
+
+    fn do_test_duplicates(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let source = duplicated_source();
+        match build_source(&source,&config.duplicates(NCDDuplicatePolicy::Error)) {
+            Err(NCDError::DuplicateKey(key,position)) => { assert_eq!((b"0".to_vec(),300),(key,position)); },
+            _ => { panic!("duplicate not found"); }
+        }
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(build_source(&source,config)?)))?)?;
+        assert_eq!(Some(b"0:1".to_vec()),reader.get(b"1")?);
+        let merge = NCDMerge::new(|key,earlier,later| [key,earlier,later].join(&b","[..]));
+        for policy in [NCDDuplicatePolicy::KeepFirst,NCDDuplicatePolicy::KeepLast,NCDDuplicatePolicy::Merge(merge)] {
+            let file = build_source(&source,&config.duplicates(policy.clone()).build_stats(true))?;
+            let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+            assert_eq!(Some(301),reader.len()?);
+            for i in 0..300 {
+                let key = format!("{}",i).into_bytes();
+                let values = source.0.iter().filter(|(k,_)| k == &key).map(|(_,v)| v.clone()).collect::<Vec<_>>();
+                let expected = match policy {
+                    NCDDuplicatePolicy::KeepFirst => values[0].clone(),
+                    NCDDuplicatePolicy::KeepLast => values[2].clone(),
+                    _ => {
+                        let once = [&key[..],&values[0],&values[1]].join(&b","[..]);
+                        [&key[..],&once,&values[2]].join(&b","[..])
+                    }
+                };
+                assert_eq!(Some(expected.clone()),reader.get(&key)?);
+                assert_eq!(Some(expected.len() as u64),reader.value_len(&key)?);
+            }
+            assert_eq!(Some(b"only".to_vec()),reader.get(b"once")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_duplicates(&config).unwrap();
+        do_test_duplicates(&config.external_keys(true)).unwrap();
+        do_test_duplicates(&config.dedup(true)).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_duplicates(&config.compression(Some(crate::NCDCompression::Zstd(3))).page_compression(Some(crate::NCDCompression::Lz4))).unwrap();
+    }
+
+    #[test]
+    fn test_reseed() {
+        do_test_reseed().unwrap();
+    }
+
+    /* the kind, offset and length of every read */
+    struct RecordingAccessor<T>(T,Rc<RefCell<Vec<(NCDReadKind,u64,u64)>>>);
+
+    impl<T: NCDReadAccessor> NCDReadAccessor for RecordingAccessor<T> {
+        fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.read_kind(NCDReadKind::Other,offset,length)
+        }
+
+        fn read_kind(&mut self, kind: NCDReadKind, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.1.borrow_mut().push((kind,offset,length));
+            self.0.read_kind(kind,offset,length)
+        }
+    }
+
+    fn do_test_alignment(config: &NCDBuildConfig, alignment: u64, external: bool) -> Result<(),NCDError> {
+        let mut data = numeric_key_values(COUNT);
+        for i in 0..50 {
+            data.insert(format!("big{}",i).into_bytes(),vec![b'a'+(i%26) as u8;300+i*37]);
+        }
+        let file = build_file(data.clone(),&config.page_alignment(Some(alignment as u32)).align_external(external))?;
+        let reads = Rc::new(RefCell::new(vec![]));
+        let accessor = RecordingAccessor(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?,reads.clone());
+        let mut reader = NCDReader::new(accessor)?;
+        assert_eq!(0,reader.header().page_size() as u64 % alignment);
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        for (kind,offset,length) in reads.borrow().iter() {
+            match kind {
+                NCDReadKind::Page => { assert_eq!((0,0),(offset % alignment,length % alignment)); },
+                NCDReadKind::External if external => {
+                    assert_eq!(length.div_ceil(alignment),(offset+length-1)/alignment - offset/alignment + 1);
+                },
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_alignment() {
+        let config = NCDBuildConfig::new().target_page_size(1000);
+        do_test_alignment(&config,512,false).unwrap();
+        do_test_alignment(&config,512,true).unwrap();
+        do_test_alignment(&config,100,true).unwrap();
+        do_test_alignment(&config.perfect_hash(true),512,true).unwrap();
+        /* rounding up past 64k needs four-byte pointers */
+        do_test_alignment(&config.target_page_size(65500),4096,true).unwrap();
+        assert!(build_file(numeric_key_values(10),&config.page_alignment(Some(0))).is_err());
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        assert!(build_file(numeric_key_values(10),&config.page_alignment(Some(512)).page_compression(Some(crate::NCDCompression::Lz4))).is_err());
+    }
 }
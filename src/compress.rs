@@ -0,0 +1,231 @@
+use crate::bitbash::{MAX_LESQLITE2_BYTES, lesqlite2_read, lesqlite2_write};
+use crate::util::NCDError;
+#[cfg(feature="zstd")]
+use crate::util::wrap_io_error;
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum NCDCompression {
+    /* with the compression level */
+    Zstd(i32),
+    Lz4
+}
+
+impl NCDCompression {
+    pub(crate) fn codec(&self) -> u8 {
+        match self {
+            NCDCompression::Zstd(_) => CODEC_ZSTD,
+            NCDCompression::Lz4 => CODEC_LZ4
+        }
+    }
+}
+
+/* In files with compression every value starts with one of these, and compressed values then with their
+ * decompressed length as lesqlite2.
+ */
+const CODEC_RAW : u8 = 0;
+const CODEC_ZSTD : u8 = 1;
+const CODEC_LZ4 : u8 = 2;
+
+/* Longest codec byte and length */
+pub(crate) const MAX_VALUE_PREFIX : usize = 1 + MAX_LESQLITE2_BYTES;
+
+pub(crate) struct NCDValuePrefix {
+    compressed: bool,
+    /* length of the value once decoded */
+    len: u64,
+    /* bytes before the raw or compressed data */
+    size: usize
+}
+
+impl NCDValuePrefix {
+    pub(crate) fn parse(bytes: &[u8]) -> Result<NCDValuePrefix,NCDError> {
+        let codec = *bytes.first().ok_or_else(|| NCDError::CorruptNCDFile("missing codec".to_string()))?;
+        match codec {
+            CODEC_RAW => Ok(NCDValuePrefix { compressed: false, len: 0, size: 1 }),
+            CODEC_ZSTD | CODEC_LZ4 => {
+                let mut size = 1;
+                let len = lesqlite2_read(bytes,&mut size)?;
+                Ok(NCDValuePrefix { compressed: true, len, size })
+            },
+            x => Err(NCDError::CorruptNCDFile(format!("unknown codec {}",x)))
+        }
+    }
+
+    pub(crate) fn compressed(&self) -> bool { self.compressed }
+    pub(crate) fn size(&self) -> usize { self.size }
+
+    /* the length of raw values follows from that of the whole stored value */
+    pub(crate) fn len(&self, stored_len: u64) -> u64 {
+        if self.compressed { self.len } else { stored_len - 1 }
+    }
+}
+
+#[cfg(feature="zstd")]
+fn zstd_compress(compressor: &mut Option<zstd::bulk::Compressor<'static>>, level: i32, dictionary: &[u8], value: &[u8]) -> Result<Vec<u8>,NCDError> {
+    if compressor.is_none() {
+        *compressor = Some(wrap_io_error(zstd::bulk::Compressor::with_dictionary(level,dictionary))?);
+    }
+    wrap_io_error(compressor.as_mut().unwrap().compress(value))
+}
+
+#[cfg(feature="zstd")]
+fn zstd_decompress(dictionary: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>,NCDError> {
+    let mut decompressor = wrap_io_error(zstd::bulk::Decompressor::with_dictionary(dictionary))?;
+    decompressor.decompress(data,len).map_err(|e| NCDError::CorruptNCDFile(format!("bad zstd value: {}",e)))
+}
+
+#[cfg(feature="zstd")]
+fn zstd_dictionary(samples: &[Vec<u8>], size: usize) -> Vec<u8> {
+    zstd::dict::from_samples(samples,size).unwrap_or_default()
+}
+
+#[cfg(not(feature="zstd"))]
+fn zstd_dictionary(_samples: &[Vec<u8>], _size: usize) -> Vec<u8> { vec![] }
+
+#[cfg(not(feature="zstd"))]
+fn zstd_decompress(_dictionary: &[u8], _data: &[u8], _len: usize) -> Result<Vec<u8>,NCDError> {
+    Err(NCDError::UnsupportedVersion("built without zstd support".to_string()))
+}
+
+#[cfg(feature="lz4_flex")]
+fn lz4_compress(dictionary: &[u8], value: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress_with_dict(value,dictionary)
+}
+
+#[cfg(feature="lz4_flex")]
+fn lz4_decompress(dictionary: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>,NCDError> {
+    lz4_flex::block::decompress_with_dict(data,len,dictionary).map_err(|e| NCDError::CorruptNCDFile(format!("bad lz4 value: {}",e)))
+}
+
+#[cfg(not(feature="lz4_flex"))]
+fn lz4_decompress(_dictionary: &[u8], _data: &[u8], _len: usize) -> Result<Vec<u8>,NCDError> {
+    Err(NCDError::UnsupportedVersion("built without lz4 support".to_string()))
+}
+
+/* data of a known decompressed length, so long as this build supports the codec */
+pub(crate) fn decompress(codec: u8, dictionary: &[u8], data: &[u8], len: usize) -> Result<Vec<u8>,NCDError> {
+    let out = match codec {
+        CODEC_ZSTD => zstd_decompress(dictionary,data,len)?,
+        CODEC_LZ4 => lz4_decompress(dictionary,data,len)?,
+        x => { return Err(NCDError::CorruptNCDFile(format!("unknown codec {}",x))); }
+    };
+    if out.len() != len {
+        return Err(NCDError::CorruptNCDFile(format!("decompressed to {} bytes, not {}",out.len(),len)));
+    }
+    Ok(out)
+}
+
+/* Values come back whole, whichever codec was used */
+pub(crate) fn decode_value(dictionary: &[u8], stored: &[u8]) -> Result<Vec<u8>,NCDError> {
+    let prefix = NCDValuePrefix::parse(stored)?;
+    let data = &stored[prefix.size..];
+    if !prefix.compressed {
+        return Ok(data.to_vec());
+    }
+    decompress(stored[0],dictionary,data,prefix.len(stored.len() as u64) as usize)
+}
+
+/* Builds a dictionary from sample values: trained for zstd, the tail of the samples for lz4. Too few samples
+ * to train on gives no dictionary, rather than an error.
+ */
+pub(crate) fn make_dictionary(compression: NCDCompression, samples: &[Vec<u8>], size: usize) -> Vec<u8> {
+    match compression {
+        NCDCompression::Zstd(_) => zstd_dictionary(samples,size),
+        NCDCompression::Lz4 => {
+            let all = samples.concat();
+            all[all.len().saturating_sub(size)..].to_vec()
+        }
+    }
+}
+
+pub(crate) struct NCDCompressor {
+    compression: NCDCompression,
+    dictionary: Vec<u8>,
+    #[cfg(feature="zstd")]
+    zstd: Option<zstd::bulk::Compressor<'static>>
+}
+
+impl NCDCompressor {
+    pub(crate) fn new(compression: NCDCompression, dictionary: Vec<u8>) -> Result<NCDCompressor,NCDError> {
+        #[cfg(not(feature="zstd"))]
+        if let NCDCompression::Zstd(_) = compression {
+            return Err(NCDError::BadConfiguration("built without zstd support".to_string()));
+        }
+        #[cfg(not(feature="lz4_flex"))]
+        if compression == NCDCompression::Lz4 {
+            return Err(NCDError::BadConfiguration("built without lz4 support".to_string()));
+        }
+        Ok(NCDCompressor {
+            compression, dictionary,
+            #[cfg(feature="zstd")]
+            zstd: None
+        })
+    }
+
+    pub(crate) fn dictionary(&self) -> &[u8] { &self.dictionary }
+
+    #[allow(unused_variables)]
+    pub(crate) fn compress(&mut self, value: &[u8]) -> Result<Vec<u8>,NCDError> {
+        match self.compression {
+            #[cfg(feature="zstd")]
+            NCDCompression::Zstd(level) => zstd_compress(&mut self.zstd,level,&self.dictionary,value),
+            #[cfg(feature="lz4_flex")]
+            NCDCompression::Lz4 => Ok(lz4_compress(&self.dictionary,value)),
+            #[allow(unreachable_patterns)]
+            _ => Err(NCDError::BadConfiguration("compression unsupported in this build".to_string()))
+        }
+    }
+
+    /* Values which don't shrink are stored raw */
+    pub(crate) fn encode(&mut self, value: &[u8]) -> Result<Vec<u8>,NCDError> {
+        let compressed = self.compress(value)?;
+        let mut out = vec![0;MAX_VALUE_PREFIX+compressed.len().max(value.len())];
+        let mut offset = 1;
+        if compressed.len() + MAX_LESQLITE2_BYTES < value.len() {
+            out[0] = self.compression.codec();
+            lesqlite2_write(&mut out,&mut offset,value.len() as u64)?;
+            out[offset..offset+compressed.len()].copy_from_slice(&compressed);
+            out.truncate(offset+compressed.len());
+        } else {
+            out[0] = CODEC_RAW;
+            out[offset..offset+value.len()].copy_from_slice(value);
+            out.truncate(offset+value.len());
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(all(test,feature="zstd",feature="lz4_flex"))]
+mod test {
+    use crate::util::NCDError;
+
+    use super::{NCDCompression, NCDCompressor, NCDValuePrefix, decode_value, make_dictionary};
+
+    fn do_test_codecs() -> Result<(),NCDError> {
+        let samples = (0..1000).map(|i| format!("{{\"id\":{},\"name\":\"item {}\",\"tags\":[\"alpha\",\"beta\"]}}",i,i).into_bytes()).collect::<Vec<_>>();
+        let long = samples.concat();
+        for compression in [NCDCompression::Zstd(3),NCDCompression::Lz4] {
+            for dictionary in [vec![],make_dictionary(compression,&samples,4096)] {
+                let mut compressor = NCDCompressor::new(compression,dictionary.clone())?;
+                for value in [&b""[..],b"x",&samples[7],&long] {
+                    let stored = compressor.encode(value)?;
+                    assert_eq!(value,decode_value(&dictionary,&stored)?.as_slice());
+                    let prefix = NCDValuePrefix::parse(&stored)?;
+                    assert_eq!(value.len() as u64,prefix.len(stored.len() as u64));
+                }
+                assert!(NCDValuePrefix::parse(&compressor.encode(&long)?)?.compressed());
+                assert!(!NCDValuePrefix::parse(&compressor.encode(b"x")?)?.compressed());
+                assert!(compressor.encode(&long)?.len() < long.len()/5);
+            }
+            assert!(!make_dictionary(compression,&samples,4096).is_empty());
+        }
+        assert!(decode_value(&[],&[9,1,2]).is_err());
+        assert!(decode_value(&[],&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_codecs() {
+        do_test_codecs().unwrap();
+    }
+}
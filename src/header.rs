@@ -1,22 +1,95 @@
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
-use crate::bitbash::{all_set, read_u32, read_u64, write_u32, write_u64};
-use crate::read::{NCDReadAccessor};
+use crate::bitbash::{NCDHashAlgorithm, all_set, compute_full_hash, read_u32, read_u64, write_u32, write_u64};
+use crate::metadata::BUILD_STATS_SIZE;
+use crate::read::{NCDReadAccessor, NCDReadKind};
 use crate::util::{NCDError, wrap_io_error};
 
 // chosen to very much repel text tools (and an N).
 pub const MAGIC_NUMBER : u32 = 0x4E00C0FE; // be->le byteswapped, so first byte in file is 0xFE
 
+/* low byte of the version field is the version proper, the rest are flags for optional format extensions */
+const VERSION_MASK : u32 = 0xFF;
+/* external pointers also hold the key and value length, so lookups can be settled from the page */
+pub(crate) const FLAG_EXTERNAL_KEYS : u32 = 0x100;
+/* width of the key fingerprint in external pointers: 0 for the original 32 bits, which overlap those used for
+ * page and slot selection, else 32 or 64 bits from the otherwise unused low half of the hash, or all 128 bits.
+ */
+const FINGERPRINT_SHIFT : u32 = 9;
+const FINGERPRINT_MASK : u32 = 0x600;
+/* the header is followed by a hash algorithm id (4) and seed (16) */
+const FLAG_HASH_PARAMS : u32 = 0x800;
+/* values are prefixed by a codec, and the header followed by the length of the dictionary (4), which is at the
+ * start of the external area
+ */
+const FLAG_COMPRESSION : u32 = 0x1000;
+/* pages are compressed whole and follow the external area, found through an index of their offsets after the
+ * header. The header is followed by the page codec (4).
+ */
+const FLAG_PAGE_COMPRESSION : u32 = 0x2000;
+/* the header is followed by the offset of a metadata block within the external area (8) and its length (4) */
+const FLAG_METADATA : u32 = 0x4000;
+/* the header is followed by the offset of the build statistics within the external area (8) */
+const FLAG_BUILD_STATS : u32 = 0x8000;
+/* values may be shared between entries: external pointers point at the value alone, which other pointers may
+ * also point at, and internal values may refer to the same value earlier in the heap. Needs external keys.
+ */
+pub(crate) const FLAG_SHARED_VALUES : u32 = 0x10000;
+/* each key's value is a list of values */
+pub(crate) const FLAG_MULTI_VALUED : u32 = 0x20000;
+/* keys without values */
+pub(crate) const FLAG_SET : u32 = 0x40000;
+/* with FLAG_SET: only the fingerprints of the keys, so membership is probable rather than certain */
+pub(crate) const FLAG_FINGERPRINTS_ONLY : u32 = 0x80000;
+/* the table is one slot, pointing at a perfect hash of the page's keys onto its entries */
+const FLAG_PERFECT_HASH : u32 = 0x100000;
+const KNOWN_FLAGS : u32 = FLAG_EXTERNAL_KEYS | FINGERPRINT_MASK | FLAG_HASH_PARAMS | FLAG_COMPRESSION | FLAG_PAGE_COMPRESSION |
+    FLAG_METADATA | FLAG_BUILD_STATS | FLAG_SHARED_VALUES | FLAG_MULTI_VALUED | FLAG_SET | FLAG_FINGERPRINTS_ONLY |
+    FLAG_PERFECT_HASH;
+const EXTENSION_FLAGS : u32 = FLAG_HASH_PARAMS | FLAG_COMPRESSION | FLAG_PAGE_COMPRESSION | FLAG_METADATA | FLAG_BUILD_STATS;
+
+pub(crate) fn fingerprint_flags(bits: Option<u32>) -> Result<u32,NCDError> {
+    let code = match bits {
+        None => 0,
+        Some(32) => 1,
+        Some(64) => 2,
+        Some(128) => 3,
+        Some(x) => { return Err(NCDError::BadConfiguration(format!("unsupported fingerprint width {}",x))); }
+    };
+    Ok(code << FINGERPRINT_SHIFT)
+}
+
+/* What a page parser needs to know about the heap entries in this file */
+#[derive(Clone,Copy)]
+pub(crate) struct NCDEntryFormat {
+    pub(crate) external_keys: bool,
+    pub(crate) fingerprint_size: usize,
+    pub(crate) shared_values: bool,
+    pub(crate) set: bool,
+    pub(crate) fingerprints_only: bool
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
 pub struct NCDHeader {
     #[allow(unused)]
     version: u32,
     number_of_pages: u64,
     heap_size: u32,
     table_size: u32,
-    stamp: u32
+    stamp: u32,
+    hash_algorithm: NCDHashAlgorithm,
+    hash_seed: u128,
+    dictionary_len: u32,
+    page_codec: u32,
+    metadata_offset: u64,
+    metadata_len: u32,
+    build_stats_offset: u64
 }
 
 pub(crate) const HEADER_SIZE : usize = 28;
+/* with every extension: readers fetch this much so that the header never takes two requests */
+pub(crate) const MAX_HEADER_SIZE : usize = HEADER_SIZE + 20 + 4 + 4 + 12 + 8;
 
 impl NCDHeader {
     pub fn new(number_of_pages: u64, heap_size: u32, table_size: u32, force_header_size: Option<u32>, stamp: u32) -> Result<NCDHeader,NCDError> {
@@ -32,13 +105,24 @@ impl NCDHeader {
             number_of_pages,
             heap_size,
             table_size,
-            stamp
+            stamp,
+            hash_algorithm: NCDHashAlgorithm::Murmur3,
+            hash_seed: 0,
+            dictionary_len: 0,
+            page_codec: 0,
+            metadata_offset: 0,
+            metadata_len: 0,
+            build_stats_offset: 0
         })
     }
 
     pub fn read(accessor: &mut dyn NCDReadAccessor) -> Result<NCDHeader,NCDError> {
-        let vec = wrap_io_error(accessor.read(0, HEADER_SIZE as u64))?;
-        let bytes = vec.as_slice();
+        let vec = wrap_io_error(accessor.read_kind(NCDReadKind::Header,0,MAX_HEADER_SIZE as u64))?;
+        NCDHeader::parse(&vec)
+    }
+
+    /* from the start of the file, which may extend beyond the header */
+    pub fn parse(bytes: &[u8]) -> Result<NCDHeader,NCDError> {
         let mut offset = 0;
         let magic_number = read_u32(bytes,&mut offset)?;
         let version = read_u32(bytes,&mut offset)?;
@@ -49,10 +133,47 @@ impl NCDHeader {
         if magic_number != MAGIC_NUMBER {
             return Err(NCDError::CorruptNCDFile(format!("Bad magic number {0:x}, not an NCD file",magic_number)));
         }
-        if version > 1 {
-            return Err(NCDError::CorruptNCDFile(format!("Unsupported version {}",version)));
+        if version & VERSION_MASK > 1 {
+            return Err(NCDError::CorruptNCDFile(format!("Unsupported version {}",version & VERSION_MASK)));
+        }
+        if version & !(VERSION_MASK|KNOWN_FLAGS) != 0 {
+            return Err(NCDError::CorruptNCDFile(format!("Unsupported format flags {:x}",version & !VERSION_MASK)));
+        }
+        if version & FLAG_SHARED_VALUES != 0 && version & FLAG_EXTERNAL_KEYS == 0 {
+            return Err(NCDError::CorruptNCDFile("shared values without external keys".to_string()));
+        }
+        if version & FLAG_FINGERPRINTS_ONLY != 0 && version & FLAG_SET == 0 {
+            return Err(NCDError::CorruptNCDFile("fingerprints only outside a set".to_string()));
+        }
+        if version & FLAG_PERFECT_HASH != 0 && table_size > 1 {
+            return Err(NCDError::CorruptNCDFile("perfect hash with more than one table slot".to_string()));
+        }
+        let mut out = NCDHeader {
+            version, number_of_pages, heap_size, table_size, stamp,
+            hash_algorithm: NCDHashAlgorithm::Murmur3, hash_seed: 0, dictionary_len: 0, page_codec: 0,
+            metadata_offset: 0, metadata_len: 0, build_stats_offset: 0
+        };
+        /* extensions follow in flag order */
+        if version & FLAG_HASH_PARAMS != 0 {
+            out.hash_algorithm = NCDHashAlgorithm::from_id(read_u32(bytes,&mut offset)?)?;
+            out.hash_seed = read_u64(bytes,&mut offset)? as u128 | (read_u64(bytes,&mut offset)? as u128) << 64;
+        }
+        if version & FLAG_COMPRESSION != 0 {
+            out.dictionary_len = read_u32(bytes,&mut offset)?;
+        }
+        if version & FLAG_PAGE_COMPRESSION != 0 {
+            out.page_codec = read_u32(bytes,&mut offset)?;
+        }
+        if version & FLAG_METADATA != 0 {
+            out.metadata_offset = read_u64(bytes,&mut offset)?;
+            out.metadata_len = read_u32(bytes,&mut offset)?;
+        }
+        if version & FLAG_BUILD_STATS != 0 {
+            out.build_stats_offset = read_u64(bytes,&mut offset)?;
+        }
+        if (heap_size as usize) < out.size() && number_of_pages > 0 {
+            return Err(NCDError::CorruptNCDFile("Heap too small for header".to_string()));
         }
-        let out = NCDHeader { version, number_of_pages, heap_size, table_size, stamp };
         let page_size_check = table_size as u64 * out.pointer_length() as u64 + heap_size as u64 + 4;
         if page_size_check > 0xFFFFFFFF {
             return Err(NCDError::CorruptNCDFile(format!("Pages too big")));
@@ -60,8 +181,8 @@ impl NCDHeader {
         Ok(out)
     }
 
-    pub fn write(&self, file: &mut File) -> Result<(),NCDError> {
-        let mut bytes = vec![0;HEADER_SIZE];
+    fn to_bytes(&self) -> Result<Vec<u8>,NCDError> {
+        let mut bytes = vec![0;self.size()];
         let mut offset = 0;
         write_u32(&mut bytes,&mut offset,MAGIC_NUMBER)?;
         write_u32(&mut bytes,&mut offset,self.version)?;
@@ -69,12 +190,250 @@ impl NCDHeader {
         write_u32(&mut bytes,&mut offset,self.heap_size)?;
         write_u32(&mut bytes,&mut offset,self.table_size)?;
         write_u32(&mut bytes,&mut offset,self.stamp)?;
+        if self.version & FLAG_HASH_PARAMS != 0 {
+            write_u32(&mut bytes,&mut offset,self.hash_algorithm.id())?;
+            write_u64(&mut bytes,&mut offset,self.hash_seed as u64)?;
+            write_u64(&mut bytes,&mut offset,(self.hash_seed >> 64) as u64)?;
+        }
+        if self.version & FLAG_COMPRESSION != 0 {
+            write_u32(&mut bytes,&mut offset,self.dictionary_len)?;
+        }
+        if self.version & FLAG_PAGE_COMPRESSION != 0 {
+            write_u32(&mut bytes,&mut offset,self.page_codec)?;
+        }
+        if self.version & FLAG_METADATA != 0 {
+            write_u64(&mut bytes,&mut offset,self.metadata_offset)?;
+            write_u32(&mut bytes,&mut offset,self.metadata_len)?;
+        }
+        if self.version & FLAG_BUILD_STATS != 0 {
+            write_u64(&mut bytes,&mut offset,self.build_stats_offset)?;
+        }
+        Ok(bytes)
+    }
+
+    /* A short string holding the header, eg for client config, so that it needn't be fetched:
+     * ncd1.VERSION.PAGES.HEAP.TABLE.STAMP, all in hex, then .ALGORITHM.SEED if the file has hash parameters,
+     * .DICTIONARY_LENGTH if it has compression, .PAGE_CODEC if it has page compression and
+     * .METADATA_OFFSET.METADATA_LENGTH if it has metadata and .BUILD_STATS_OFFSET if it has build statistics.
+     */
+    pub fn descriptor(&self) -> String {
+        let mut out = format!("ncd1.{:x}.{:x}.{:x}.{:x}.{:x}",self.version,self.number_of_pages,self.heap_size,self.table_size,self.stamp);
+        if self.version & FLAG_HASH_PARAMS != 0 {
+            out.push_str(&format!(".{:x}.{:x}",self.hash_algorithm.id(),self.hash_seed));
+        }
+        if self.version & FLAG_COMPRESSION != 0 {
+            out.push_str(&format!(".{:x}",self.dictionary_len));
+        }
+        if self.version & FLAG_PAGE_COMPRESSION != 0 {
+            out.push_str(&format!(".{:x}",self.page_codec));
+        }
+        if self.version & FLAG_METADATA != 0 {
+            out.push_str(&format!(".{:x}.{:x}",self.metadata_offset,self.metadata_len));
+        }
+        if self.version & FLAG_BUILD_STATS != 0 {
+            out.push_str(&format!(".{:x}",self.build_stats_offset));
+        }
+        out
+    }
+
+    pub fn from_descriptor(descriptor: &str) -> Result<NCDHeader,NCDError> {
+        let bad = || NCDError::BadConfiguration(format!("bad header descriptor '{}'",descriptor));
+        let parts = descriptor.trim().split('.').collect::<Vec<_>>();
+        if parts[0] != "ncd1" { return Err(bad()); }
+        let field = |i: usize| parts.get(i).and_then(|x| u128::from_str_radix(x,16).ok()).ok_or_else(bad);
+        let narrow = |x: u128| u32::try_from(x).map_err(|_| bad());
+        let mut header = NCDHeader {
+            version: narrow(field(1)?)?,
+            number_of_pages: u64::try_from(field(2)?).map_err(|_| bad())?,
+            heap_size: narrow(field(3)?)?,
+            table_size: narrow(field(4)?)?,
+            stamp: narrow(field(5)?)?,
+            hash_algorithm: NCDHashAlgorithm::Murmur3,
+            hash_seed: 0,
+            dictionary_len: 0,
+            page_codec: 0,
+            metadata_offset: 0,
+            metadata_len: 0,
+            build_stats_offset: 0
+        };
+        let mut next = 6;
+        if header.version & FLAG_HASH_PARAMS != 0 {
+            header.hash_algorithm = NCDHashAlgorithm::from_id(narrow(field(next)?)?).map_err(|_| bad())?;
+            header.hash_seed = field(next+1)?;
+            next += 2;
+        }
+        if header.version & FLAG_COMPRESSION != 0 {
+            header.dictionary_len = narrow(field(next)?)?;
+            next += 1;
+        }
+        if header.version & FLAG_PAGE_COMPRESSION != 0 {
+            header.page_codec = narrow(field(next)?)?;
+            next += 1;
+        }
+        if header.version & FLAG_METADATA != 0 {
+            header.metadata_offset = u64::try_from(field(next)?).map_err(|_| bad())?;
+            header.metadata_len = narrow(field(next+1)?)?;
+            next += 2;
+        }
+        if header.version & FLAG_BUILD_STATS != 0 {
+            header.build_stats_offset = u64::try_from(field(next)?).map_err(|_| bad())?;
+            next += 1;
+        }
+        if parts.len() != next { return Err(bad()); }
+        /* same checks as a header read from a file */
+        NCDHeader::parse(&header.to_bytes()?)
+    }
+
+    pub fn write(&self, file: &mut File) -> Result<(),NCDError> {
+        let bytes = self.to_bytes()?;
         wrap_io_error(file.seek(SeekFrom::Start(0)))?;
         wrap_io_error(file.write_all(&bytes))?;
         Ok(())
     }
 
-    pub fn pointer_length(&self) -> usize { if self.version == 1 { 2 } else { 4 } }
+    pub(crate) fn with_flags(mut self, flags: u32) -> NCDHeader {
+        /* extensions come with their own methods */
+        self.version |= flags & KNOWN_FLAGS & !EXTENSION_FLAGS;
+        self
+    }
+
+    /* files with the default murmur3 and seed 0 are written without hash parameters, as before they existed */
+    pub(crate) fn with_hash(mut self, algorithm: NCDHashAlgorithm, seed: u128) -> NCDHeader {
+        self.hash_algorithm = algorithm;
+        self.hash_seed = seed;
+        if algorithm == NCDHashAlgorithm::Murmur3 && seed == 0 {
+            self.version &= !FLAG_HASH_PARAMS;
+        } else {
+            self.version |= FLAG_HASH_PARAMS;
+        }
+        self
+    }
+
+    pub(crate) fn with_compression(mut self, dictionary_len: u32) -> NCDHeader {
+        self.version |= FLAG_COMPRESSION;
+        self.dictionary_len = dictionary_len;
+        self
+    }
+
+    pub(crate) fn with_page_compression(mut self, codec: u8) -> NCDHeader {
+        self.version |= FLAG_PAGE_COMPRESSION;
+        self.page_codec = codec as u32;
+        self
+    }
+
+    pub(crate) fn with_metadata(mut self, offset: u64, len: u32) -> NCDHeader {
+        self.version |= FLAG_METADATA;
+        self.metadata_offset = offset;
+        self.metadata_len = len;
+        self
+    }
+
+    pub(crate) fn with_build_stats(mut self, offset: u64) -> NCDHeader {
+        self.version |= FLAG_BUILD_STATS;
+        self.build_stats_offset = offset;
+        self
+    }
+
+    /* the table shrinks to one slot and the heap takes the rest, so pages stay the same size. A heap too big for
+     * two-byte pointers to reach all of, short of the unused value, needs four-byte ones.
+     */
+    pub(crate) fn with_perfect_hash(mut self) -> NCDHeader {
+        self.version |= FLAG_PERFECT_HASH;
+        if self.table_size > 1 {
+            let page_size = self.page_size();
+            if self.pointer_length() == 2 && page_size - 4 - 2 > 0xFFFF {
+                self.version &= !VERSION_MASK;
+            }
+            self.table_size = 1;
+            self.heap_size = page_size - 4 - self.pointer_length() as u32;
+        }
+        self
+    }
+
+    pub(crate) fn with_number_of_pages(mut self, number_of_pages: u64) -> NCDHeader {
+        self.number_of_pages = number_of_pages;
+        self
+    }
+
+    pub fn compressed(&self) -> bool { self.version & FLAG_COMPRESSION != 0 }
+    pub(crate) fn dictionary_len(&self) -> u32 { self.dictionary_len }
+    pub fn pages_compressed(&self) -> bool { self.version & FLAG_PAGE_COMPRESSION != 0 }
+    pub(crate) fn page_codec(&self) -> u8 { self.page_codec as u8 }
+
+    pub(crate) fn build_stats_location(&self) -> Option<(u64,u64)> {
+        if self.version & FLAG_BUILD_STATS != 0 {
+            Some((self.external_start().saturating_add(self.build_stats_offset),BUILD_STATS_SIZE as u64))
+        } else {
+            None
+        }
+    }
+
+    /* absolute offset and length of the metadata block */
+    pub(crate) fn metadata_location(&self) -> Option<(u64,u64)> {
+        if self.version & FLAG_METADATA != 0 {
+            Some((self.external_start().saturating_add(self.metadata_offset),self.metadata_len as u64))
+        } else {
+            None
+        }
+    }
+
+    pub fn hash_algorithm(&self) -> NCDHashAlgorithm { self.hash_algorithm }
+    pub fn hash_seed(&self) -> u128 { self.hash_seed }
+
+    /* bytes at the start of the first page's heap taken by the header */
+    pub(crate) fn size(&self) -> usize {
+        let hash_params = if self.version & FLAG_HASH_PARAMS != 0 { 20 } else { 0 };
+        let compression = if self.version & FLAG_COMPRESSION != 0 { 4 } else { 0 };
+        let page_compression = if self.version & FLAG_PAGE_COMPRESSION != 0 { 4 } else { 0 };
+        let metadata = if self.version & FLAG_METADATA != 0 { 12 } else { 0 };
+        let build_stats = if self.version & FLAG_BUILD_STATS != 0 { 8 } else { 0 };
+        HEADER_SIZE + hash_params + compression + page_compression + metadata + build_stats
+    }
+
+    /* with page compression, the offset of each page and of the end of the last, just after the header */
+    pub(crate) fn page_index_size(&self) -> u64 { self.number_of_pages.saturating_add(1).saturating_mul(8) }
+
+    pub(crate) fn full_hash(&self, key: &[u8]) -> Result<u128,NCDError> {
+        compute_full_hash(self.hash_algorithm,self.hash_seed,key)
+    }
+
+    pub(crate) fn hash(&self, key: &[u8]) -> Result<u64,NCDError> {
+        Ok((self.full_hash(key)? >> 64) as u64)
+    }
+    pub fn external_keys(&self) -> bool { self.version & FLAG_EXTERNAL_KEYS != 0 }
+    pub fn shared_values(&self) -> bool { self.version & FLAG_SHARED_VALUES != 0 }
+    pub fn multi_valued(&self) -> bool { self.version & FLAG_MULTI_VALUED != 0 }
+    pub fn set(&self) -> bool { self.version & FLAG_SET != 0 }
+    pub fn fingerprints_only(&self) -> bool { self.version & FLAG_FINGERPRINTS_ONLY != 0 }
+    pub fn perfect_hash(&self) -> bool { self.version & FLAG_PERFECT_HASH != 0 }
+
+    fn fingerprint_code(&self) -> u32 { (self.version & FINGERPRINT_MASK) >> FINGERPRINT_SHIFT }
+
+    pub fn fingerprint_bits(&self) -> u32 {
+        match self.fingerprint_code() { 0|1 => 32, 2 => 64, _ => 128 }
+    }
+
+    pub(crate) fn entry_format(&self) -> NCDEntryFormat {
+        NCDEntryFormat {
+            external_keys: self.external_keys(),
+            fingerprint_size: (self.fingerprint_bits()/8) as usize,
+            shared_values: self.shared_values(),
+            set: self.set(),
+            fingerprints_only: self.fingerprints_only()
+        }
+    }
+
+    /* from the full 128-bit hash of the key */
+    pub(crate) fn fingerprint(&self, full_hash: u128) -> u128 {
+        match self.fingerprint_code() {
+            0 => self.hash_ext((full_hash >> 64) as u64) as u128,
+            1 => full_hash & 0xFFFFFFFF,
+            2 => full_hash & 0xFFFFFFFFFFFFFFFF,
+            _ => full_hash
+        }
+    }
+
+    pub fn pointer_length(&self) -> usize { if self.version & VERSION_MASK == 1 { 2 } else { 4 } }
     pub fn table_size_entries(&self) -> u32 { self.table_size }
     pub fn number_of_pages(&self) ->u64 { self.number_of_pages }
     pub fn page_size(&self) -> u32 { 4 + self.table_size * (self.pointer_length() as u32) + self.heap_size }
@@ -110,4 +469,119 @@ impl NCDHeader {
     }
 
     pub(crate) fn structured_size(&self) -> u64 { self.page_offset(self.number_of_pages) }
+
+    /* where external values start: after the pages, or after the page index with page compression */
+    pub(crate) fn external_start(&self) -> u64 {
+        if self.pages_compressed() {
+            (self.size() as u64).saturating_add(self.page_index_size())
+        } else {
+            self.structured_size()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::NCDError;
+
+    use crate::bitbash::NCDHashAlgorithm;
+
+    use super::{FLAG_EXTERNAL_KEYS, HEADER_SIZE, MAX_HEADER_SIZE, NCDHeader, fingerprint_flags};
+
+    fn do_test_descriptor() -> Result<(),NCDError> {
+        let header = NCDHeader::new(12,900,100,None,0xDEADBEEF)?;
+        assert_eq!("ncd1.1.c.384.64.deadbeef",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        let header = NCDHeader::new(1,100000,1000,None,7)?;
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        let header = NCDHeader::new(12,900,100,None,1)?.with_flags(FLAG_EXTERNAL_KEYS);
+        assert_eq!("ncd1.101.c.384.64.1",header.descriptor());
+        assert!(NCDHeader::from_descriptor(&header.descriptor())?.external_keys());
+        let header = NCDHeader::new(12,900,100,None,1)?.with_hash(NCDHashAlgorithm::SipHash,u128::MAX);
+        assert_eq!("ncd1.801.c.384.64.1.2.ffffffffffffffffffffffffffffffff",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(header,NCDHeader::parse(&header.to_bytes()?)?);
+        assert_eq!(HEADER_SIZE+20,header.to_bytes()?.len());
+        assert_eq!(HEADER_SIZE,header.clone().with_hash(NCDHashAlgorithm::Murmur3,0).to_bytes()?.len());
+        assert!(NCDHeader::parse(&header.to_bytes()?[..HEADER_SIZE]).is_err());
+        let header = NCDHeader::new(12,900,100,None,1)?.with_compression(4096);
+        assert_eq!("ncd1.1001.c.384.64.1.1000",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(header,NCDHeader::parse(&header.to_bytes()?)?);
+        let header = header.with_hash(NCDHashAlgorithm::Xxh3,5);
+        assert_eq!("ncd1.1801.c.384.64.1.1.5.1000",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(HEADER_SIZE+24,header.to_bytes()?.len());
+        assert_eq!(4096,NCDHeader::parse(&header.to_bytes()?)?.dictionary_len());
+        let header = header.with_page_compression(2);
+        assert_eq!("ncd1.3801.c.384.64.1.1.5.1000.2",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(HEADER_SIZE+28,header.to_bytes()?.len());
+        assert_eq!(HEADER_SIZE as u64+28+13*8,header.external_start());
+        let header = header.with_metadata(4096,77);
+        assert_eq!("ncd1.7801.c.384.64.1.1.5.1000.2.1000.4d",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(header,NCDHeader::parse(&header.to_bytes()?)?);
+        assert_eq!(HEADER_SIZE+40,header.to_bytes()?.len());
+        assert_eq!(Some((HEADER_SIZE as u64+40+13*8+4096,77)),header.metadata_location());
+        let header = header.with_build_stats(4173);
+        assert_eq!("ncd1.f801.c.384.64.1.1.5.1000.2.1000.4d.104d",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert_eq!(header,NCDHeader::parse(&header.to_bytes()?)?);
+        assert_eq!(MAX_HEADER_SIZE,header.to_bytes()?.len());
+        assert_eq!(Some((MAX_HEADER_SIZE as u64+13*8+4173,44)),header.build_stats_location());
+        for bad in ["","ncd1.1.c.384.64","ncd1.801.c.384.64.1","ncd1.1.c.384.64.1.2.1","ncd1.801.c.384.64.1.3.1","ncd2.1.c.384.64.1","ncd1.2.c.384.64.1","ncd1.8001.c.384.64.1","ncd1.1.c.384.64.x","ncd1.1.c.384.64.100000000","ncd1.1001.c.384.64.1"] {
+            assert!(NCDHeader::from_descriptor(bad).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_descriptor() {
+        do_test_descriptor().unwrap();
+    }
+
+    fn do_test_fingerprint() -> Result<(),NCDError> {
+        let full_hash = 0x0123456789ABCDEF_FEDCBA9876543210_u128;
+        let header = NCDHeader::new(12,900,100,None,1)?;
+        assert_eq!(32,header.fingerprint_bits());
+        assert_eq!(header.hash_ext(0x0123456789ABCDEF) as u128,header.fingerprint(full_hash));
+        for (bits,expect) in [(32,0x76543210),(64,0xFEDCBA9876543210),(128,full_hash)] {
+            let header = header.clone().with_flags(fingerprint_flags(Some(bits))?);
+            assert_eq!(bits,header.fingerprint_bits());
+            assert_eq!(bits as usize/8,header.entry_format().fingerprint_size);
+            assert_eq!(expect,header.fingerprint(full_hash));
+            assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        }
+        assert!(fingerprint_flags(Some(48)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        do_test_fingerprint().unwrap();
+    }
+
+    fn do_test_perfect_hash() -> Result<(),NCDError> {
+        let plain = NCDHeader::new(12,900,100,None,1)?;
+        let header = plain.clone().with_perfect_hash();
+        assert!(header.perfect_hash() && !plain.perfect_hash());
+        assert_eq!(plain.page_size(),header.page_size());
+        assert_eq!((1,900+99*2),(header.table_size_entries(),header.heap_size()));
+        assert_eq!("ncd1.100001.c.44a.1.1",header.descriptor());
+        assert_eq!(header,NCDHeader::from_descriptor(&header.descriptor())?);
+        assert!(NCDHeader::from_descriptor("ncd1.100001.c.384.64.1").is_err());
+        /* two-byte pointers can't reach all of a heap which grows past 64k */
+        let header = NCDHeader::new(1,60000,3000,None,1)?;
+        assert_eq!(2,header.pointer_length());
+        let header = header.with_perfect_hash();
+        assert_eq!((4,1,66004-8),(header.pointer_length(),header.table_size_entries(),header.heap_size()));
+        assert_eq!(66004,header.page_size());
+        Ok(())
+    }
+
+    #[test]
+    fn test_perfect_hash() {
+        do_test_perfect_hash().unwrap();
+    }
 }
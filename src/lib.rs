@@ -2,13 +2,32 @@
 mod util;
 
 mod accessors {
+    #[cfg(any(feature="curl",feature="ureq"))]
+    pub(crate) mod config;
+    pub(crate) mod fault;
+    #[cfg(feature="curl")]
     pub(crate) mod http;
+    pub(crate) mod instrumented;
+    #[cfg(any(feature="curl",feature="ureq"))]
+    pub(crate) mod range;
     pub(crate) mod std;
+    #[cfg(feature="ureq")]
+    pub(crate) mod ureq;
 }
 mod header;
 mod bitbash;
 mod build;
+mod compress;
+mod list;
+mod metadata;
+mod perfect;
 mod read;
+mod set;
+mod servers {
+    pub(crate) mod gateway;
+    pub(crate) mod http;
+    pub(crate) mod range;
+}
 mod sources {
     pub(crate) mod flat;
     pub(crate) mod hashmap;
@@ -18,13 +37,28 @@ mod write;
 #[cfg(test)]
 mod test;
 
+pub use crate::bitbash::NCDHashAlgorithm;
 pub use crate::build::{ NCDBuildConfig, NCDBuild };
-pub use crate::read::{ NCDReader, NCDReadAccessor };
-pub use crate::util::{ NCDError, wrap_io_error };
-pub use crate::write::NCDValueSource;
+pub use crate::compress::NCDCompression;
+pub use crate::metadata::NCDBuildStats;
+pub use crate::read::{ NCDReader, NCDReadAccessor, NCDReadConfig, NCDReadKind, NCDValueReader };
+pub use crate::header::NCDHeader;
+pub use crate::set::{ NCDSetMode, NCDSetReader };
+pub use crate::servers::gateway::{ NCDGateway, NCDGatewayConfig };
+pub use crate::servers::http::NCDServerHandle;
+pub use crate::servers::range::{ NCDRangeServer, NCDServerConfig };
+pub use crate::util::{ NCDError, NCDAccessError, NCDAccessErrorKind, wrap_io_error };
+pub use crate::write::{ NCDDuplicatePolicy, NCDMerge, NCDValueSource };
 
+#[cfg(any(feature="curl",feature="ureq"))]
+pub use crate::accessors::config::{ NCDHttpConfig, NCDTokenSource };
+#[cfg(feature="curl")]
 pub use crate::accessors::http::{ CurlNCDReadAccessor, CurlConfig };
+#[cfg(feature="ureq")]
+pub use crate::accessors::ureq::UreqNCDReadAccessor;
 pub use crate::accessors::std::{ StdNCDReadMutAccessor, StdNCDReadAccessor };
+pub use crate::accessors::fault::{ NCDFaultAccessor, NCDFaultConfig };
+pub use crate::accessors::instrumented::{ NCDInstrumentedAccessor, NCDAccessStats, NCDKindStats };
 
 pub use crate::sources::flat::{ NCDFlatSource, NCDFlatConfig };
 pub use crate::sources::hashmap::NCDHashMapValueSource;
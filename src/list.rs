@@ -0,0 +1,53 @@
+use crate::bitbash::{MAX_LESQLITE2_BYTES, lesqlite2_read, lesqlite2_write, read_bytes, write_bytes};
+use crate::util::NCDError;
+
+/* In multi-valued files a key's values are stored as each value's length (lesqlite2) then its bytes, one after
+ * another with no count, so that two lists join by concatenation.
+ */
+pub(crate) fn encode_list(values: &[Vec<u8>]) -> Result<Vec<u8>,NCDError> {
+    let size = values.iter().map(|v| v.len()+MAX_LESQLITE2_BYTES).sum::<usize>();
+    let mut out = vec![0;size];
+    let mut offset = 0;
+    for value in values {
+        lesqlite2_write(&mut out,&mut offset,value.len() as u64)?;
+        write_bytes(&mut out,&mut offset,value)?;
+    }
+    out.truncate(offset);
+    Ok(out)
+}
+
+pub(crate) fn decode_list(bytes: &[u8]) -> Result<Vec<Vec<u8>>,NCDError> {
+    let mut offset = 0;
+    let mut out = vec![];
+    while offset < bytes.len() {
+        let len = lesqlite2_read(bytes,&mut offset)?;
+        if len > (bytes.len()-offset) as u64 {
+            return Err(NCDError::CorruptNCDFile("value overruns its list".to_string()));
+        }
+        out.push(read_bytes(bytes,&mut offset,len as usize)?.to_vec());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::NCDError;
+
+    use super::{decode_list, encode_list};
+
+    fn do_test_list() -> Result<(),NCDError> {
+        let values = vec![b"ENST00000456328".to_vec(),vec![],vec![7;300]];
+        let bytes = encode_list(&values)?;
+        assert_eq!(values,decode_list(&bytes)?);
+        let joined = [encode_list(&values[..1])?,encode_list(&values[1..])?].concat();
+        assert_eq!(bytes,joined);
+        assert!(decode_list(&[])?.is_empty());
+        assert!(decode_list(&bytes[..bytes.len()-1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list() {
+        do_test_list().unwrap();
+    }
+}
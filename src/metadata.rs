@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use crate::bitbash::{MAX_LESQLITE2_BYTES, lesqlite2_read, lesqlite2_write, read_bytes, read_u32, read_u64, write_bytes, write_u32, write_u64};
+use crate::util::NCDError;
+
+/* The metadata block is a count then each key and value as a length and UTF-8 bytes, all lengths lesqlite2 */
+pub(crate) fn encode_metadata(metadata: &BTreeMap<String,String>) -> Result<Vec<u8>,NCDError> {
+    let size = metadata.iter().map(|(k,v)| k.len()+v.len()+2*MAX_LESQLITE2_BYTES).sum::<usize>();
+    let mut out = vec![0;MAX_LESQLITE2_BYTES+size];
+    let mut offset = 0;
+    lesqlite2_write(&mut out,&mut offset,metadata.len() as u64)?;
+    for (key,value) in metadata {
+        for part in [key,value] {
+            lesqlite2_write(&mut out,&mut offset,part.len() as u64)?;
+            write_bytes(&mut out,&mut offset,part.as_bytes())?;
+        }
+    }
+    out.truncate(offset);
+    Ok(out)
+}
+
+fn read_string(bytes: &[u8], offset: &mut usize) -> Result<String,NCDError> {
+    let len = lesqlite2_read(bytes,offset)?;
+    if len > (bytes.len()-*offset) as u64 {
+        return Err(NCDError::CorruptNCDFile("metadata overruns its block".to_string()));
+    }
+    let len = len as usize;
+    String::from_utf8(read_bytes(bytes,offset,len)?.to_vec()).map_err(|_| NCDError::CorruptNCDFile("metadata not UTF-8".to_string()))
+}
+
+pub(crate) fn decode_metadata(bytes: &[u8]) -> Result<BTreeMap<String,String>,NCDError> {
+    let mut offset = 0;
+    let count = lesqlite2_read(bytes,&mut offset)?;
+    let mut out = BTreeMap::new();
+    for _ in 0..count {
+        let key = read_string(bytes,&mut offset)?;
+        let value = read_string(bytes,&mut offset)?;
+        out.insert(key,value);
+    }
+    Ok(out)
+}
+
+/* Counted while building, and stored as a fixed-size block in the external area */
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct NCDBuildStats {
+    pub(crate) records: u64,
+    /* of the keys and values as given to the build */
+    pub(crate) total_length: u64,
+    pub(crate) external_entries: u64,
+    /* most slots past its hashed slot that any entry is in */
+    pub(crate) max_probe: u32,
+    pub(crate) min_page_entries: u32,
+    pub(crate) max_page_entries: u32,
+    pub(crate) min_heap_used: u32,
+    pub(crate) max_heap_used: u32
+}
+
+pub(crate) const BUILD_STATS_SIZE : usize = 44;
+
+impl NCDBuildStats {
+    pub(crate) fn new() -> NCDBuildStats {
+        NCDBuildStats {
+            records: 0, total_length: 0, external_entries: 0, max_probe: 0,
+            min_page_entries: 0, max_page_entries: 0, min_heap_used: 0, max_heap_used: 0
+        }
+    }
+
+    pub fn records(&self) -> u64 { self.records }
+    pub fn total_length(&self) -> u64 { self.total_length }
+    pub fn external_entries(&self) -> u64 { self.external_entries }
+    pub fn max_probe(&self) -> u32 { self.max_probe }
+    pub fn min_page_entries(&self) -> u32 { self.min_page_entries }
+    pub fn max_page_entries(&self) -> u32 { self.max_page_entries }
+    pub fn min_heap_used(&self) -> u32 { self.min_heap_used }
+    pub fn max_heap_used(&self) -> u32 { self.max_heap_used }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>,NCDError> {
+        let mut out = vec![0;BUILD_STATS_SIZE];
+        let mut offset = 0;
+        for value in [self.records,self.total_length,self.external_entries] {
+            write_u64(&mut out,&mut offset,value)?;
+        }
+        for value in [self.max_probe,self.min_page_entries,self.max_page_entries,self.min_heap_used,self.max_heap_used] {
+            write_u32(&mut out,&mut offset,value)?;
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<NCDBuildStats,NCDError> {
+        let mut offset = 0;
+        Ok(NCDBuildStats {
+            records: read_u64(bytes,&mut offset)?,
+            total_length: read_u64(bytes,&mut offset)?,
+            external_entries: read_u64(bytes,&mut offset)?,
+            max_probe: read_u32(bytes,&mut offset)?,
+            min_page_entries: read_u32(bytes,&mut offset)?,
+            max_page_entries: read_u32(bytes,&mut offset)?,
+            min_heap_used: read_u32(bytes,&mut offset)?,
+            max_heap_used: read_u32(bytes,&mut offset)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::util::NCDError;
+
+    use super::{BUILD_STATS_SIZE, NCDBuildStats, decode_metadata, encode_metadata};
+
+    fn do_test_metadata_block() -> Result<(),NCDError> {
+        let mut metadata = BTreeMap::new();
+        assert_eq!(metadata,decode_metadata(&encode_metadata(&metadata)?)?);
+        metadata.insert("source".to_string(),"catalogue.tsv".to_string());
+        metadata.insert("schema".to_string(),"".to_string());
+        metadata.insert("".to_string(),"x".repeat(1000));
+        metadata.insert("ünïcode".to_string(),"✓".to_string());
+        let bytes = encode_metadata(&metadata)?;
+        assert_eq!(metadata,decode_metadata(&bytes)?);
+        assert!(decode_metadata(&bytes[..bytes.len()-1]).is_err());
+        assert!(decode_metadata(&[1,1,0xFF,0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_block() {
+        do_test_metadata_block().unwrap();
+    }
+
+    fn do_test_build_stats_block() -> Result<(),NCDError> {
+        let stats = NCDBuildStats {
+            records: 1, total_length: u64::MAX, external_entries: 3, max_probe: 4,
+            min_page_entries: 5, max_page_entries: 6, min_heap_used: 7, max_heap_used: u32::MAX
+        };
+        let bytes = stats.to_bytes()?;
+        assert_eq!(BUILD_STATS_SIZE,bytes.len());
+        assert_eq!(stats,NCDBuildStats::parse(&bytes)?);
+        assert!(NCDBuildStats::parse(&bytes[..BUILD_STATS_SIZE-1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_stats_block() {
+        do_test_build_stats_block().unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp::Reverse;
+
+use crate::bitbash::{MAX_LESQLITE2_BYTES, lesqlite2_read, lesqlite2_write, read_u16, read_uvar, write_u16, write_uvar};
+use crate::util::NCDError;
+
+/* In files with perfect hashing a page's keys are mapped onto its entries with no empty slots and no probing. The
+ * keys are split into buckets, and each bucket given the displacement which puts all its keys into free slots, as
+ * in CHD. The block is the number of entries and of buckets (lesqlite2), a two-byte displacement for each bucket,
+ * and then a pointer to each entry, of the table's pointer length.
+ */
+
+/* keys per bucket tried first, then fewer, which take more space but are easier to place */
+const KEYS_PER_BUCKET : [u64;3] = [4,2,1];
+
+/* SplitMix64's finaliser */
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/* from the low half of the hash, the high half having chosen the page */
+fn bucket(full_hash: u128, buckets: u64) -> u64 { mix(full_hash as u64) % buckets }
+
+fn slot(full_hash: u128, displacement: u16, entries: u64) -> u64 {
+    mix(full_hash as u64 ^ mix(displacement as u64 + 1)) % entries
+}
+
+/* the displacement of each bucket and the slot of each key, largest buckets first while there is most room */
+fn place(hashes: &[u128], buckets: u64) -> Option<(Vec<u16>,Vec<u64>)> {
+    let entries = hashes.len() as u64;
+    let mut members = vec![vec![];buckets as usize];
+    for (i,hash) in hashes.iter().enumerate() {
+        members[bucket(*hash,buckets) as usize].push(i);
+    }
+    let mut order = (0..members.len()).collect::<Vec<_>>();
+    order.sort_by_key(|b| Reverse(members[*b].len()));
+    let mut taken = vec![false;hashes.len()];
+    let mut displacements = vec![0;members.len()];
+    let mut slots = vec![0;hashes.len()];
+    let mut candidate = vec![];
+    for b in order {
+        if members[b].is_empty() { break; }
+        let displacement = (0..=u16::MAX).find(|d| {
+            candidate.clear();
+            for i in &members[b] {
+                let s = slot(hashes[*i],*d,entries);
+                if taken[s as usize] || candidate.contains(&s) { return false; }
+                candidate.push(s);
+            }
+            true
+        })?;
+        displacements[b] = displacement;
+        for (i,s) in members[b].iter().zip(candidate.iter()) {
+            taken[*s as usize] = true;
+            slots[*i] = *s;
+        }
+    }
+    Some((displacements,slots))
+}
+
+/* the block for entries with these full hashes at these offsets, or None where no displacements could be found */
+pub(crate) fn encode_perfect_hash(hashes: &[u128], pointers: &[u64], pointer_length: usize) -> Result<Option<Vec<u8>>,NCDError> {
+    let entries = hashes.len() as u64;
+    for keys_per_bucket in KEYS_PER_BUCKET {
+        let buckets = entries.div_ceil(keys_per_bucket).max(1);
+        let (displacements,slots) = match place(hashes,buckets) {
+            Some(placed) => placed,
+            None => { continue; }
+        };
+        let mut out = vec![0;2*MAX_LESQLITE2_BYTES+2*displacements.len()+pointer_length*hashes.len()];
+        let mut offset = 0;
+        lesqlite2_write(&mut out,&mut offset,entries)?;
+        lesqlite2_write(&mut out,&mut offset,buckets)?;
+        for displacement in displacements {
+            write_u16(&mut out,&mut offset,displacement)?;
+        }
+        for (s,pointer) in slots.iter().zip(pointers) {
+            let mut pos = offset + (*s as usize)*pointer_length;
+            write_uvar(&mut out,&mut pos,*pointer,pointer_length)?;
+        }
+        out.truncate(offset+pointer_length*hashes.len());
+        return Ok(Some(out));
+    }
+    Ok(None)
+}
+
+/* the pointer a key with this full hash would have, from the block at offset in the heap */
+pub(crate) fn perfect_hash_lookup(heap: &[u8], offset: usize, full_hash: u128, pointer_length: usize) -> Result<Option<u64>,NCDError> {
+    let mut offset = offset;
+    let entries = lesqlite2_read(heap,&mut offset)?;
+    let buckets = lesqlite2_read(heap,&mut offset)?;
+    if entries > heap.len() as u64 || buckets > heap.len() as u64 {
+        return Err(NCDError::CorruptNCDFile("perfect hash block overruns its heap".to_string()));
+    }
+    if entries == 0 || buckets == 0 {
+        return Ok(None);
+    }
+    let mut pos = offset + 2*bucket(full_hash,buckets) as usize;
+    let displacement = read_u16(heap,&mut pos)?;
+    let mut pos = offset + 2*buckets as usize + pointer_length*slot(full_hash,displacement,entries) as usize;
+    Ok(Some(read_uvar(heap,&mut pos,pointer_length)?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::{bitbash::{NCDHashAlgorithm, compute_full_hash}, util::NCDError};
+
+    use super::{encode_perfect_hash, perfect_hash_lookup};
+
+    fn do_test_perfect_hash() -> Result<(),NCDError> {
+        for count in [1,2,3,10,100,1000,5000] {
+            let hashes = (0..count).map(|i| compute_full_hash(NCDHashAlgorithm::Xxh3,0,format!("key {}",i).as_bytes())).collect::<Result<Vec<_>,_>>()?;
+            let pointers = (0..count).map(|i| i*3).collect::<Vec<u64>>();
+            for pointer_length in [2,4] {
+                let mut heap = vec![7;5];
+                heap.extend(encode_perfect_hash(&hashes,&pointers,pointer_length)?.unwrap());
+                assert!(heap.len() <= 5 + 20 + (count as usize).div_ceil(4)*2 + count as usize*pointer_length);
+                let mut seen = HashSet::new();
+                for (hash,pointer) in hashes.iter().zip(&pointers) {
+                    assert_eq!(Some(*pointer),perfect_hash_lookup(&heap,5,*hash,pointer_length)?);
+                    seen.insert(*pointer);
+                }
+                assert_eq!(count as usize,seen.len());
+            }
+        }
+        assert_eq!(None,perfect_hash_lookup(&[0,0],0,1,2)?);
+        assert!(perfect_hash_lookup(&[0x70,1],0,1,2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_perfect_hash() {
+        do_test_perfect_hash().unwrap();
+    }
+}
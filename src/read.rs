@@ -1,11 +1,44 @@
-use std::io;
+use std::{collections::BTreeMap, io};
 
-use crate::bitbash::{bounds_check, lesqlite2_read, read_bytes, read_u32, read_uvar};
+use crate::bitbash::{bounds_check, lesqlite2_read, lesqlite2_size, read_bytes, read_u32, read_u64, read_uvar};
+use crate::accessors::instrumented::NCDAccessStats;
+use crate::compress::{MAX_VALUE_PREFIX, NCDValuePrefix, decode_value, decompress};
+use crate::list::decode_list;
+use crate::metadata::{NCDBuildStats, decode_metadata};
+use crate::perfect::perfect_hash_lookup;
 use crate::util::{NCDError, wrap_io_error};
-use crate::{bitbash::compute_hash, header::{ NCDHeader }};
+use crate::header::{MAX_HEADER_SIZE, NCDEntryFormat, NCDHeader};
+
+/* What a read is for, so that wrapping accessors can account for or treat them differently. */
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum NCDReadKind {
+    Header,
+    Page,
+    External,
+    /* made directly, not by NCDReader */
+    Other
+}
 
 pub trait NCDReadAccessor {
     fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>>;
+
+    fn read_kind(&mut self, _kind: NCDReadKind, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.read(offset,length)
+    }
+
+    fn stats(&self) -> Option<NCDAccessStats> { None }
+    fn reset_stats(&mut self) {}
+}
+
+impl<'a> NCDReadAccessor for Box<dyn NCDReadAccessor + 'a> {
+    fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> { self.as_mut().read(offset,length) }
+
+    fn read_kind(&mut self, kind: NCDReadKind, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        self.as_mut().read_kind(kind,offset,length)
+    }
+
+    fn stats(&self) -> Option<NCDAccessStats> { self.as_ref().stats() }
+    fn reset_stats(&mut self) { self.as_mut().reset_stats() }
 }
 
 // XXX async
@@ -13,19 +46,61 @@ pub trait NCDReadAccessor {
 #[cfg_attr(debug_assertions,derive(Debug,PartialEq,Eq))]
 pub(crate) enum NCDLookupResult {
     Internal(Vec<u8>,Vec<u8>),
-    External(u64,u64,u32),
+    /* offset, size and key fingerprint, then the key and value length where the file stores them */
+    External(u64,u64,u128,Option<(Vec<u8>,u64)>),
+    /* in sets of fingerprints only */
+    Fingerprint(u128),
     Empty
 }
 
 #[cfg_attr(debug_assertions,derive(Debug,PartialEq,Eq))]
 enum NCDLookupEntry {
     Value(Vec<u8>),
+    /* absolute offset and length of an external value, not yet fetched */
+    Located(u64,u64),
     Skip,
     Finish
 }
 
+/* Longest lesqlite2 encoding */
+const MAX_VARINT_SIZE : u64 = 9;
+
+/* Reads just enough of an external entry to check the key and find where its value lies */
+fn locate_external(reader: &mut NCDReader, key: &[u8], offset: u64, size: u64) -> Result<NCDLookupEntry,NCDError> {
+    let want = size.min(2*MAX_VARINT_SIZE+key.len() as u64);
+    let bytes = wrap_io_error(reader.accessor().read_kind(NCDReadKind::External,offset,want))?;
+    let mut pos = 0;
+    let key_len = lesqlite2_read(&bytes,&mut pos)?;
+    if key_len == 0 {
+        return Err(NCDError::CorruptNCDFile("recursive external reference".to_string()));
+    }
+    if key_len-1 != key.len() as u64 { return Ok(NCDLookupEntry::Skip); }
+    bounds_check(&bytes,pos,key.len())?;
+    if read_bytes(&bytes,&mut pos,key.len())? != key { return Ok(NCDLookupEntry::Skip); }
+    let value_len = if reader.header().set() { 0 } else { lesqlite2_read(&bytes,&mut pos)? };
+    if pos as u64 + value_len > size {
+        return Err(NCDError::CorruptNCDFile("external value overruns its entry".to_string()));
+    }
+    Ok(NCDLookupEntry::Located(offset+pos as u64,value_len))
+}
+
+/* Where the value lies in an external entry, from the key and value length held in its pointer */
+fn locate_in_pointer(key: &[u8], offset: u64, size: u64, value_len: u64) -> Result<NCDLookupEntry,NCDError> {
+    let prefix = lesqlite2_size(key.len() as u64+1)? + key.len() + lesqlite2_size(value_len)?;
+    if prefix as u64 + value_len > size {
+        return Err(NCDError::CorruptNCDFile("external value overruns its entry".to_string()));
+    }
+    Ok(NCDLookupEntry::Located(offset+prefix as u64,value_len))
+}
+
 impl NCDLookupResult {
+    #[cfg(test)]
     fn resolve(self, reader: &mut NCDReader, key: &[u8]) -> Result<NCDLookupEntry,NCDError> {
+        self.resolve_as(reader,key,false)
+    }
+
+    /* with locate, external values are located rather than fetched */
+    fn resolve_as(self, reader: &mut NCDReader, key: &[u8], locate: bool) -> Result<NCDLookupEntry,NCDError> {
         match self {
             NCDLookupResult::Internal(k,v) => {
                 if key == k {
@@ -35,13 +110,41 @@ impl NCDLookupResult {
                 }
             },
             NCDLookupResult::Empty => Ok(NCDLookupEntry::Finish),
-            NCDLookupResult::External(offset,size,hash) => {
-                let key_hash = reader.header().hash_ext(compute_hash(key)?);
-                if key_hash != hash {
+            NCDLookupResult::Fingerprint(fingerprint) => {
+                if reader.header().fingerprint(reader.header().full_hash(key)?) == fingerprint {
+                    Ok(NCDLookupEntry::Value(vec![]))
+                } else {
+                    Ok(NCDLookupEntry::Skip)
+                }
+            },
+            NCDLookupResult::External(offset,size,fingerprint,key_info) => {
+                let key_fingerprint = reader.header().fingerprint(reader.header().full_hash(key)?);
+                if key_fingerprint != fingerprint {
                     return Ok(NCDLookupEntry::Skip);
                 }
-                let bytes = wrap_io_error(reader.accessor().read(offset,size))?;
-                let entry = parse_entry(&bytes, 0)?;
+                if let Some((k,value_len)) = key_info {
+                    /* settled without fetching the entry */
+                    if k != key { return Ok(NCDLookupEntry::Skip); }
+                    let located = if reader.header().set() {
+                        NCDLookupEntry::Located(offset+size,0)
+                    } else if reader.header().shared_values() {
+                        if value_len != size {
+                            return Err(NCDError::CorruptNCDFile("shared value length mismatch".to_string()));
+                        }
+                        NCDLookupEntry::Located(offset,size)
+                    } else {
+                        locate_in_pointer(key,offset,size,value_len)?
+                    };
+                    return match (locate,located) {
+                        (false,NCDLookupEntry::Located(offset,length)) => Ok(NCDLookupEntry::Value(read_external(reader,offset,length)?)),
+                        (_,located) => Ok(located)
+                    };
+                }
+                if locate {
+                    return locate_external(reader,key,offset,size);
+                }
+                let bytes = wrap_io_error(reader.accessor().read_kind(NCDReadKind::External,offset,size))?;
+                let entry = parse_entry(&bytes,0,reader.header().entry_format())?;
                 match entry {
                     NCDLookupResult::Internal(k,v) => {
                         if k == key {
@@ -51,7 +154,7 @@ impl NCDLookupResult {
                         }
                     },
                     NCDLookupResult::Empty => Ok(NCDLookupEntry::Finish),
-                    NCDLookupResult::External(_,_,_) => {
+                    NCDLookupResult::External(_,_,_,_) | NCDLookupResult::Fingerprint(_) => {
                         return Err(NCDError::CorruptNCDFile(format!("recursive external reference")))
                     }        
                 }
@@ -60,22 +163,52 @@ impl NCDLookupResult {
     }
 }
 
-pub(crate) fn parse_entry(heap: &[u8], offset: usize) -> Result<NCDLookupResult,NCDError> {
+fn read_fingerprint(heap: &[u8], offset: &mut usize, size: usize) -> Result<u128,NCDError> {
+    bounds_check(heap,*offset,size)?;
+    let mut fingerprint = [0;16];
+    fingerprint[..size].copy_from_slice(read_bytes(heap,offset,size)?);
+    Ok(u128::from_le_bytes(fingerprint))
+}
+
+pub(crate) fn parse_entry(heap: &[u8], offset: usize, format: NCDEntryFormat) -> Result<NCDLookupResult,NCDError> {
     let mut offset = offset;
+    if format.fingerprints_only {
+        return Ok(NCDLookupResult::Fingerprint(read_fingerprint(heap,&mut offset,format.fingerprint_size)?));
+    }
     let key_len = lesqlite2_read(heap,&mut offset)?;
     if key_len == 0 {
         /* external */
         let ext_offset = lesqlite2_read(heap,&mut offset)?;
         let ext_length = lesqlite2_read(heap,&mut offset)?;
-        bounds_check(heap,offset,4)?;
-        let hash = read_u32(heap, &mut offset)?;
-        return Ok(NCDLookupResult::External(ext_offset,ext_length,hash));
+        let fingerprint = read_fingerprint(heap,&mut offset,format.fingerprint_size)?;
+        let key_info = if format.external_keys {
+            let key_len = lesqlite2_read(heap,&mut offset)? as usize;
+            bounds_check(heap,offset,key_len)?;
+            let key = read_bytes(heap,&mut offset,key_len)?.to_vec();
+            let value_len = lesqlite2_read(heap,&mut offset)?;
+            Some((key,value_len))
+        } else {
+            None
+        };
+        return Ok(NCDLookupResult::External(ext_offset,ext_length,fingerprint,key_info));
     } else {
         /* internal */
         let key_len = (key_len-1) as usize;
         bounds_check(heap,offset,key_len)?;
         let key = read_bytes(heap, &mut offset, key_len)?.to_vec();
-        let value_len = lesqlite2_read(heap,&mut offset)? as usize;
+        if format.set {
+            return Ok(NCDLookupResult::Internal(key,vec![]));
+        }
+        let mut value_len = lesqlite2_read(heap,&mut offset)?;
+        if format.shared_values {
+            /* low bit set for a value held elsewhere in the heap, at the offset which follows */
+            let shared = value_len & 1 != 0;
+            value_len >>= 1;
+            if shared {
+                offset = lesqlite2_read(heap,&mut offset)? as usize;
+            }
+        }
+        let value_len = value_len as usize;
         bounds_check(heap,offset,value_len)?;
         let value = read_bytes(heap,&mut offset,value_len)?.to_vec();
         return Ok(NCDLookupResult::Internal(key,value));
@@ -84,17 +217,47 @@ pub(crate) fn parse_entry(heap: &[u8], offset: usize) -> Result<NCDLookupResult,
 
 struct NCDPage {
     heap: Vec<u8>,
-    table: Vec<Option<u64>>
+    table: Vec<Option<u64>>,
+    format: NCDEntryFormat
 }
 
 impl NCDPage {
     fn read(accessor: &mut dyn NCDReadAccessor, header: &NCDHeader, index: u64) -> Result<NCDPage,NCDError> {
         let page_size = header.page_size() as u64;
         if header.table_size_entries() == 0 {
-            return Ok(NCDPage { heap: vec![], table: vec![] });
+            return Ok(NCDPage { heap: vec![], table: vec![], format: header.entry_format() });
         }
-        let vec = wrap_io_error(accessor.read(page_size*index,page_size))?;
-        let bytes = vec.as_slice();
+        let vec = wrap_io_error(accessor.read_kind(NCDReadKind::Page,page_size*index,page_size))?;
+        NCDPage::parse(header,&vec)
+    }
+
+    /* A compressed page starts with the stamp, so a stale index is caught before decompressing */
+    fn read_compressed(accessor: &mut dyn NCDReadAccessor, header: &NCDHeader, page_index: &[u64], index: u64) -> Result<NCDPage,NCDError> {
+        if header.table_size_entries() == 0 {
+            return Ok(NCDPage { heap: vec![], table: vec![], format: header.entry_format() });
+        }
+        let (start,end) = match (page_index.get(index as usize),page_index.get(index as usize+1)) {
+            (Some(start),Some(end)) if *end >= start+4 => (*start,*end),
+            _ => { return Err(NCDError::CorruptNCDFile(format!("bad page index entry for page {}",index))); }
+        };
+        let vec = wrap_io_error(accessor.read_kind(NCDReadKind::Page,start,end-start))?;
+        let mut offset = 0;
+        if read_u32(&vec,&mut offset)? != header.stamp() {
+            return Err(NCDError::WrongStamp);
+        }
+        let page = decompress(header.page_codec(),&[],&vec[offset..],header.page_size() as usize)?;
+        NCDPage::parse(header,&page)
+    }
+
+    fn parse(header: &NCDHeader, vec: &[u8]) -> Result<NCDPage,NCDError> {
+        let page_size = header.page_size() as u64;
+        if header.table_size_entries() == 0 {
+            return Ok(NCDPage { heap: vec![], table: vec![], format: header.entry_format() });
+        }
+        if (vec.len() as u64) < page_size {
+            return Err(NCDError::CorruptNCDFile(format!("short page read: wanted {} bytes, got {}",page_size,vec.len())));
+        }
+        let bytes = vec;
         let mut table = vec![];
         table.reserve(header.table_size_entries() as usize);
         let mut offset = header.heap_size() as usize;
@@ -111,7 +274,8 @@ impl NCDPage {
         }
         Ok(NCDPage {
             heap: bytes[0..(header.heap_size() as usize)].to_vec(),
-            table
+            table,
+            format: header.entry_format()
         })
     }
 
@@ -124,20 +288,31 @@ impl NCDPage {
             return Ok(NCDLookupResult::Empty);
         }
         let offset = offset.unwrap() as usize;
-        parse_entry(&self.heap,offset)
+        parse_entry(&self.heap,offset,self.format)
     }
 
     fn scan(&self, reader: &mut NCDReader, key: &[u8], hash: u64) -> Result<Option<Vec<u8>>,NCDError> {
+        Ok(match self.scan_as(reader,key,hash,false)? {
+            Some(NCDValueLocation::Inline(value)) => Some(value),
+            _ => None
+        })
+    }
+
+    fn scan_as(&self, reader: &mut NCDReader, key: &[u8], hash: u64, locate: bool) -> Result<Option<NCDValueLocation>,NCDError> {
         let header = reader.header();
         if header.table_size_entries() == 0 {
             return Ok(None);
         }
+        if header.perfect_hash() {
+            return self.scan_perfect(reader,key,locate);
+        }
         let mut hash = header.hash_page_slot(hash);
         let first_hash = hash;
         loop {
-            let result = self.lookup(hash)?.resolve(reader,key)?;
+            let result = self.lookup(hash)?.resolve_as(reader,key,locate)?;
             match result {
-                NCDLookupEntry::Value(value) => { return Ok(Some(value)); },
+                NCDLookupEntry::Value(value) => { return Ok(Some(NCDValueLocation::Inline(value))); },
+                NCDLookupEntry::Located(offset,length) => { return Ok(Some(NCDValueLocation::External(offset,length))); },
                 NCDLookupEntry::Finish => { return Ok(None); },
                 NCDLookupEntry::Skip => {}
             }
@@ -145,46 +320,338 @@ impl NCDPage {
             if hash == first_hash { return Ok(None); }
         }
     }
+
+    /* with a perfect hash, the one entry which can be for the key */
+    fn scan_perfect(&self, reader: &mut NCDReader, key: &[u8], locate: bool) -> Result<Option<NCDValueLocation>,NCDError> {
+        let block = match self.table.first() {
+            Some(Some(block)) => *block as usize,
+            _ => { return Ok(None); }
+        };
+        let header = reader.header();
+        let offset = match perfect_hash_lookup(&self.heap,block,header.full_hash(key)?,header.pointer_length())? {
+            Some(offset) => offset as usize,
+            None => { return Ok(None); }
+        };
+        Ok(match parse_entry(&self.heap,offset,self.format)?.resolve_as(reader,key,locate)? {
+            NCDLookupEntry::Value(value) => Some(NCDValueLocation::Inline(value)),
+            NCDLookupEntry::Located(offset,length) => Some(NCDValueLocation::External(offset,length)),
+            NCDLookupEntry::Skip | NCDLookupEntry::Finish => None
+        })
+    }
+}
+
+enum NCDValueLocation {
+    Inline(Vec<u8>),
+    External(u64,u64)
+}
+
+impl NCDValueLocation {
+    fn len(&self) -> u64 {
+        match self {
+            NCDValueLocation::Inline(value) => value.len() as u64,
+            NCDValueLocation::External(_,length) => *length
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NCDReadConfig {
+    prefix_size: u64,
+    header_hint: Option<NCDHeader>
+}
+
+impl Default for NCDReadConfig {
+    fn default() -> NCDReadConfig { NCDReadConfig::new() }
+}
+
+/* On opening, the reader can fetch more than just the header in the same request. If that covers the first
+ * page, the page is kept for the first lookup which needs it. A header hint from an earlier session sizes the
+ * prefix to cover the first page exactly.
+ */
+impl NCDReadConfig {
+    pub fn new() -> NCDReadConfig {
+        NCDReadConfig {
+            prefix_size: 0,
+            header_hint: None
+        }
+    }
+
+    chain!(prefix_size,get_prefix_size,u64,NCDReadConfig);
+    chain!(header_hint,get_header_hint,Option<NCDHeader>,NCDReadConfig);
 }
 
 pub struct NCDReader<'a> {
     reader: Box<dyn NCDReadAccessor + 'a>,
-    header: NCDHeader
+    header: NCDHeader,
+    first_page: Option<Vec<u8>>,
+    /* for files with compression, fetched when first needed */
+    dictionary: Option<Vec<u8>>,
+    /* likewise the page offsets, for files with page compression */
+    page_index: Option<Vec<u64>>
 }
 
 impl<'a> NCDReader<'a> {
-    pub fn new_box(mut reader: Box<dyn NCDReadAccessor + 'a>) -> Result<NCDReader<'a>,NCDError> {
-        let header = NCDHeader::read(reader.as_mut())?;
-        Ok(NCDReader { reader, header })
+    pub fn new_box(reader: Box<dyn NCDReadAccessor + 'a>) -> Result<NCDReader<'a>,NCDError> {
+        Self::new_box_with_config(reader,&NCDReadConfig::new())
     }
 
     pub fn new<T>(reader: T) -> Result<NCDReader<'a>,NCDError> where T: NCDReadAccessor + 'a {
         Self::new_box(Box::new(reader))
     }
 
+    pub fn new_box_with_config(mut reader: Box<dyn NCDReadAccessor + 'a>, config: &NCDReadConfig) -> Result<NCDReader<'a>,NCDError> {
+        let hint_size = config.header_hint.as_ref().map(|h| h.page_size() as u64).unwrap_or(0);
+        let prefix_size = config.prefix_size.max(hint_size).max(MAX_HEADER_SIZE as u64);
+        let prefix = wrap_io_error(reader.read_kind(NCDReadKind::Header,0,prefix_size))?;
+        let header = NCDHeader::parse(&prefix)?;
+        let page_size = header.page_size() as usize;
+        let first_page = if prefix.len() >= page_size && header.number_of_pages() > 0 && !header.pages_compressed() {
+            Some(prefix[..page_size].to_vec())
+        } else {
+            None
+        };
+        let index_end = (header.size() as u64).saturating_add(header.page_index_size());
+        let page_index = if header.pages_compressed() && prefix.len() as u64 >= index_end {
+            Some(parse_page_index(&prefix[header.size()..index_end as usize])?)
+        } else {
+            None
+        };
+        Ok(NCDReader { reader, header, first_page, dictionary: None, page_index })
+    }
+
+    /* No request is made: a stale header is noticed on the first page read, by its stamp or the page not fitting it, and replaced. */
+    pub fn new_box_with_header(reader: Box<dyn NCDReadAccessor + 'a>, header: NCDHeader) -> NCDReader<'a> {
+        NCDReader { reader, header, first_page: None, dictionary: None, page_index: None }
+    }
+
+    pub fn with_header<T>(reader: T, header: NCDHeader) -> NCDReader<'a> where T: NCDReadAccessor + 'a {
+        Self::new_box_with_header(Box::new(reader),header)
+    }
+
+    pub fn with_config<T>(reader: T, config: &NCDReadConfig) -> Result<NCDReader<'a>,NCDError> where T: NCDReadAccessor + 'a {
+        Self::new_box_with_config(Box::new(reader),config)
+    }
+
     #[cfg(test)]
     pub(super) fn testharness_header(&mut self) -> &mut NCDHeader { &mut self.header }
 
     pub fn accessor(&mut self) -> &mut dyn NCDReadAccessor { self.reader.as_mut() }
     pub fn header(&self) -> &NCDHeader { &self.header }
-    fn page(&mut self, index: u64) -> Result<NCDPage,NCDError> { NCDPage::read(self.reader.as_mut(),&self.header,index) }
+
+    /* None unless the accessor keeps statistics, eg NCDInstrumentedAccessor */
+    pub fn stats(&self) -> Option<NCDAccessStats> { self.reader.stats() }
+    pub fn reset_stats(&mut self) { self.reader.reset_stats() }
+
+    /* the first page from opening is used at most once, so later changes to the file are still seen */
+    fn page(&mut self, index: u64) -> Result<NCDPage,NCDError> {
+        if let (0,Some(bytes)) = (index,self.first_page.take()) {
+            return NCDPage::parse(&self.header,&bytes);
+        }
+        if self.header.pages_compressed() {
+            if self.page_index.is_none() {
+                let bytes = wrap_io_error(self.reader.read_kind(NCDReadKind::Header,self.header.size() as u64,self.header.page_index_size()))?;
+                if (bytes.len() as u64) < self.header.page_index_size() {
+                    return Err(NCDError::CorruptNCDFile("short page index".to_string()));
+                }
+                self.page_index = Some(parse_page_index(&bytes)?);
+            }
+            return NCDPage::read_compressed(self.reader.as_mut(),&self.header,self.page_index.as_deref().unwrap_or(&[]),index);
+        }
+        NCDPage::read(self.reader.as_mut(),&self.header,index)
+    }
+
+    fn replace_header(&mut self, header: NCDHeader) {
+        self.header = header;
+        self.first_page = None;
+        self.dictionary = None;
+        self.page_index = None;
+    }
 
     pub fn lookup(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>,NCDError> {
-        let hash = compute_hash(key)?;
+        let hash = self.header.hash(key)?;
+        let page_index = self.header.hash_page_index(hash);
+        let page = self.page(page_index)?;
+        match page.scan(self,key,hash)? {
+            Some(stored) => Ok(Some(self.decode(stored)?)),
+            None => Ok(None)
+        }
+    }
+
+    /* values as stored, so with their codec prefix in files with compression */
+    fn locate_stored(&mut self, key: &[u8]) -> Result<Option<NCDValueLocation>,NCDError> {
+        let hash = self.header.hash(key)?;
         let page_index = self.header.hash_page_index(hash);
         let page = self.page(page_index)?;
-        page.scan(self,key,hash)
+        page.scan_as(self,key,hash,true)
+    }
+
+    /* Raw external values stay located, past their prefix. Compressed ones must be fetched whole to decode. */
+    fn locate(&mut self, key: &[u8]) -> Result<Option<NCDValueLocation>,NCDError> {
+        let location = self.locate_stored(key)?;
+        if !self.header.compressed() {
+            return Ok(location);
+        }
+        Ok(match location {
+            None => None,
+            Some(NCDValueLocation::Inline(stored)) => Some(NCDValueLocation::Inline(self.decode(stored)?)),
+            Some(NCDValueLocation::External(offset,length)) => {
+                let prefix = self.read_prefix(offset,length)?;
+                if prefix.compressed() {
+                    let stored = read_external(self,offset,length)?;
+                    Some(NCDValueLocation::Inline(self.decode(stored)?))
+                } else {
+                    let size = prefix.size() as u64;
+                    Some(NCDValueLocation::External(offset+size,length-size))
+                }
+            }
+        })
+    }
+
+    fn read_prefix(&mut self, offset: u64, length: u64) -> Result<NCDValuePrefix,NCDError> {
+        NCDValuePrefix::parse(&read_external(self,offset,length.min(MAX_VALUE_PREFIX as u64))?)
+    }
+
+    fn decode(&mut self, stored: Vec<u8>) -> Result<Vec<u8>,NCDError> {
+        if !self.header.compressed() {
+            return Ok(stored);
+        }
+        if NCDValuePrefix::parse(&stored)?.compressed() && self.dictionary.is_none() {
+            let offset = self.header.external_start();
+            let length = self.header.dictionary_len() as u64;
+            self.dictionary = Some(read_external(self,offset,length)?);
+        }
+        decode_value(self.dictionary.as_deref().unwrap_or(&[]),&stored)
+    }
+
+    /* Re-reads the header, returning true if the file has been rebuilt since it was last read */
+    pub fn reload(&mut self) -> Result<bool,NCDError> {
+        let header = NCDHeader::read(self.reader.as_mut())?;
+        let changed = header.stamp() != self.header.stamp();
+        self.replace_header(header);
+        Ok(changed)
     }
 
     pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>,NCDError> {
+        self.retrying(|reader| reader.lookup(key))
+    }
+
+    /* Every value for the key, in the order given to the build, and none for a missing key. In multi-valued files
+     * the other methods see the list as stored, as each value's length then its bytes. Other files have at
+     * most one value.
+     */
+    pub fn get_all(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>,NCDError> {
+        match self.get(key)? {
+            Some(list) if self.header.multi_valued() => decode_list(&list),
+            Some(value) => Ok(vec![value]),
+            None => Ok(vec![])
+        }
+    }
+
+    /* None for files built without build stats */
+    pub fn build_stats(&mut self) -> Result<Option<NCDBuildStats>,NCDError> {
+        match self.header.build_stats_location() {
+            Some((offset,length)) => Ok(Some(NCDBuildStats::parse(&read_external(self,offset,length)?)?)),
+            None => Ok(None)
+        }
+    }
+
+    /* number of records, for files with build stats */
+    pub fn len(&mut self) -> Result<Option<u64>,NCDError> {
+        Ok(self.build_stats()?.map(|stats| stats.records()))
+    }
+
+    pub fn is_empty(&mut self) -> Result<Option<bool>,NCDError> {
+        Ok(self.len()?.map(|len| len == 0))
+    }
+
+    /* None for files built without metadata */
+    pub fn metadata(&mut self) -> Result<Option<BTreeMap<String,String>>,NCDError> {
+        match self.header.metadata_location() {
+            Some((offset,length)) => Ok(Some(decode_metadata(&read_external(self,offset,length)?)?)),
+            None => Ok(None)
+        }
+    }
+
+    /* Without fetching an external value. Only the start of its entry is read, to check the key, and with
+     * external_keys in the build config not even that.
+     */
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool,NCDError> {
+        Ok(self.retrying(|reader| reader.locate_stored(key))?.is_some())
+    }
+
+    /* compressed values give their length in their prefix, so needn't be decoded */
+    pub fn value_len(&mut self, key: &[u8]) -> Result<Option<u64>,NCDError> {
+        self.retrying(|reader| {
+            let location = match reader.locate_stored(key)? {
+                Some(location) => location,
+                None => { return Ok(None); }
+            };
+            if !reader.header.compressed() {
+                return Ok(Some(location.len()));
+            }
+            let stored_len = location.len();
+            let prefix = match location {
+                NCDValueLocation::Inline(stored) => NCDValuePrefix::parse(&stored)?,
+                NCDValueLocation::External(offset,length) => reader.read_prefix(offset,length)?
+            };
+            Ok(Some(prefix.len(stored_len)))
+        })
+    }
+
+    /* Up to len bytes of the value from start. External values are read only in the part needed. */
+    pub fn get_range(&mut self, key: &[u8], start: u64, len: u64) -> Result<Option<Vec<u8>>,NCDError> {
+        self.retrying(|reader| Ok(match reader.locate(key)? {
+            None => None,
+            Some(NCDValueLocation::Inline(value)) => {
+                let start = start.min(value.len() as u64) as usize;
+                let end = start.saturating_add(len.min(usize::MAX as u64) as usize).min(value.len());
+                Some(value[start..end].to_vec())
+            },
+            Some(NCDValueLocation::External(offset,length)) => {
+                let start = start.min(length);
+                let len = len.min(length-start);
+                Some(read_external(reader,offset+start,len)?)
+            }
+        }))
+    }
+
+    /* Streams the value, fetching external values in chunks. The file changing part way through can't be
+     * recovered from, so shows as an NCDAccessError of kind ResourceChanged.
+     */
+    pub fn get_reader<'r>(&'r mut self, key: &[u8]) -> Result<Option<NCDValueReader<'r,'a>>,NCDError> {
+        Ok(self.retrying(|reader| reader.locate(key))?.map(move |location| NCDValueReader {
+            reader: self,
+            location,
+            position: 0,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            buffer: vec![],
+            buffer_start: 0
+        }))
+    }
+
+    fn retrying<T,F>(&mut self, mut callback: F) -> Result<T,NCDError> where F: FnMut(&mut NCDReader<'a>) -> Result<T,NCDError> {
+        let mut changed = false;
         loop {
-            match self.lookup(key) {
+            match callback(self) {
                 Err(NCDError::WrongStamp) => {
                     let new_header = NCDHeader::read(self.reader.as_mut())?;
                     if new_header.stamp() == self.header().stamp() {
                         return Err(NCDError::WrongStamp);
                     }
-                    self.header = new_header;
+                    self.replace_header(new_header);
+                },
+                Err(NCDError::ResourceChanged) if !changed => {
+                    /* accessor noticed the file was replaced: the stamp may or may not have changed */
+                    let new_header = NCDHeader::read(self.reader.as_mut())?;
+                    self.replace_header(new_header);
+                    changed = true;
+                },
+                Err(NCDError::CorruptNCDFile(message)) => {
+                    /* with a stale header a page can come up short or misparse before its stamp is reached */
+                    match NCDHeader::read(self.reader.as_mut()) {
+                        Ok(new_header) if new_header.stamp() != self.header().stamp() => { self.replace_header(new_header); },
+                        _ => { return Err(NCDError::CorruptNCDFile(message)); }
+                    }
                 },
                 x => { return x; }                
             }
@@ -192,13 +659,76 @@ impl<'a> NCDReader<'a> {
     }
 }
 
+fn parse_page_index(bytes: &[u8]) -> Result<Vec<u64>,NCDError> {
+    let mut offset = 0;
+    let mut out = vec![];
+    while offset < bytes.len() {
+        out.push(read_u64(bytes,&mut offset)?);
+    }
+    Ok(out)
+}
+
+fn read_external(reader: &mut NCDReader, offset: u64, length: u64) -> Result<Vec<u8>,NCDError> {
+    if length == 0 { return Ok(vec![]); }
+    let out = wrap_io_error(reader.accessor().read_kind(NCDReadKind::External,offset,length))?;
+    if (out.len() as u64) < length {
+        return Err(NCDError::CorruptNCDFile(format!("short external read: wanted {} bytes, got {}",length,out.len())));
+    }
+    Ok(out)
+}
+
+const DEFAULT_CHUNK_SIZE : u64 = 1024*1024;
+
+pub struct NCDValueReader<'r,'a> {
+    reader: &'r mut NCDReader<'a>,
+    location: NCDValueLocation,
+    position: u64,
+    chunk_size: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64
+}
+
+impl<'r,'a> NCDValueReader<'r,'a> {
+    pub fn chunk_size(mut self, chunk_size: u64) -> NCDValueReader<'r,'a> {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn len(&self) -> u64 { self.location.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<'r,'a> io::Read for NCDValueReader<'r,'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len() - self.position;
+        if remaining == 0 || buf.is_empty() { return Ok(0); }
+        let available = match &self.location {
+            NCDValueLocation::Inline(value) => &value[self.position as usize..],
+            NCDValueLocation::External(offset,_) => {
+                let buffer_end = self.buffer_start + self.buffer.len() as u64;
+                if self.position < self.buffer_start || self.position >= buffer_end {
+                    let size = remaining.min(self.chunk_size);
+                    self.buffer = read_external(self.reader,offset+self.position,size)?;
+                    self.buffer_start = self.position;
+                }
+                &self.buffer[(self.position-self.buffer_start) as usize..]
+            }
+        };
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::{env::temp_dir, fs::{File, OpenOptions}, io::{BufWriter, Write}, path::Path};
+    use std::{collections::HashMap, env::temp_dir, fs::{File, OpenOptions}, io::{self, BufWriter, Cursor, Read, Write}, path::Path};
 
     use tempfile::{NamedTempFile, tempfile};
 
-    use crate::{StdNCDReadMutAccessor, bitbash::{compute_hash, write_u32}, header::MAGIC_NUMBER, read::{NCDReader, NCDLookupEntry, NCDLookupResult}, test::{SMOKE_FILE, delete_if_exists, example_file, fuzz_scratch, tinker_with_data, update_header_stamp, update_table_stamp}, util::{NCDError, wrap_io_error}};
+    use crate::{NCDAccessError, NCDAccessErrorKind, NCDBuildConfig, NCDInstrumentedAccessor, NCDReadAccessor, NCDReadConfig, NCDReadKind, StdNCDReadAccessor, StdNCDReadMutAccessor, bitbash::{compute_hash, write_u32}, header::{MAGIC_NUMBER, NCDHeader}, read::{NCDReader, NCDLookupEntry, NCDLookupResult}, test::{ListSource, SMOKE_FILE, build_file, build_source, delete_if_exists, example_file, fuzz_scratch, numeric_key_values, tinker_with_data, update_header_stamp, update_table_stamp}, util::{NCDError, wrap_io_error}};
 
     fn do_file_read_smoke() -> Result<(),NCDError> {
         let tmp_dir = temp_dir();
@@ -216,7 +746,7 @@ mod test {
         assert_eq!(&value,&NCDLookupResult::Internal(b"Hello".to_vec(),b"World".to_vec()));
         let value = page.lookup(0)?;
         match value {
-            NCDLookupResult::External(_,_,_) => {},
+            NCDLookupResult::External(_,_,_,_) => {},
             _ => { assert!(true); }
         }
         let value = page.lookup(0)?.resolve(&mut reader,b"Goodbye")?;
@@ -315,6 +845,566 @@ mod test {
         Ok(())
     }
 
+    struct ChangingAccessor {
+        data: Vec<u8>,
+        replacement: Option<Vec<u8>>,
+        reads: usize,
+        change_at: usize
+    }
+
+    impl NCDReadAccessor for ChangingAccessor {
+        fn read(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+            self.reads += 1;
+            if self.reads >= self.change_at && self.replacement.is_some() {
+                self.data = self.replacement.take().unwrap();
+                return Err(NCDAccessError::new(NCDAccessErrorKind::ResourceChanged,1).into());
+            }
+            let start = (offset as usize).min(self.data.len());
+            let end = ((offset+length) as usize).min(self.data.len());
+            Ok(self.data[start..end].to_vec())
+        }
+    }
+
+    fn do_test_resource_changed() -> Result<(),NCDError> {
+        let mut reader = NCDReader::new(ChangingAccessor { data: SMOKE_FILE.to_vec(), replacement: None, reads: 0, change_at: 2 })?;
+        assert_eq!(None,reader.get(b"1")?);
+        /* first read is the header */
+        let accessor = ChangingAccessor { data: SMOKE_FILE.to_vec(), replacement: Some(example_file()?), reads: 0, change_at: 2 };
+        let mut reader = NCDReader::new(accessor)?;
+        assert_eq!(Some(b"999".to_vec()),reader.get(b"1")?);
+        assert_eq!(None,reader.get(b"Hello")?);
+        /* the header, then the page, then the change on reading the value */
+        let mut data = numeric_key_values(10);
+        data.insert(b"big".to_vec(),vec![1;100000]);
+        let before = build_file(data.clone(),&NCDBuildConfig::new())?;
+        data.insert(b"big".to_vec(),vec![2;200000]);
+        let after = build_file(data,&NCDBuildConfig::new())?;
+        let mut reader = NCDReader::new(ChangingAccessor { data: before.clone(), replacement: Some(after.clone()), reads: 0, change_at: 4 })?;
+        assert_eq!(Some(vec![2;10]),reader.get_range(b"big",50000,10)?);
+        let mut reader = NCDReader::new(ChangingAccessor { data: before, replacement: Some(after), reads: 0, change_at: 4 })?;
+        let error = reader.get_reader(b"big")?.unwrap().read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(Some(&NCDAccessErrorKind::ResourceChanged),NCDAccessError::from_io_error(&error).map(|e| e.kind()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_changed() {
+        do_test_resource_changed().unwrap();
+    }
+
+    fn page_zero_key(reader: &NCDReader) -> Result<Vec<u8>,NCDError> {
+        for i in 0.. {
+            let key = format!("{}",i).into_bytes();
+            if reader.header().hash_page_index(compute_hash(&key)?) == 0 { return Ok(key); }
+        }
+        unreachable!()
+    }
+
+    fn page_reads(reader: &NCDReader) -> u64 { reader.stats().unwrap().get(NCDReadKind::Page).reads() }
+
+    fn do_test_speculative_open() -> Result<(),NCDError> {
+        let data = example_file()?;
+        let open = |config: &NCDReadConfig| -> Result<NCDReader,NCDError> {
+            let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(data.clone())))?;
+            NCDReader::with_config(NCDInstrumentedAccessor::new(std),config)
+        };
+        let mut reader = open(&NCDReadConfig::new().prefix_size(4096))?;
+        let key = page_zero_key(&reader)?;
+        let stats = reader.stats().unwrap();
+        assert_eq!(1,stats.get(NCDReadKind::Header).reads());
+        assert_eq!(4096,stats.get(NCDReadKind::Header).bytes());
+        assert!(reader.get(&key)?.is_some());
+        assert_eq!(0,page_reads(&reader));
+        assert!(reader.get(&key)?.is_some());
+        assert_eq!(1,page_reads(&reader));
+        /* too short to include the first page */
+        let mut reader = open(&NCDReadConfig::new().prefix_size(100))?;
+        assert!(reader.get(&key)?.is_some());
+        assert_eq!(1,page_reads(&reader));
+        let header = reader.header().clone();
+        let mut reader = open(&NCDReadConfig::new().header_hint(Some(header.clone())))?;
+        assert_eq!(header.page_size() as u64,reader.stats().unwrap().get(NCDReadKind::Header).bytes());
+        assert_eq!(&header,reader.header());
+        assert!(reader.get(&key)?.is_some());
+        assert_eq!(0,page_reads(&reader));
+        Ok(())
+    }
+
+    #[test]
+    fn test_speculative_open() {
+        do_test_speculative_open().unwrap();
+    }
+
+    fn do_test_with_header() -> Result<(),NCDError> {
+        let data = example_file()?;
+        let open = |header: NCDHeader| -> Result<NCDReader,NCDError> {
+            let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(data.clone())))?;
+            Ok(NCDReader::with_header(NCDInstrumentedAccessor::new(std),header))
+        };
+        let descriptor = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(data.clone())))?)?.header().descriptor();
+        let mut reader = open(NCDHeader::from_descriptor(&descriptor)?)?;
+        assert_eq!(Some(b"999".to_vec()),reader.get(b"1")?);
+        let stats = reader.stats().unwrap();
+        assert_eq!(0,stats.get(NCDReadKind::Header).reads());
+        assert_eq!(1,stats.get(NCDReadKind::Page).reads());
+        /* out of date */
+        let mut parts = descriptor.split('.').map(|x| x.to_string()).collect::<Vec<_>>();
+        parts[5] = "1234".to_string();
+        let mut reader = open(NCDHeader::from_descriptor(&parts.join("."))?)?;
+        assert_eq!(Some(b"999".to_vec()),reader.get(b"1")?);
+        assert_eq!(descriptor,reader.header().descriptor());
+        assert_eq!(1,reader.stats().unwrap().get(NCDReadKind::Header).reads());
+        /* out of date, from before the file was rebuilt with a different page count and page size */
+        for count in [10,5000] {
+            let file = build_file(numeric_key_values(count),&NCDBuildConfig::new())?;
+            let mut parts = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?.header().descriptor().split('.').map(|x| x.to_string()).collect::<Vec<_>>();
+            parts[5] = "1234".to_string();
+            let stale = NCDHeader::from_descriptor(&parts.join("."))?;
+            assert_ne!(stale.number_of_pages(),NCDHeader::from_descriptor(&descriptor)?.number_of_pages());
+            let mut reader = open(stale)?;
+            assert_eq!(Some(b"999".to_vec()),reader.get(b"1")?);
+            assert_eq!(descriptor,reader.header().descriptor());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_header() {
+        do_test_with_header().unwrap();
+    }
+
+    fn big_value(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i*7 % 251) as u8).collect()
+    }
+
+    fn do_test_partial_reads() -> Result<(),NCDError> {
+        let mut data = numeric_key_values(200);
+        data.insert(b"big".to_vec(),big_value(300000));
+        data.insert(b"empty".to_vec(),vec![]);
+        let file = build_file(data,&NCDBuildConfig::new().target_page_size(1024))?;
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        let big = big_value(300000);
+        reader.reset_stats();
+        assert_eq!(Some(big[1000..1100].to_vec()),reader.get_range(b"big",1000,100)?);
+        let stats = reader.stats().unwrap();
+        assert_eq!(2,stats.get(NCDReadKind::External).reads());
+        assert!(stats.get(NCDReadKind::External).bytes() < 200);
+        assert_eq!(Some(big[299990..].to_vec()),reader.get_range(b"big",299990,100)?);
+        assert_eq!(Some(vec![]),reader.get_range(b"big",400000,100)?);
+        assert_eq!(Some(b"9".to_vec()),reader.get_range(b"101",1,10)?);
+        assert_eq!(Some(vec![]),reader.get_range(b"empty",0,10)?);
+        assert_eq!(None,reader.get_range(b"missing",0,10)?);
+        reader.reset_stats();
+        let mut value_reader = reader.get_reader(b"big")?.unwrap().chunk_size(65536);
+        assert_eq!(300000,value_reader.len());
+        let mut out = vec![];
+        wrap_io_error(value_reader.read_to_end(&mut out))?;
+        assert_eq!(big,out);
+        /* one to locate, then five chunks */
+        assert_eq!(6,reader.stats().unwrap().get(NCDReadKind::External).reads());
+        let mut out = String::new();
+        wrap_io_error(reader.get_reader(b"101")?.unwrap().read_to_string(&mut out))?;
+        assert_eq!("99",out);
+        assert!(reader.get_reader(b"missing")?.is_none());
+        assert_eq!(Some(big),reader.get(b"big")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_reads() {
+        do_test_partial_reads().unwrap();
+    }
+
+    fn external_reads(reader: &NCDReader) -> u64 { reader.stats().unwrap().get(NCDReadKind::External).reads() }
+
+    fn do_test_existence(external_keys: bool) -> Result<(),NCDError> {
+        let mut data = numeric_key_values(200);
+        data.insert(b"big".to_vec(),big_value(300000));
+        let file = build_file(data.clone(),&NCDBuildConfig::new().target_page_size(1024).external_keys(external_keys))?;
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert_eq!(external_keys,reader.header().external_keys());
+        reader.reset_stats();
+        assert!(reader.contains(b"big")?);
+        assert_eq!(Some(300000),reader.value_len(b"big")?);
+        assert_eq!(if external_keys { 0 } else { 2 },external_reads(&reader));
+        assert!(reader.stats().unwrap().get(NCDReadKind::External).bytes() < 100);
+        for i in 200..300 {
+            assert!(!reader.contains(format!("{}",i).as_bytes())?);
+            assert_eq!(None,reader.value_len(format!("{}",i).as_bytes())?);
+        }
+        for (key,value) in &data {
+            assert!(reader.contains(key)?);
+            assert_eq!(Some(value.len() as u64),reader.value_len(key)?);
+        }
+        reader.reset_stats();
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        assert_eq!(Some(b"9".to_vec()),reader.get_range(b"101",1,10)?);
+        assert_eq!(Some(big_value(300000)[5..10].to_vec()),reader.get_range(b"big",5,5)?);
+        Ok(())
+    }
+
+    fn do_test_fingerprint_widths(bits: Option<u32>) -> Result<(),NCDError> {
+        let mut data = numeric_key_values(200);
+        for i in 0..50 {
+            data.insert(format!("big{}",i).into_bytes(),big_value(1000+i));
+        }
+        let file = build_file(data.clone(),&NCDBuildConfig::new().target_page_size(1024).fingerprint_bits(bits))?;
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert_eq!(bits.unwrap_or(32),reader.header().fingerprint_bits());
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        assert!(external_reads(&reader) >= 50);
+        reader.reset_stats();
+        for i in 50..2000 {
+            assert_eq!(None,reader.get(format!("big{}",i).as_bytes())?);
+        }
+        if bits.unwrap_or(32) > 32 {
+            assert_eq!(0,external_reads(&reader));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_widths() {
+        for bits in [None,Some(32),Some(64),Some(128)] {
+            do_test_fingerprint_widths(bits).unwrap();
+        }
+        assert!(build_file(numeric_key_values(10),&NCDBuildConfig::new().fingerprint_bits(Some(16))).is_err());
+    }
+
+    #[cfg(all(feature="zstd",feature="lz4_flex"))]
+    fn json_values(limit: usize) -> HashMap<Vec<u8>,Vec<u8>> {
+        (0..limit).map(|i| {
+            let value = format!("{{\"id\":{},\"name\":\"item number {}\",\"tags\":[\"alpha\",\"beta\",\"gamma\"],\"active\":true}}",i,i);
+            (format!("{}",i).into_bytes(),value.into_bytes())
+        }).collect()
+    }
+
+    #[cfg(all(feature="zstd",feature="lz4_flex"))]
+    fn do_test_compression(compression: crate::NCDCompression, dictionary_size: usize) -> Result<(),NCDError> {
+        let mut data = json_values(2000);
+        let noise = (0..300000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        data.insert(b"big".to_vec(),noise.clone());
+        data.insert(b"text".to_vec(),b"abcdefgh".repeat(10000));
+        data.insert(b"empty".to_vec(),vec![]);
+        let config = NCDBuildConfig::new().target_page_size(4096);
+        let plain = build_file(data.clone(),&config)?;
+        let file = build_file(data.clone(),&config.compression(Some(compression)).dictionary_size(dictionary_size))?;
+        assert!(file.len() < plain.len());
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert!(reader.header().compressed());
+        assert_eq!(dictionary_size > 0,reader.header().dictionary_len() > 0);
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+            assert_eq!(Some(value.len() as u64),reader.value_len(key)?);
+            assert!(reader.contains(key)?);
+        }
+        assert_eq!(None,reader.get(b"missing")?);
+        assert_eq!(None,reader.value_len(b"missing")?);
+        assert!(!reader.contains(b"missing")?);
+        let text = b"abcdefgh".repeat(10000);
+        assert_eq!(Some(text[1000..1010].to_vec()),reader.get_range(b"text",1000,10)?);
+        assert_eq!(Some(b"\"id\":7".to_vec()),reader.get_range(b"7",1,6)?);
+        /* incompressible values stay raw, and are still read only in the part needed. (A dictionary may have
+         * been trained on some of the noise, so only without one.)
+         */
+        reader.reset_stats();
+        assert_eq!(Some(noise[1000..1100].to_vec()),reader.get_range(b"big",1000,100)?);
+        if dictionary_size == 0 {
+            assert_eq!(3,external_reads(&reader));
+            assert!(reader.stats().unwrap().get(NCDReadKind::External).bytes() < 200);
+        }
+        let mut out = String::new();
+        wrap_io_error(reader.get_reader(b"text")?.unwrap().read_to_string(&mut out))?;
+        assert_eq!(text,out.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature="zstd",feature="lz4_flex"))]
+    fn test_compression() {
+        for compression in [crate::NCDCompression::Zstd(3),crate::NCDCompression::Lz4] {
+            do_test_compression(compression,0).unwrap();
+            do_test_compression(compression,4096).unwrap();
+        }
+    }
+
+    #[cfg(all(feature="zstd",feature="lz4_flex"))]
+    fn do_test_page_compression(compression: crate::NCDCompression, values: Option<crate::NCDCompression>) -> Result<(),NCDError> {
+        let mut data = json_values(2000);
+        data.insert(b"text".to_vec(),b"abcdefgh".repeat(10000));
+        data.insert(b"empty".to_vec(),vec![]);
+        let config = NCDBuildConfig::new().target_page_size(4096).compression(values).dictionary_size(4096);
+        let plain = build_file(data.clone(),&config)?;
+        let file = build_file(data.clone(),&config.page_compression(Some(compression)))?;
+        assert!(file.len()*2 < plain.len());
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file.clone())))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert!(reader.header().pages_compressed());
+        reader.reset_stats();
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+            assert_eq!(Some(value.len() as u64),reader.value_len(key)?);
+        }
+        for i in 2000..2100 {
+            assert_eq!(None,reader.get(format!("{}",i).as_bytes())?);
+        }
+        let stats = reader.stats().unwrap();
+        /* the index is fetched once */
+        assert_eq!(1,stats.get(NCDReadKind::Header).reads());
+        assert!(stats.get(NCDReadKind::Page).bytes()*2 < stats.get(NCDReadKind::Page).reads()*reader.header().page_size() as u64);
+        /* with a big enough prefix, the index comes with the header */
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::with_config(NCDInstrumentedAccessor::new(std),&NCDReadConfig::new().prefix_size(65536))?;
+        assert_eq!(Some(b"abcdefgh".repeat(10000)),reader.get(b"text")?);
+        assert_eq!(1,reader.stats().unwrap().get(NCDReadKind::Header).reads());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature="zstd",feature="lz4_flex"))]
+    fn test_page_compression() {
+        for compression in [crate::NCDCompression::Zstd(3),crate::NCDCompression::Lz4] {
+            do_test_page_compression(compression,None).unwrap();
+            do_test_page_compression(compression,Some(compression)).unwrap();
+        }
+    }
+
+    fn do_test_metadata(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let data = numeric_key_values(500);
+        let file = build_file(data.clone(),&config.metadata_entry("source","numbers").metadata_entry("schema","2"))?;
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+        let metadata = reader.metadata()?.unwrap();
+        assert_eq!(Some("numbers"),metadata.get("source").map(|x| x.as_str()));
+        assert_eq!(Some("2"),metadata.get("schema").map(|x| x.as_str()));
+        assert_eq!(Some("500"),metadata.get("ncd.records").map(|x| x.as_str()));
+        assert!(metadata.get("ncd.config").unwrap().contains("target_page_size=1024"));
+        assert!(metadata.contains_key("ncd.build_time"));
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        let file = build_file(data.clone(),config)?;
+        assert_eq!(None,NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?.metadata()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_metadata(&config).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        {
+            let compression = Some(crate::NCDCompression::Zstd(3));
+            do_test_metadata(&config.compression(compression).dictionary_size(1024).page_compression(compression)).unwrap();
+        }
+    }
+
+    fn do_test_build_stats(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let mut data = numeric_key_values(1000);
+        for i in 0..20 {
+            data.insert(format!("big{}",i).into_bytes(),big_value(5000));
+        }
+        let total_length = data.iter().map(|(k,v)| (k.len()+v.len()) as u64).sum::<u64>();
+        let file = build_file(data.clone(),&config.build_stats(true))?;
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        reader.reset_stats();
+        assert_eq!(Some(1020),reader.len()?);
+        assert_eq!(1,reader.stats().unwrap().total().reads());
+        assert_eq!(Some(false),reader.is_empty()?);
+        let stats = reader.build_stats()?.unwrap();
+        assert_eq!(total_length,stats.total_length());
+        assert_eq!(20,stats.external_entries());
+        assert!(stats.min_page_entries() <= stats.max_page_entries());
+        assert!(stats.max_page_entries() as u64*reader.header().number_of_pages() >= 1020);
+        assert!(stats.min_heap_used() <= stats.max_heap_used());
+        assert!(stats.max_heap_used() <= reader.header().heap_size());
+        assert!(stats.max_probe() < reader.header().table_size_entries());
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        let file = build_file(HashMap::new(),&config.build_stats(true))?;
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+        assert_eq!(Some(0),reader.len()?);
+        let file = build_file(numeric_key_values(10),config)?;
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+        assert_eq!(None,reader.len()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_stats() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_build_stats(&config).unwrap();
+        do_test_build_stats(&config.metadata(true)).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_build_stats(&config.metadata(true).page_compression(Some(crate::NCDCompression::Lz4))).unwrap();
+    }
+
+    fn do_test_dedup(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let mut data = HashMap::new();
+        for i in 0..2000 {
+            data.insert(format!("big{}",i).into_bytes(),big_value(2000+i%3));
+            data.insert(format!("small{}",i).into_bytes(),format!("colour {}",["red","green","blue"][i%3]).into_bytes());
+            data.insert(format!("tiny{}",i).into_bytes(),vec![b'x';i%2]);
+        }
+        data.insert(b"alone".to_vec(),big_value(5000));
+        let plain = build_file(data.clone(),config)?;
+        let file = build_file(data.clone(),&config.dedup(true))?;
+        assert!(file.len()*5 < plain.len());
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert!(reader.header().shared_values() && reader.header().external_keys());
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+            assert!(reader.contains(key)?);
+            assert_eq!(Some(value.len() as u64),reader.value_len(key)?);
+        }
+        assert_eq!(None,reader.get(b"big2000")?);
+        assert!(!reader.contains(b"small2000")?);
+        reader.reset_stats();
+        assert_eq!(Some(big_value(2001)[100..110].to_vec()),reader.get_range(b"big1",100,10)?);
+        assert!(reader.stats().unwrap().get(NCDReadKind::External).bytes() < 100);
+        assert_eq!(Some(b"ree".to_vec()),reader.get_range(b"small1",8,3)?);
+        /* still right when small values are kept in every entry */
+        let unshared = build_file(data.clone(),&config.dedup(true).dedup_in_pages(false))?;
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(unshared)))?)?;
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() {
+        let config = NCDBuildConfig::new().target_page_size(4096);
+        do_test_dedup(&config).unwrap();
+        do_test_dedup(&config.fingerprint_bits(Some(64))).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_dedup(&config.compression(Some(crate::NCDCompression::Lz4))).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_dedup(&config.page_compression(Some(crate::NCDCompression::Zstd(3)))).unwrap();
+    }
+
+    fn do_test_multi_valued(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let mut list = vec![];
+        for gene in 0..300 {
+            for transcript in 0..gene%4 {
+                list.push((format!("GENE{}",gene).into_bytes(),format!("TX{}.{}",gene,transcript).into_bytes()));
+            }
+        }
+        /* some given again out of order, and one with too many to keep in its page */
+        for gene in (0..300).step_by(7) {
+            list.push((format!("GENE{}",gene).into_bytes(),format!("TX{}.late",gene).into_bytes()));
+        }
+        for transcript in 0..500 {
+            list.push((b"BIG".to_vec(),format!("TXBIG.{}",transcript).into_bytes()));
+        }
+        let mut expected : HashMap<Vec<u8>,Vec<Vec<u8>>> = HashMap::new();
+        for (key,value) in &list {
+            expected.entry(key.clone()).or_default().push(value.clone());
+        }
+        let file = build_source(&ListSource(list),&config.multi_valued(true).build_stats(true))?;
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert!(reader.header().multi_valued());
+        assert_eq!(Some(expected.len() as u64),reader.len()?);
+        reader.reset_stats();
+        for (key,values) in &expected {
+            assert_eq!(values,&reader.get_all(key)?);
+        }
+        assert_eq!(Vec::<Vec<u8>>::new(),reader.get_all(b"GENE300")?);
+        /* all but the big list in one read each */
+        let stats = reader.stats().unwrap();
+        assert_eq!(expected.len() as u64+1,stats.get(NCDReadKind::Page).reads());
+        assert!(external_reads(&reader) <= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_valued() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_multi_valued(&config).unwrap();
+        do_test_multi_valued(&config.external_keys(true)).unwrap();
+        do_test_multi_valued(&config.dedup(true)).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_multi_valued(&config.compression(Some(crate::NCDCompression::Zstd(3)))).unwrap();
+        let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(example_file().unwrap()))).unwrap()).unwrap();
+        assert_eq!(vec![b"999".to_vec()],reader.get_all(b"1").unwrap());
+        assert!(reader.get_all(b"missing").unwrap().is_empty());
+    }
+
+    fn do_test_perfect_hash(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let mut data = numeric_key_values(3000);
+        for i in 0..20 {
+            data.insert(format!("big{}",i).into_bytes(),big_value(5000));
+        }
+        let plain = build_file(data.clone(),&config.build_stats(true))?;
+        let file = build_file(data.clone(),&config.perfect_hash(true).build_stats(true))?;
+        assert!(file.len() < plain.len());
+        let std = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?;
+        let mut reader = NCDReader::new(NCDInstrumentedAccessor::new(std))?;
+        assert!(reader.header().perfect_hash());
+        assert_eq!(1,reader.header().table_size_entries());
+        assert_eq!(0,reader.build_stats()?.unwrap().max_probe());
+        reader.reset_stats();
+        for (key,value) in &data {
+            assert_eq!(Some(value),reader.get(key)?.as_ref());
+        }
+        for i in 3000..4000 {
+            assert_eq!(None,reader.get(format!("{}",i).as_bytes())?);
+        }
+        assert_eq!(data.len() as u64+1000,page_reads(&reader));
+        Ok(())
+    }
+
+    /* pages of a little over 64k, whose heaps grow past what two-byte pointers reach once the table goes */
+    fn do_test_perfect_hash_wide_heap() -> Result<(),NCDError> {
+        let data = (0..5000).map(|i| (format!("{}",i).into_bytes(),format!("{:080}",i).into_bytes())).collect::<HashMap<_,_>>();
+        for (page_size,heap_wiggle_room) in [(69000,0.8),(69500,0.9),(70000,1.0)] {
+            let config = NCDBuildConfig::new().target_page_size(page_size).heap_wiggle_room(heap_wiggle_room).perfect_hash(true);
+            let file = build_file(data.clone(),&config)?;
+            let mut reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+            assert!(reader.header().heap_size() > 0xFFFF);
+            assert_eq!(4,reader.header().pointer_length());
+            for (key,value) in &data {
+                assert_eq!(Some(value),reader.get(key)?.as_ref());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_perfect_hash_wide_heap() {
+        do_test_perfect_hash_wide_heap().unwrap();
+    }
+
+    #[test]
+    fn test_perfect_hash() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_perfect_hash(&config).unwrap();
+        do_test_perfect_hash(&config.external_keys(true)).unwrap();
+        do_test_perfect_hash(&config.dedup(true)).unwrap();
+        do_test_perfect_hash(&config.hash_algorithm(crate::NCDHashAlgorithm::Xxh3).target_page_size(65536)).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_perfect_hash(&config.page_compression(Some(crate::NCDCompression::Lz4))).unwrap();
+        let config = config.perfect_hash(true).set(Some(crate::NCDSetMode::Fingerprints));
+        assert!(build_file(numeric_key_values(10),&config).is_err());
+    }
+
+    #[test]
+    fn test_existence() {
+        do_test_existence(false).unwrap();
+        do_test_existence(true).unwrap();
+    }
+
     #[test]
     fn test_stamp_change() {
         do_test_stamp_change(false,false,true).unwrap();
@@ -0,0 +1,475 @@
+use std::{io, net::TcpListener, sync::{Arc, Mutex, mpsc::{self, Sender}}, time::{Duration, Instant}};
+use crate::{NCDError, NCDReadAccessor, NCDReadKind, NCDReader, servers::http::{HttpHandler, HttpRequest, HttpResponse, percent_decode_path, start_server_on}};
+
+#[derive(Clone)]
+pub struct NCDGatewayConfig {
+    reload_interval: Option<Duration>,
+    max_batch: usize
+}
+
+impl Default for NCDGatewayConfig {
+    fn default() -> NCDGatewayConfig { NCDGatewayConfig::new() }
+}
+
+impl NCDGatewayConfig {
+    pub fn new() -> NCDGatewayConfig {
+        NCDGatewayConfig {
+            reload_interval: Some(Duration::new(10,0)),
+            max_batch: 1000
+        }
+    }
+
+    chain!(reload_interval,get_reload_interval,Option<Duration>,NCDGatewayConfig);
+    chain!(max_batch,get_max_batch,usize,NCDGatewayConfig);
+}
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+enum Format { Raw, Text, Json }
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "raw" => Some(Format::Raw),
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Format> {
+        match mime {
+            "application/octet-stream" => Some(Format::Raw),
+            "text/plain" => Some(Format::Text),
+            "application/json" => Some(Format::Json),
+            _ => None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Raw => "application/octet-stream",
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Json => "application/json"
+        }
+    }
+}
+
+/* An explicit ?format= wins, then the first recognised type in Accept. Quality values are ignored. */
+fn choose_format(request: &HttpRequest, default: Format) -> Option<Format> {
+    if let Some(name) = request.query_value("format") {
+        return Format::from_name(&name);
+    }
+    let accept = match request.header("Accept") {
+        Some(accept) => accept,
+        None => { return Some(default); }
+    };
+    for mime in accept.split(',') {
+        let mime = mime.split(';').next().unwrap_or("").trim();
+        if mime == "*/*" { return Some(default); }
+        if let Some(format) = Format::from_mime(mime) { return Some(format); }
+    }
+    None
+}
+
+fn json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}",c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+struct GatewayFile<'a> {
+    name: String,
+    reader: NCDReader<'a>,
+    last_check: Instant,
+    healthy: bool,
+    hits: u64
+}
+
+#[derive(Default)]
+struct GatewayMetrics {
+    requests: u64,
+    lookups: u64,
+    hits: u64,
+    misses: u64,
+    errors: u64,
+    reloads: u64
+}
+
+enum GatewayError {
+    Status(u32,String),
+    Lookup(NCDError)
+}
+
+impl GatewayError {
+    fn response(self) -> HttpResponse {
+        match self {
+            GatewayError::Status(code,message) => HttpResponse::text(code,&message),
+            GatewayError::Lookup(e) => HttpResponse::text(502,&e.to_string())
+        }
+    }
+}
+
+fn not_acceptable() -> GatewayError {
+    GatewayError::Status(406,"Requested format unavailable: use format=raw, text or json".to_string())
+}
+
+fn utf8(value: &[u8]) -> Result<&str,GatewayError> {
+    std::str::from_utf8(value).map_err(|_| GatewayError::Status(406,"Value is not UTF-8: use format=raw".to_string()))
+}
+
+/* Answers key lookups over HTTP from one or more NCD files, searched in the order added. NCDReaders aren't
+ * thread-safe, so requests are funnelled to the thread calling serve().
+ */
+pub struct NCDGateway<'a> {
+    config: NCDGatewayConfig,
+    files: Vec<GatewayFile<'a>>,
+    metrics: GatewayMetrics,
+    started: Instant
+}
+
+struct ChannelHandler(Mutex<Sender<(HttpRequest,Sender<HttpResponse>)>>);
+
+impl HttpHandler for ChannelHandler {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let (sender,receiver) = mpsc::channel();
+        let sent = self.0.lock().ok().map(|jobs| jobs.send((request.clone(),sender)).is_ok()).unwrap_or(false);
+        if !sent { return HttpResponse::text(503,"Gateway stopped"); }
+        receiver.recv().unwrap_or_else(|_| HttpResponse::text(503,"Gateway stopped"))
+    }
+}
+
+impl<'a> NCDGateway<'a> {
+    pub fn new(config: &NCDGatewayConfig) -> NCDGateway<'a> {
+        NCDGateway { config: config.clone(), files: vec![], metrics: GatewayMetrics::default(), started: Instant::now() }
+    }
+
+    pub fn add_file(&mut self, name: &str, accessor: Box<dyn NCDReadAccessor + 'a>) -> Result<(),NCDError> {
+        self.files.push(GatewayFile {
+            name: name.to_string(),
+            reader: NCDReader::new_box(accessor)?,
+            last_check: Instant::now(),
+            healthy: true,
+            hits: 0
+        });
+        Ok(())
+    }
+
+    /* Only returns on error */
+    pub fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        let (sender,receiver) = mpsc::channel();
+        let _server = start_server_on(listener,Arc::new(ChannelHandler(Mutex::new(sender))))?;
+        for (request,reply) in receiver {
+            reply.send(self.handle(&request)).ok();
+        }
+        Ok(())
+    }
+
+    fn maybe_reload(&mut self, index: usize) -> Result<(),NCDError> {
+        let interval = match self.config.reload_interval {
+            Some(interval) => interval,
+            None => { return Ok(()); }
+        };
+        let file = &mut self.files[index];
+        if file.last_check.elapsed() < interval { return Ok(()); }
+        file.last_check = Instant::now();
+        if file.reader.reload()? {
+            self.metrics.reloads += 1;
+        }
+        Ok(())
+    }
+
+    fn lookup_in(&mut self, index: usize, key: &[u8]) -> Result<Option<Vec<u8>>,NCDError> {
+        let out = self.maybe_reload(index).and_then(|_| self.files[index].reader.get(key));
+        let file = &mut self.files[index];
+        file.healthy = out.is_ok();
+        if let Ok(Some(_)) = out { file.hits += 1; }
+        out
+    }
+
+    fn lookup(&mut self, key: &[u8], file: Option<&str>) -> Result<Option<Vec<u8>>,GatewayError> {
+        self.metrics.lookups += 1;
+        let indexes = match file {
+            Some(name) => {
+                let index = self.files.iter().position(|f| f.name == name)
+                    .ok_or_else(|| GatewayError::Status(404,format!("No such file: {}",name)))?;
+                vec![index]
+            },
+            None => (0..self.files.len()).collect()
+        };
+        for index in indexes {
+            match self.lookup_in(index,key) {
+                Ok(Some(value)) => {
+                    self.metrics.hits += 1;
+                    return Ok(Some(value));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    self.metrics.errors += 1;
+                    return Err(GatewayError::Lookup(e));
+                }
+            }
+        }
+        self.metrics.misses += 1;
+        Ok(None)
+    }
+
+    fn get_key(&mut self, request: &HttpRequest, key: &[u8]) -> Result<HttpResponse,GatewayError> {
+        let format = choose_format(request,Format::Raw).ok_or_else(not_acceptable)?;
+        let value = match self.lookup(key,request.query_value("file").as_deref())? {
+            Some(value) => value,
+            None => { return Ok(HttpResponse::text(404,"Key not found")); }
+        };
+        let body = match format {
+            Format::Raw => value,
+            Format::Text => utf8(&value)?.as_bytes().to_vec(),
+            Format::Json => {
+                let mut out = "{\"key\":".to_string();
+                json_string(&mut out,utf8(key)?);
+                out.push_str(",\"value\":");
+                json_string(&mut out,utf8(&value)?);
+                out.push('}');
+                out.into_bytes()
+            }
+        };
+        Ok(HttpResponse::new(200).header("Content-Type",format.content_type()).body(body))
+    }
+
+    /* Body is one key per line. Text output is a key<TAB>value line per key found; JSON maps every key to its
+     * value or null.
+     */
+    fn get_keys(&mut self, request: &HttpRequest) -> Result<HttpResponse,GatewayError> {
+        let format = choose_format(request,Format::Json).ok_or_else(not_acceptable)?;
+        if format == Format::Raw { return Err(not_acceptable()); }
+        let body = utf8(request.body())?;
+        let keys = body.lines().filter(|x| !x.is_empty()).collect::<Vec<_>>();
+        if keys.len() > self.config.max_batch {
+            return Err(GatewayError::Status(400,format!("Too many keys: limit is {}",self.config.max_batch)));
+        }
+        let file = request.query_value("file");
+        let mut out = String::new();
+        if format == Format::Json { out.push('{'); }
+        for (i,key) in keys.iter().enumerate() {
+            let value = self.lookup(key.as_bytes(),file.as_deref())?;
+            let value = value.as_deref().map(utf8).transpose()?;
+            if format == Format::Json {
+                if i > 0 { out.push(','); }
+                json_string(&mut out,key);
+                out.push(':');
+                match value {
+                    Some(value) => json_string(&mut out,value),
+                    None => out.push_str("null")
+                }
+            } else if let Some(value) = value {
+                out.push_str(&format!("{}\t{}\n",key,value));
+            }
+        }
+        if format == Format::Json { out.push('}'); }
+        Ok(HttpResponse::new(200).header("Content-Type",format.content_type()).body(out.into_bytes()))
+    }
+
+    fn health(&self) -> HttpResponse {
+        let unhealthy = self.files.iter().filter(|f| !f.healthy).map(|f| f.name.as_str()).collect::<Vec<_>>();
+        if unhealthy.is_empty() {
+            HttpResponse::text(200,"ok\n")
+        } else {
+            HttpResponse::text(503,&format!("failing: {}\n",unhealthy.join(", ")))
+        }
+    }
+
+    /* Prometheus text exposition format */
+    fn metrics(&self) -> HttpResponse {
+        let m = &self.metrics;
+        let mut out = String::new();
+        for (name,kind,value) in [
+            ("ncd_gateway_requests_total","counter",m.requests),
+            ("ncd_gateway_lookups_total","counter",m.lookups),
+            ("ncd_gateway_hits_total","counter",m.hits),
+            ("ncd_gateway_misses_total","counter",m.misses),
+            ("ncd_gateway_errors_total","counter",m.errors),
+            ("ncd_gateway_reloads_total","counter",m.reloads),
+            ("ncd_gateway_uptime_seconds","gauge",self.started.elapsed().as_secs())
+        ] {
+            out.push_str(&format!("# TYPE {} {}\n{} {}\n",name,kind,name,value));
+        }
+        out.push_str("# TYPE ncd_gateway_file_hits_total counter\n");
+        for file in &self.files {
+            out.push_str(&format!("ncd_gateway_file_hits_total{{file=\"{}\"}} {}\n",file.name.replace('"',"\\\""),file.hits));
+        }
+        out.push_str("# TYPE ncd_gateway_file_healthy gauge\n");
+        for file in &self.files {
+            out.push_str(&format!("ncd_gateway_file_healthy{{file=\"{}\"}} {}\n",file.name.replace('"',"\\\""),file.healthy as u32));
+        }
+        out.push_str("# TYPE ncd_gateway_file_reads_total counter\n# TYPE ncd_gateway_file_read_bytes_total counter\n");
+        for file in &self.files {
+            let stats = match file.reader.stats() { Some(stats) => stats, None => { continue; } };
+            for (kind_name,kind) in [("header",NCDReadKind::Header),("page",NCDReadKind::Page),("external",NCDReadKind::External)] {
+                let name = file.name.replace('"',"\\\"");
+                out.push_str(&format!("ncd_gateway_file_reads_total{{file=\"{}\",kind=\"{}\"}} {}\n",name,kind_name,stats.get(kind).reads()));
+                out.push_str(&format!("ncd_gateway_file_read_bytes_total{{file=\"{}\",kind=\"{}\"}} {}\n",name,kind_name,stats.get(kind).bytes()));
+            }
+        }
+        HttpResponse::new(200).header("Content-Type","text/plain; version=0.0.4").body(out.into_bytes())
+    }
+
+    pub(crate) fn handle(&mut self, request: &HttpRequest) -> HttpResponse {
+        self.metrics.requests += 1;
+        let path = request.path();
+        let out = match (request.method(),path) {
+            ("GET",_) | ("HEAD",_) if path.starts_with("/key/") => {
+                let key = percent_decode_path(&path["/key/".len()..]);
+                self.get_key(request,&key)
+            },
+            ("POST","/keys") => self.get_keys(request),
+            ("GET","/health") | ("HEAD","/health") => Ok(self.health()),
+            ("GET","/metrics") => Ok(self.metrics()),
+            (_,"/keys") | (_,"/health") | (_,"/metrics") => Ok(HttpResponse::text(405,"Method not allowed")),
+            _ if path.starts_with("/key/") => Ok(HttpResponse::text(405,"Method not allowed")),
+            _ => Ok(HttpResponse::text(404,"Not found"))
+        };
+        out.unwrap_or_else(|e| e.response())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::{Cursor, Read, Write}, net::{TcpListener, TcpStream}, thread, time::Duration};
+    use tempfile::TempDir;
+
+    use crate::{NCDError, NCDInstrumentedAccessor, StdNCDReadAccessor, servers::http::HttpRequest, test::{SMOKE_FILE, example_file}, wrap_io_error};
+
+    use super::{NCDGateway, NCDGatewayConfig};
+
+    fn request(text: &str) -> HttpRequest {
+        HttpRequest::read(&mut Cursor::new(text.as_bytes().to_vec())).unwrap().unwrap()
+    }
+
+    fn post(path: &str, headers: &str, body: &str) -> HttpRequest {
+        request(&format!("POST {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",path,headers,body.len(),body))
+    }
+
+    fn run(gateway: &mut NCDGateway, request: &HttpRequest) -> (u32,String,String) {
+        let response = gateway.handle(request);
+        let mut out = vec![];
+        response.write(&mut out,false,false).unwrap();
+        let out = String::from_utf8_lossy(&out).to_string();
+        let (head,body) = out.split_once("\r\n\r\n").unwrap();
+        let content_type = head.lines().find_map(|x| x.strip_prefix("Content-Type: ")).unwrap_or("").to_string();
+        (response.code(),content_type,body.to_string())
+    }
+
+    fn gateway<'a>() -> Result<NCDGateway<'a>,NCDError> {
+        let mut gateway = NCDGateway::new(&NCDGatewayConfig::new());
+        let smoke = wrap_io_error(StdNCDReadAccessor::new(Cursor::new(SMOKE_FILE.to_vec())))?;
+        gateway.add_file("smoke",Box::new(NCDInstrumentedAccessor::new(smoke)))?;
+        gateway.add_file("example",Box::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(example_file()?)))?))?;
+        Ok(gateway)
+    }
+
+    fn do_test_gateway_get() -> Result<(),NCDError> {
+        let mut gateway = gateway()?;
+        assert_eq!((200,"application/octet-stream".to_string(),"World".to_string()),run(&mut gateway,&request("GET /key/Hello HTTP/1.1\r\n\r\n")));
+        assert_eq!((200,"999".to_string()),{ let x = run(&mut gateway,&request("GET /key/1 HTTP/1.1\r\n\r\n")); (x.0,x.2) });
+        assert_eq!(404,run(&mut gateway,&request("GET /key/1?file=smoke HTTP/1.1\r\n\r\n")).0);
+        assert_eq!(404,run(&mut gateway,&request("GET /key/missing HTTP/1.1\r\n\r\n")).0);
+        assert_eq!(404,run(&mut gateway,&request("GET /key/1?file=nope HTTP/1.1\r\n\r\n")).0);
+        let (code,content_type,body) = run(&mut gateway,&request("GET /key/Hello?format=json HTTP/1.1\r\n\r\n"));
+        assert_eq!((200,"application/json"),(code,content_type.as_str()));
+        assert_eq!("{\"key\":\"Hello\",\"value\":\"World\"}",body);
+        let (_,content_type,_) = run(&mut gateway,&request("GET /key/1 HTTP/1.1\r\nAccept: text/html, text/plain;q=0.9\r\n\r\n"));
+        assert_eq!("text/plain; charset=utf-8",content_type);
+        assert_eq!(406,run(&mut gateway,&request("GET /key/1 HTTP/1.1\r\nAccept: image/png\r\n\r\n")).0);
+        assert_eq!(406,run(&mut gateway,&request("GET /key/1?format=xml HTTP/1.1\r\n\r\n")).0);
+        assert_eq!(405,run(&mut gateway,&request("DELETE /key/1 HTTP/1.1\r\n\r\n")).0);
+        assert_eq!(404,run(&mut gateway,&request("GET /other HTTP/1.1\r\n\r\n")).0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_get() {
+        do_test_gateway_get().unwrap()
+    }
+
+    fn do_test_gateway_batch() -> Result<(),NCDError> {
+        let mut gateway = gateway()?;
+        let (code,content_type,body) = run(&mut gateway,&post("/keys?file=smoke","","Hello\nmissing\nGoodbye\n"));
+        assert_eq!((200,"application/json"),(code,content_type.as_str()));
+        assert_eq!("{\"Hello\":\"World\",\"missing\":null,\"Goodbye\":\"Mars\"}",body);
+        let (code,_,body) = run(&mut gateway,&post("/keys?file=smoke","Accept: text/plain\r\n","Hello\nmissing\nGoodbye\n"));
+        assert_eq!(200,code);
+        assert_eq!("Hello\tWorld\nGoodbye\tMars\n",body);
+        assert_eq!(406,run(&mut gateway,&post("/keys","Accept: application/octet-stream\r\n","1\n")).0);
+        let mut gateway = NCDGateway::new(&NCDGatewayConfig::new().max_batch(1));
+        assert_eq!(400,run(&mut gateway,&post("/keys","","1\n2\n")).0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_batch() {
+        do_test_gateway_batch().unwrap()
+    }
+
+    fn do_test_gateway_metrics() -> Result<(),NCDError> {
+        let mut gateway = gateway()?;
+        run(&mut gateway,&request("GET /key/Hello HTTP/1.1\r\n\r\n"));
+        run(&mut gateway,&request("GET /key/missing HTTP/1.1\r\n\r\n"));
+        let (code,_,body) = run(&mut gateway,&request("GET /metrics HTTP/1.1\r\n\r\n"));
+        assert_eq!(200,code);
+        assert!(body.contains("ncd_gateway_requests_total 3\n"));
+        assert!(body.contains("ncd_gateway_hits_total 1\n"));
+        assert!(body.contains("ncd_gateway_misses_total 1\n"));
+        assert!(body.contains("ncd_gateway_file_hits_total{file=\"smoke\"} 1\n"));
+        assert!(body.contains("ncd_gateway_file_reads_total{file=\"smoke\",kind=\"page\"} 2\n"));
+        assert!(!body.contains("ncd_gateway_file_reads_total{file=\"example\""));
+        assert_eq!((200,"ok\n".to_string()),{ let x = run(&mut gateway,&request("GET /health HTTP/1.1\r\n\r\n")); (x.0,x.2) });
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_metrics() {
+        do_test_gateway_metrics().unwrap()
+    }
+
+    /* the file is rebuilt under the running gateway */
+    fn do_test_gateway_serve() -> Result<(),NCDError> {
+        let dir = wrap_io_error(TempDir::new())?;
+        let path = dir.path().join("test.ncd");
+        wrap_io_error(fs::write(&path,SMOKE_FILE))?;
+        let listener = wrap_io_error(TcpListener::bind("127.0.0.1:0"))?;
+        let address = wrap_io_error(listener.local_addr())?;
+        let path2 = path.clone();
+        thread::spawn(move || {
+            let file = fs::File::open(path2).unwrap();
+            let mut gateway = NCDGateway::new(&NCDGatewayConfig::new().reload_interval(Some(Duration::from_millis(0))));
+            gateway.add_file("test",Box::new(StdNCDReadAccessor::new(file).unwrap())).unwrap();
+            gateway.serve(listener).ok();
+        });
+        let get = |key: &str| -> String {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(format!("GET /key/{} HTTP/1.1\r\nConnection: close\r\n\r\n",key).as_bytes()).unwrap();
+            let mut out = String::new();
+            stream.read_to_string(&mut out).unwrap();
+            out.split_once("\r\n\r\n").unwrap().1.to_string()
+        };
+        assert_eq!("World",get("Hello"));
+        let example = example_file()?;
+        wrap_io_error(fs::OpenOptions::new().write(true).open(&path).and_then(|mut f| f.write_all(&example)))?;
+        assert_eq!("Key not found",get("Hello"));
+        assert_eq!("999",get("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_serve() {
+        do_test_gateway_serve().unwrap()
+    }
+}
@@ -0,0 +1,435 @@
+use std::{fs::File, io::{self, BufRead, BufReader, Read, Write}, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/* Just enough HTTP/1.1 for our servers: no chunked request bodies, no pipelining beyond keep-alive. */
+
+const MAX_REQUEST_LINE_BYTES : usize = 8192;
+const MAX_HEADER_BYTES : usize = 65536;
+/* only batches of keys are posted */
+const MAX_BODY_BYTES : usize = 1024*1024;
+const MAX_CONNECTIONS : usize = 256;
+/* for each read or write, so an idle keep-alive connection is dropped after this long too */
+const IO_TIMEOUT : Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub(crate) struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String,String)>,
+    body: Vec<u8>,
+    http10: bool
+}
+
+fn bad_request(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,message.to_string())
+}
+
+/* read_line, but an error rather than more memory for a line longer than limit */
+fn read_line_limited<R: BufRead>(reader: &mut R, line: &mut String, limit: usize, message: &str) -> io::Result<usize> {
+    let n = reader.by_ref().take(limit as u64+1).read_line(line)?;
+    if n > limit { return Err(bad_request(message)); }
+    Ok(n)
+}
+
+impl HttpRequest {
+    /* None on clean EOF before a request starts */
+    pub(crate) fn read<R: BufRead>(reader: &mut R) -> io::Result<Option<HttpRequest>> {
+        let mut line = String::new();
+        let mut total = 0;
+        loop {
+            line.clear();
+            let n = read_line_limited(reader,&mut line,MAX_REQUEST_LINE_BYTES,"request line too long")?;
+            if n == 0 { return Ok(None); }
+            if !line.trim().is_empty() { break; }
+            total += n;
+            if total > MAX_HEADER_BYTES { return Err(bad_request("bad request line")); }
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or_else(|| bad_request("missing method"))?.to_string();
+        let target = parts.next().ok_or_else(|| bad_request("missing target"))?;
+        let version = parts.next().unwrap_or("HTTP/1.0");
+        let (path,query) = match target.split_once('?') {
+            Some((path,query)) => (path.to_string(),Some(query.to_string())),
+            None => (target.to_string(),None)
+        };
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            let n = read_line_limited(reader,&mut line,MAX_HEADER_BYTES-total,"bad headers")?;
+            total += n;
+            if n == 0 { return Err(bad_request("bad headers")); }
+            let line = line.trim_end();
+            if line.is_empty() { break; }
+            if let Some((key,value)) = line.split_once(':') {
+                headers.push((key.trim().to_string(),value.trim().to_string()));
+            }
+        }
+        let mut out = HttpRequest { method, path, query, headers, body: vec![], http10: version == "HTTP/1.0" };
+        if let Some(length) = out.header("Content-Length") {
+            let length = length.parse::<usize>().map_err(|_| bad_request("bad content length"))?;
+            if length > MAX_BODY_BYTES { return Err(bad_request("body too large")); }
+            /* read as it arrives, so a claimed length alone takes no memory */
+            reader.by_ref().take(length as u64).read_to_end(&mut out.body)?;
+            if out.body.len() != length { return Err(bad_request("short body")); }
+        }
+        Ok(Some(out))
+    }
+
+    pub(crate) fn method(&self) -> &str { &self.method }
+    pub(crate) fn path(&self) -> &str { &self.path }
+    pub(crate) fn body(&self) -> &[u8] { &self.body }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k,_)| k.eq_ignore_ascii_case(name)).map(|(_,v)| v.as_str())
+    }
+
+    pub(crate) fn query_value(&self, name: &str) -> Option<String> {
+        self.query.as_ref()?.split('&').filter_map(|x| x.split_once('=')).find(|(k,_)| *k == name).map(|(_,v)| percent_decode(v))
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(x) if x.eq_ignore_ascii_case("close") => false,
+            Some(x) if x.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !self.http10
+        }
+    }
+}
+
+/* In query strings + also means space, but not in paths */
+fn unescape(input: &str, form: bool) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i+1..i+3).and_then(|x| std::str::from_utf8(x).ok()).and_then(|x| u8::from_str_radix(x,16).ok());
+        match (bytes[i],hex) {
+            (b'%',Some(value)) => { out.push(value); i += 3; },
+            (b'+',_) if form => { out.push(b' '); i += 1; },
+            (b,_) => { out.push(b); i += 1; }
+        }
+    }
+    out
+}
+
+pub(crate) fn percent_decode(input: &str) -> String {
+    String::from_utf8_lossy(&unescape(input,true)).to_string()
+}
+
+pub(crate) fn percent_decode_path(input: &str) -> Vec<u8> {
+    unescape(input,false)
+}
+
+fn reason(code: u32) -> &'static str {
+    match code {
+        200 => "OK",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        412 => "Precondition Failed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown"
+    }
+}
+
+pub(crate) struct HttpResponse {
+    code: u32,
+    headers: Vec<(String,String)>,
+    body: Vec<u8>,
+    /* sent in place of body, so large files needn't be held in memory */
+    file: Option<(File,u64)>
+}
+
+impl HttpResponse {
+    pub(crate) fn new(code: u32) -> HttpResponse {
+        HttpResponse { code, headers: vec![], body: vec![], file: None }
+    }
+
+    pub(crate) fn text(code: u32, text: &str) -> HttpResponse {
+        HttpResponse::new(code).header("Content-Type","text/plain; charset=utf-8").body(text.as_bytes().to_vec())
+    }
+
+    pub(crate) fn header(mut self, key: &str, value: &str) -> HttpResponse {
+        self.headers.push((key.to_string(),value.to_string()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: Vec<u8>) -> HttpResponse {
+        self.body = body;
+        self
+    }
+
+    /* the next length bytes of the file, from its current position */
+    pub(crate) fn file(mut self, file: File, length: u64) -> HttpResponse {
+        self.file = Some((file,length));
+        self
+    }
+
+    pub(crate) fn code(&self) -> u32 { self.code }
+
+    fn body_length(&self) -> u64 {
+        match &self.file {
+            Some((_,length)) => *length,
+            None => self.body.len() as u64
+        }
+    }
+
+    /* Content-Length is taken from the body unless already set, which allows deliberately lying about it. A file
+     * which turns out shorter than promised is an error, as the connection can't be reused.
+     */
+    pub(crate) fn write<W: Write>(&self, out: &mut W, head: bool, keep_alive: bool) -> io::Result<()> {
+        let mut text = format!("HTTP/1.1 {} {}\r\n",self.code,reason(self.code));
+        for (key,value) in &self.headers {
+            text.push_str(&format!("{}: {}\r\n",key,value));
+        }
+        if !self.headers.iter().any(|(k,_)| k.eq_ignore_ascii_case("Content-Length")) {
+            text.push_str(&format!("Content-Length: {}\r\n",self.body_length()));
+        }
+        text.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+        out.write_all(text.as_bytes())?;
+        if !head {
+            match &self.file {
+                Some((file,length)) => {
+                    if io::copy(&mut file.take(*length),out)? < *length {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,"file shorter than expected"));
+                    }
+                },
+                None => { out.write_all(&self.body)?; }
+            }
+        }
+        out.flush()
+    }
+}
+
+pub(crate) trait HttpHandler: Send + Sync {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse;
+}
+
+fn serve_connection(stream: TcpStream, handler: &dyn HttpHandler) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let request = match HttpRequest::read(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => { return Ok(()); },
+            Err(e) => {
+                HttpResponse::text(400,&e.to_string()).write(&mut writer,false,false)?;
+                return Ok(());
+            }
+        };
+        let response = handler.handle(&request);
+        let keep_alive = request.keep_alive() && response.code() != 400;
+        response.write(&mut writer,request.method() == "HEAD",keep_alive)?;
+        if !keep_alive { return Ok(()); }
+    }
+}
+
+/* Counts an open connection until dropped */
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(open: &Arc<AtomicUsize>) -> Option<ConnectionSlot> {
+        if open.fetch_add(1,Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1,Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot(open.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1,Ordering::SeqCst);
+    }
+}
+
+/* Beyond MAX_CONNECTIONS a connection gets a 503 at once rather than a thread */
+fn refuse_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    HttpResponse::text(503,"Too many connections").header("Retry-After","1").write(&mut stream,false,false)
+}
+
+/* Runs a server on a background thread, stopping it when dropped. */
+pub struct NCDServerHandle {
+    address: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl NCDServerHandle {
+    pub fn address(&self) -> SocketAddr { self.address }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}",self.address,path.trim_start_matches('/'))
+    }
+
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for NCDServerHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.store(true,Ordering::SeqCst);
+            /* wake the accept loop */
+            TcpStream::connect_timeout(&self.address,Duration::from_secs(1)).ok();
+            thread.join().ok();
+        }
+    }
+}
+
+pub(crate) fn start_server<A: ToSocketAddrs>(address: A, handler: Arc<dyn HttpHandler>) -> io::Result<NCDServerHandle> {
+    start_server_on(TcpListener::bind(address)?,handler)
+}
+
+pub(crate) fn start_server_on(listener: TcpListener, handler: Arc<dyn HttpHandler>) -> io::Result<NCDServerHandle> {
+    let address = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown2 = shutdown.clone();
+    let open = Arc::new(AtomicUsize::new(0));
+    let thread = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if shutdown2.load(Ordering::SeqCst) { break; }
+            if let Ok(stream) = stream {
+                let slot = match ConnectionSlot::take(&open) {
+                    Some(slot) => slot,
+                    None => { refuse_connection(stream).ok(); continue; }
+                };
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    serve_connection(stream,handler.as_ref()).ok();
+                    drop(slot);
+                });
+            }
+        }
+    });
+    Ok(NCDServerHandle { address, shutdown, thread: Some(thread) })
+}
+
+/* IMF-fixdate, eg Sun, 06 Nov 1994 08:49:37 GMT */
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS : [&str;7] = ["Thu","Fri","Sat","Sun","Mon","Tue","Wed"];
+    const MONTHS : [&str;12] = ["Jan","Feb","Mar","Apr","May","Jun","Jul","Aug","Sep","Oct","Nov","Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    /* civil-from-days, after Howard Hinnant */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2)/153;
+    let day = doy - (153*mp+2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],day,MONTHS[(month-1) as usize],year,rem/3600,(rem%3600)/60,rem%60)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::{self, Cursor, Read, Seek, SeekFrom, Write}, net::TcpStream, sync::Arc, thread, time::{Duration, UNIX_EPOCH}};
+
+    use tempfile::tempfile;
+
+    use super::{HttpHandler, HttpRequest, HttpResponse, MAX_BODY_BYTES, MAX_CONNECTIONS, MAX_HEADER_BYTES, MAX_REQUEST_LINE_BYTES, http_date, percent_decode, percent_decode_path, start_server};
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT",http_date(UNIX_EPOCH));
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT",http_date(UNIX_EPOCH+Duration::from_secs(784111777)));
+        assert_eq!("Tue, 29 Feb 2000 12:00:00 GMT",http_date(UNIX_EPOCH+Duration::from_secs(951825600)));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let text = b"GET /a%20b.ncd?x=1&y=hello+there HTTP/1.1\r\nHost: x\r\nrange: bytes=0-3\r\nContent-Length: 2\r\n\r\nhi";
+        let request = HttpRequest::read(&mut Cursor::new(text.to_vec())).unwrap().unwrap();
+        assert_eq!("GET",request.method());
+        assert_eq!("/a%20b.ncd",request.path());
+        assert_eq!(Some("bytes=0-3"),request.header("Range"));
+        assert_eq!(Some("hello there".to_string()),request.query_value("y"));
+        assert_eq!(None,request.query_value("z"));
+        assert_eq!(b"hi",request.body());
+        assert!(request.keep_alive());
+        assert!(HttpRequest::read(&mut Cursor::new(vec![])).unwrap().is_none());
+        assert_eq!("a b/%zz",percent_decode("a%20b%2F%zz"));
+        assert_eq!(b"a+b c".to_vec(),percent_decode_path("a+b%20c"));
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n","a".repeat(MAX_REQUEST_LINE_BYTES));
+        assert!(HttpRequest::read(&mut Cursor::new(long_target.into_bytes())).is_err());
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n","a".repeat(MAX_HEADER_BYTES));
+        assert!(HttpRequest::read(&mut Cursor::new(long_header.into_bytes())).is_err());
+        assert!(HttpRequest::read(&mut Cursor::new(b"\r\n".repeat(MAX_HEADER_BYTES))).is_err());
+        let short_body = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhi",MAX_BODY_BYTES);
+        assert!(HttpRequest::read(&mut Cursor::new(short_body.into_bytes())).is_err());
+        let long_body = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhi",MAX_BODY_BYTES+1);
+        assert!(HttpRequest::read(&mut Cursor::new(long_body.into_bytes())).is_err());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = vec![];
+        HttpResponse::text(404,"nope").write(&mut out,false,false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.contains("Content-Length: 4\r\n"));
+        assert!(out.ends_with("\r\n\r\nnope"));
+        let mut file = tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        let mut out = vec![];
+        HttpResponse::new(200).file(file.try_clone().unwrap(),5).write(&mut out,false,false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.ends_with("\r\n\r\n23456"));
+        file.seek(SeekFrom::Start(8)).unwrap();
+        assert!(HttpResponse::new(200).file(file,5).write(&mut vec![],false,false).is_err());
+    }
+
+    struct Hello;
+
+    impl HttpHandler for Hello {
+        fn handle(&self, _request: &HttpRequest) -> HttpResponse { HttpResponse::text(200,"hello") }
+    }
+
+    fn get_hello(stream: &mut TcpStream) -> io::Result<String> {
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        let mut text = String::new();
+        stream.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    fn do_test_connection_limit() -> io::Result<()> {
+        let server = start_server("127.0.0.1:0",Arc::new(Hello))?;
+        let idle = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(server.address())).collect::<io::Result<Vec<_>>>()?;
+        /* the 503 is sent without waiting for a request, so sending one could see the connection reset */
+        let mut refused = String::new();
+        TcpStream::connect(server.address())?.read_to_string(&mut refused)?;
+        assert!(refused.starts_with("HTTP/1.1 503"));
+        drop(idle);
+        /* the slots free up as the idle connections' threads see them close */
+        for _ in 0..500 {
+            if get_hello(&mut TcpStream::connect(server.address())?).is_ok_and(|x| x.starts_with("HTTP/1.1 200")) { return Ok(()); }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("connections never freed");
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        do_test_connection_limit().unwrap()
+    }
+}
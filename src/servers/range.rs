@@ -0,0 +1,339 @@
+use std::{fs::{self, File}, io::{self, Read, Seek, SeekFrom}, net::ToSocketAddrs, path::{Component, Path, PathBuf}, sync::{Arc, atomic::{AtomicU32, Ordering}}, thread, time::{Duration, UNIX_EPOCH}};
+use crate::servers::http::{HttpHandler, HttpRequest, HttpResponse, NCDServerHandle, http_date, percent_decode_path, start_server};
+
+/* Faults to inject, for testing clients. Errors are tried first, then range-ignoring, then short reads. */
+#[derive(Clone)]
+pub struct NCDServerConfig {
+    delay: Duration,
+    error_code: u32,
+    fail_first: u32,
+    error_rate: f64,
+    ignore_range: bool,
+    short_read_rate: f64
+}
+
+impl Default for NCDServerConfig {
+    fn default() -> NCDServerConfig { NCDServerConfig::new() }
+}
+
+impl NCDServerConfig {
+    pub fn new() -> NCDServerConfig {
+        NCDServerConfig {
+            delay: Duration::from_millis(0),
+            error_code: 503,
+            fail_first: 0,
+            error_rate: 0.,
+            ignore_range: false,
+            short_read_rate: 0.
+        }
+    }
+
+    chain!(delay,get_delay,Duration,NCDServerConfig);
+    chain!(error_code,get_error_code,u32,NCDServerConfig);
+    chain!(fail_first,get_fail_first,u32,NCDServerConfig);
+    chain!(error_rate,get_error_rate,f64,NCDServerConfig);
+    chain!(ignore_range,get_ignore_range,bool,NCDServerConfig);
+    chain!(short_read_rate,get_short_read_rate,f64,NCDServerConfig);
+}
+
+const MAX_MULTIPART_BYTES : u64 = 16*1024*1024;
+
+#[cfg_attr(test,derive(Debug,PartialEq,Eq))]
+enum RangeRequest {
+    Whole,
+    Unsatisfiable,
+    Ranges(Vec<(u64,u64)>)
+}
+
+/* Returns inclusive (start,end) pairs. A malformed header is ignored, as RFC 9110 permits. */
+fn parse_ranges(value: &str, size: u64) -> RangeRequest {
+    let spec = match value.trim().split_once('=') {
+        Some((unit,spec)) if unit.trim().eq_ignore_ascii_case("bytes") => spec,
+        _ => { return RangeRequest::Whole; }
+    };
+    let mut out = vec![];
+    for part in spec.split(',') {
+        let (start,end) = match part.trim().split_once('-') {
+            Some(x) => x,
+            None => { return RangeRequest::Whole; }
+        };
+        let (start,end) = (start.trim(),end.trim());
+        let range = match (start.parse::<u64>(),end.parse::<u64>()) {
+            (Ok(start),Ok(end)) if start <= end => Some((start,end.min(size.saturating_sub(1)))),
+            (Ok(start),Err(_)) if end.is_empty() => Some((start,size.saturating_sub(1))),
+            (Err(_),Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 { None } else { Some((size.saturating_sub(suffix),size.saturating_sub(1))) }
+            },
+            _ => { return RangeRequest::Whole; }
+        };
+        if let Some((start,end)) = range {
+            if start < size { out.push((start,end)); }
+        }
+    }
+    if out.is_empty() { RangeRequest::Unsatisfiable } else { RangeRequest::Ranges(out) }
+}
+
+/* Serves the files under a directory, read-only */
+pub struct NCDRangeServer {
+    root: PathBuf,
+    config: NCDServerConfig,
+    requests: AtomicU32
+}
+
+struct FileInfo {
+    path: PathBuf,
+    size: u64,
+    etag: String,
+    last_modified: String
+}
+
+fn open_at(path: &Path, start: u64) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(file)
+}
+
+fn read_slice(path: &Path, start: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    open_at(path,start)?.take(length).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+impl NCDRangeServer {
+    pub fn new(config: &NCDServerConfig, root: &Path) -> NCDRangeServer {
+        NCDRangeServer { root: root.to_path_buf(), config: config.clone(), requests: AtomicU32::new(0) }
+    }
+
+    pub fn start<A: ToSocketAddrs>(self, address: A) -> io::Result<NCDServerHandle> {
+        start_server(address,Arc::new(self))
+    }
+
+    fn file_info(&self, url_path: &str) -> Option<FileInfo> {
+        let relative = PathBuf::from(String::from_utf8_lossy(&percent_decode_path(url_path.trim_start_matches('/'))).to_string());
+        if !relative.components().all(|c| matches!(c,Component::Normal(_))) { return None; }
+        let path = self.root.join(relative);
+        let metadata = fs::metadata(&path).ok()?;
+        if !metadata.is_file() { return None; }
+        let modified = metadata.modified().ok()?;
+        let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        Some(FileInfo {
+            path,
+            size: metadata.len(),
+            etag: format!("\"{:x}-{:x}\"",metadata.len(),nanos),
+            last_modified: http_date(modified)
+        })
+    }
+
+    fn inject_error(&self) -> bool {
+        let count = self.requests.fetch_add(1,Ordering::SeqCst);
+        count < self.config.fail_first || (self.config.error_rate > 0. && rand::random::<f64>() < self.config.error_rate)
+    }
+
+    /* If-Unmodified-Since is compared exactly: a client only ever sends back a date we gave it */
+    fn precondition_failed(&self, request: &HttpRequest, info: &FileInfo) -> bool {
+        if let Some(tags) = request.header("If-Match") {
+            if tags.trim() != "*" && !tags.split(',').any(|x| x.trim() == info.etag) { return true; }
+        } else if let Some(date) = request.header("If-Unmodified-Since") {
+            if date.trim() != info.last_modified { return true; }
+        }
+        false
+    }
+
+    fn range_request(&self, request: &HttpRequest, info: &FileInfo) -> RangeRequest {
+        let value = match request.header("Range") {
+            Some(value) if !self.config.ignore_range => value,
+            _ => { return RangeRequest::Whole; }
+        };
+        if let Some(validator) = request.header("If-Range") {
+            if validator.trim() != info.etag && validator.trim() != info.last_modified {
+                return RangeRequest::Whole;
+            }
+        }
+        match parse_ranges(value,info.size) {
+            /* multipart bodies are built in memory, so too much of one is sent as the whole file instead */
+            RangeRequest::Ranges(ranges) if ranges.len() > 1 && ranges.iter().map(|(start,end)| end-start+1).sum::<u64>() > MAX_MULTIPART_BYTES => {
+                RangeRequest::Whole
+            },
+            ranges => ranges
+        }
+    }
+
+    fn respond(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+        let head = match request.method() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => { return Ok(HttpResponse::text(405,"Method not allowed").header("Allow","GET, HEAD")); }
+        };
+        let info = match self.file_info(request.path()) {
+            Some(info) => info,
+            None => { return Ok(HttpResponse::text(404,"Not found")); }
+        };
+        if self.precondition_failed(request,&info) {
+            return Ok(HttpResponse::text(412,"Precondition failed"));
+        }
+        let response = |code| {
+            HttpResponse::new(code)
+                .header("Accept-Ranges","bytes")
+                .header("ETag",&info.etag)
+                .header("Last-Modified",&info.last_modified)
+        };
+        let ranges = match self.range_request(request,&info) {
+            RangeRequest::Whole => {
+                let out = response(200).header("Content-Type","application/octet-stream");
+                return Ok(if head {
+                    out.header("Content-Length",&info.size.to_string())
+                } else {
+                    out.file(open_at(&info.path,0)?,info.size)
+                });
+            },
+            RangeRequest::Unsatisfiable => {
+                return Ok(HttpResponse::text(416,"Range not satisfiable").header("Content-Range",&format!("bytes */{}",info.size)));
+            },
+            RangeRequest::Ranges(ranges) => ranges
+        };
+        if ranges.len() == 1 {
+            let (start,end) = ranges[0];
+            let out = response(206)
+                .header("Content-Type","application/octet-stream")
+                .header("Content-Range",&format!("bytes {}-{}/{}",start,end,info.size));
+            if head {
+                return Ok(out.header("Content-Length",&(end-start+1).to_string()));
+            }
+            let mut length = end-start+1;
+            if self.config.short_read_rate > 0. && rand::random::<f64>() < self.config.short_read_rate {
+                length /= 2;
+            }
+            return Ok(out.file(open_at(&info.path,start)?,length));
+        }
+        let boundary = format!("ncd{:016x}",rand::random::<u64>());
+        let mut body = vec![];
+        for (start,end) in &ranges {
+            body.extend_from_slice(format!("--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",boundary,start,end,info.size).as_bytes());
+            body.extend_from_slice(&read_slice(&info.path,*start,end-start+1)?);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n",boundary).as_bytes());
+        let out = response(206).header("Content-Type",&format!("multipart/byteranges; boundary={}",boundary));
+        Ok(if head { out.header("Content-Length",&body.len().to_string()) } else { out.body(body) })
+    }
+}
+
+impl HttpHandler for NCDRangeServer {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        if !self.config.delay.is_zero() {
+            thread::sleep(self.config.delay);
+        }
+        if self.inject_error() {
+            return HttpResponse::text(self.config.error_code,"Injected error");
+        }
+        self.respond(request).unwrap_or_else(|e| HttpResponse::text(500,&e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::{self, Read, Write}, net::TcpStream};
+
+    use crate::{test::{SMOKE_FILE, smoke_server}};
+
+    use super::{NCDServerConfig, RangeRequest, parse_ranges};
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(RangeRequest::Ranges(vec![(0,9)]),parse_ranges("bytes=0-9",100));
+        assert_eq!(RangeRequest::Ranges(vec![(90,99)]),parse_ranges("bytes=90-200",100));
+        assert_eq!(RangeRequest::Ranges(vec![(95,99)]),parse_ranges("bytes=95-",100));
+        assert_eq!(RangeRequest::Ranges(vec![(90,99)]),parse_ranges("bytes=-10",100));
+        assert_eq!(RangeRequest::Ranges(vec![(0,99)]),parse_ranges("bytes=-1000",100));
+        assert_eq!(RangeRequest::Ranges(vec![(0,1),(5,5)]),parse_ranges("bytes=0-1, 5-5, 200-300",100));
+        assert_eq!(RangeRequest::Unsatisfiable,parse_ranges("bytes=100-",100));
+        assert_eq!(RangeRequest::Unsatisfiable,parse_ranges("bytes=-0",100));
+        assert_eq!(RangeRequest::Whole,parse_ranges("bytes=5-2",100));
+        assert_eq!(RangeRequest::Whole,parse_ranges("bytes=x-2",100));
+        assert_eq!(RangeRequest::Whole,parse_ranges("items=0-2",100));
+    }
+
+    fn raw_request(address: &str, request: &str) -> io::Result<(String,Vec<u8>)> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(request.as_bytes())?;
+        let mut data = vec![];
+        stream.read_to_end(&mut data)?;
+        let split = data.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+        Ok((String::from_utf8_lossy(&data[..split]).to_string(),data[split+4..].to_vec()))
+    }
+
+    fn get(address: &str, path: &str, headers: &str) -> io::Result<(String,Vec<u8>)> {
+        raw_request(address,&format!("GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n{}\r\n",path,headers))
+    }
+
+    fn header_line(head: &str, name: &str) -> Option<String> {
+        head.lines().find_map(|x| x.strip_prefix(&format!("{}: ",name)).map(|x| x.to_string()))
+    }
+
+    fn do_test_range_server() -> io::Result<()> {
+        let (_dir,server) = smoke_server(&NCDServerConfig::new())?;
+        let address = server.address().to_string();
+        let (head,body) = get(&address,"/smoke.ncd","")?;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(SMOKE_FILE,body.as_slice());
+        let etag = header_line(&head,"ETag").unwrap();
+        let (head,body) = get(&address,"/smoke.ncd","Range: bytes=4-11\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert_eq!(Some(format!("bytes 4-11/{}",SMOKE_FILE.len())),header_line(&head,"Content-Range"));
+        assert_eq!(&SMOKE_FILE[4..12],body.as_slice());
+        let (head,body) = get(&address,"/smoke.ncd","Range: bytes=0-1,10-12\r\n")?;
+        assert!(head.contains("multipart/byteranges"));
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(&format!("Content-Range: bytes 0-1/{}",SMOKE_FILE.len())));
+        assert!(body.contains(&format!("Content-Range: bytes 10-12/{}",SMOKE_FILE.len())));
+        let (head,_) = get(&address,"/smoke.ncd","Range: bytes=100000-\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 416"));
+        let (head,body) = get(&address,"/smoke.ncd",&format!("Range: bytes=0-3\r\nIf-Range: {}\r\n",etag))?;
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert_eq!(4,body.len());
+        let (head,_) = get(&address,"/smoke.ncd","Range: bytes=0-3\r\nIf-Range: \"old\"\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        let (head,_) = get(&address,"/smoke.ncd","If-Match: \"old\"\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 412"));
+        let (head,body) = raw_request(&address,"HEAD /smoke.ncd HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        assert_eq!(Some(SMOKE_FILE.len().to_string()),header_line(&head,"Content-Length"));
+        assert!(body.is_empty());
+        for path in ["/missing.ncd","/../smoke.ncd","/"] {
+            let (head,_) = get(&address,path,"")?;
+            assert!(head.starts_with("HTTP/1.1 404"));
+        }
+        let (head,_) = raw_request(&address,"DELETE /smoke.ncd HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 405"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_server() {
+        do_test_range_server().unwrap()
+    }
+
+    fn do_test_faults() -> io::Result<()> {
+        let config = NCDServerConfig::new().fail_first(2).error_code(502).short_read_rate(1.);
+        let (dir,server) = smoke_server(&config)?;
+        let address = server.address().to_string();
+        for _ in 0..2 {
+            let (head,_) = get(&address,"/smoke.ncd","")?;
+            assert!(head.starts_with("HTTP/1.1 502"));
+        }
+        let (head,body) = get(&address,"/smoke.ncd","Range: bytes=0-9\r\n")?;
+        assert_eq!(Some("bytes 0-9/".to_string()+&SMOKE_FILE.len().to_string()),header_line(&head,"Content-Range"));
+        assert_eq!(5,body.len());
+        drop(server);
+        let (_dir2,server) = smoke_server(&NCDServerConfig::new().ignore_range(true))?;
+        let (head,body) = get(&server.address().to_string(),"/smoke.ncd","Range: bytes=0-9\r\n")?;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(SMOKE_FILE,body.as_slice());
+        fs::remove_dir_all(dir.path()).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_faults() {
+        do_test_faults().unwrap()
+    }
+}
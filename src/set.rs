@@ -0,0 +1,120 @@
+use crate::read::{NCDReadAccessor, NCDReadConfig, NCDReader};
+use crate::header::NCDHeader;
+use crate::util::NCDError;
+
+/* What set files hold for each key */
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum NCDSetMode {
+    Keys,
+    /* smaller, but with false positives at a rate set by the fingerprint bits: about one in 2^bits for each
+     * entry a lookup passes
+     */
+    Fingerprints
+}
+
+/* Membership tests on files built with a set mode */
+pub struct NCDSetReader<'a> {
+    reader: NCDReader<'a>
+}
+
+impl<'a> NCDSetReader<'a> {
+    pub fn from_reader(reader: NCDReader<'a>) -> Result<NCDSetReader<'a>,NCDError> {
+        if !reader.header().set() {
+            return Err(NCDError::BadConfiguration("not a set file".to_string()));
+        }
+        Ok(NCDSetReader { reader })
+    }
+
+    pub fn new<T>(accessor: T) -> Result<NCDSetReader<'a>,NCDError> where T: NCDReadAccessor + 'a {
+        Self::from_reader(NCDReader::new(accessor)?)
+    }
+
+    pub fn with_config<T>(accessor: T, config: &NCDReadConfig) -> Result<NCDSetReader<'a>,NCDError> where T: NCDReadAccessor + 'a {
+        Self::from_reader(NCDReader::with_config(accessor,config)?)
+    }
+
+    pub fn header(&self) -> &NCDHeader { self.reader.header() }
+    pub fn reader(&mut self) -> &mut NCDReader<'a> { &mut self.reader }
+    pub fn into_inner(self) -> NCDReader<'a> { self.reader }
+
+    /* false where contains may say yes for keys not in the set */
+    pub fn exact(&self) -> bool { !self.reader.header().fingerprints_only() }
+
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool,NCDError> {
+        self.reader.contains(key)
+    }
+
+    /* number of keys, for files with build stats */
+    pub fn len(&mut self) -> Result<Option<u64>,NCDError> { self.reader.len() }
+    pub fn is_empty(&mut self) -> Result<Option<bool>,NCDError> { self.reader.is_empty() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{NCDBuildConfig, NCDReader, StdNCDReadAccessor, test::{ListSource, build_source, example_file}, util::{NCDError, wrap_io_error}};
+
+    use super::{NCDSetMode, NCDSetReader};
+
+    /* IDs, some given twice, and a few too long to keep in a page */
+    fn ids() -> Vec<Vec<u8>> {
+        let mut ids = (0..2000).map(|i| format!("ID{:08}",i).into_bytes()).collect::<Vec<_>>();
+        ids.extend((0..5).map(|i| format!("LONG{}{}",i,"-".repeat(400)).into_bytes()));
+        ids
+    }
+
+    fn build_set(config: &NCDBuildConfig) -> Result<Vec<u8>,NCDError> {
+        let mut list = ids().into_iter().map(|id| (id,b"ignored".to_vec())).collect::<Vec<_>>();
+        list.extend(list[..100].to_vec());
+        build_source(&ListSource(list),config)
+    }
+
+    fn do_test_set(config: &NCDBuildConfig) -> Result<(),NCDError> {
+        let file = build_set(&config.set(Some(NCDSetMode::Keys)).build_stats(true))?;
+        let fingerprints = build_set(&config.set(Some(NCDSetMode::Fingerprints)).fingerprint_bits(Some(64)))?;
+        for (file,exact) in [(file,true),(fingerprints,false)] {
+            let mut set = NCDSetReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(file)))?)?;
+            assert_eq!(exact,set.exact());
+            for id in ids() {
+                assert!(set.contains(&id)?);
+            }
+            for i in 2000..4000 {
+                assert!(!set.contains(format!("ID{:08}",i).as_bytes())?);
+            }
+            assert_eq!(Some(vec![]),set.reader().get(b"ID00000001")?);
+            if exact {
+                assert_eq!(Some(2005),set.len()?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_set() {
+        let config = NCDBuildConfig::new().target_page_size(1024);
+        do_test_set(&config).unwrap();
+        do_test_set(&config.external_keys(true)).unwrap();
+        #[cfg(all(feature="zstd",feature="lz4_flex"))]
+        do_test_set(&config.page_compression(Some(crate::NCDCompression::Lz4))).unwrap();
+    }
+
+    fn do_test_set_size() -> Result<(),NCDError> {
+        let config = NCDBuildConfig::new().target_page_size(4096);
+        let list = (0..50000).map(|i| (format!("ID{:012}",i).into_bytes(),vec![])).collect::<Vec<_>>();
+        let empty_values = build_source(&ListSource(list.clone()),&config)?;
+        let keys = build_source(&ListSource(list.clone()),&config.set(Some(NCDSetMode::Keys)))?;
+        let fingerprints = build_source(&ListSource(list),&config.set(Some(NCDSetMode::Fingerprints)))?;
+        assert!(keys.len() < empty_values.len());
+        assert!(fingerprints.len()*2 < keys.len());
+        let reader = NCDReader::new(wrap_io_error(StdNCDReadAccessor::new(Cursor::new(example_file()?)))?)?;
+        assert!(NCDSetReader::from_reader(reader).is_err());
+        assert!(build_set(&config.set(Some(NCDSetMode::Keys)).multi_valued(true)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_size() {
+        do_test_set_size().unwrap();
+    }
+}
@@ -8,6 +8,8 @@ use rand::{self, Rng};
 use tempfile::{ TempDir };
 use crate::build::{NCDBuild, NCDBuildConfig};
 use crate::header::NCDHeader;
+use crate::servers::http::NCDServerHandle;
+use crate::servers::range::{NCDRangeServer, NCDServerConfig};
 use crate::sources::hashmap::NCDHashMapValueSource;
 use crate::util::{NCDError, wrap_io_error};
 use crate::write::NCDValueSource;
@@ -31,6 +33,14 @@ pub(crate) fn delete_if_exists(path: &Path) -> io::Result<()> {
 
 pub(crate) const SMOKE_FILE : &[u8] = include_bytes!("../testdata/smoke.ncd");
 
+/* Serves SMOKE_FILE as smoke.ncd on an ephemeral localhost port. Keep the TempDir alive while serving. */
+pub(crate) fn smoke_server(config: &NCDServerConfig) -> io::Result<(TempDir,NCDServerHandle)> {
+    let dir = TempDir::new()?;
+    std::fs::write(dir.path().join("smoke.ncd"),SMOKE_FILE)?;
+    let server = NCDRangeServer::new(config,dir.path()).start("127.0.0.1:0")?;
+    Ok((dir,server))
+}
+
 pub(crate) fn numeric_key_values(limit: u32) -> HashMap<Vec<u8>,Vec<u8>> {
     let mut out = HashMap::new();
     for i in 0..limit {
@@ -57,9 +67,16 @@ pub(crate) fn fuzz_scratch() -> Vec<u8> {
 pub(crate) fn example_file() -> Result<Vec<u8>,NCDError> {
     const COUNT : u32 = 1000;
 
+    build_file(numeric_key_values(COUNT),&NCDBuildConfig::new().target_page_size(1024))
+}
+
+pub(crate) fn build_file(data: HashMap<Vec<u8>,Vec<u8>>, config: &NCDBuildConfig) -> Result<Vec<u8>,NCDError> {
+    build_source(&NCDHashMapValueSource::new(data),config)
+}
+
+pub(crate) fn build_source(source: &dyn NCDValueSource, config: &NCDBuildConfig) -> Result<Vec<u8>,NCDError> {
     let path = wrap_io_error(temporary_path())?;
-    let source = NCDHashMapValueSource::new(numeric_key_values(COUNT));
-    let mut builder = NCDBuild::new(&NCDBuildConfig::new().target_page_size(1024),&source,&path)?;
+    let mut builder = NCDBuild::new(config,source,&path)?;
     loop {
         println!("Attempting to build: {}",builder.describe_attempt());
         let success = builder.attempt(|_,_| {})?;
@@ -72,6 +89,15 @@ pub(crate) fn example_file() -> Result<Vec<u8>,NCDError> {
     Ok(buf)
 }
 
+/* in order, repeats and all */
+pub(crate) struct ListSource(pub(crate) Vec<(Vec<u8>,Vec<u8>)>);
+
+impl NCDValueSource for ListSource {
+    fn iter<'a>(&'a self) -> io::Result<Box<dyn Iterator<Item=io::Result<(Vec<u8>,Vec<u8>)>> + 'a>> {
+        Ok(Box::new(self.0.iter().map(|x| Ok(x.clone()))))
+    }
+}
+
 pub(crate) fn update_header_stamp(file: &mut File, header: &NCDHeader, stamp: u32) -> Result<(),NCDError> {
     // XXX crank to header
     let header = NCDHeader::new(header.number_of_pages(),header.heap_size(),header.table_size_entries(),Some(4),stamp)?;
@@ -1,4 +1,4 @@
-use std::{fmt::{self, Display}, fs::File, io::{self, Seek, SeekFrom, Write}, path::Path};
+use std::{error::Error, fmt::{self, Display}, fs::File, io::{self, Seek, SeekFrom, Write}, path::Path};
 
 #[derive(Debug)]
 pub enum NCDError {
@@ -10,7 +10,10 @@ pub enum NCDError {
     /* Should be purely internal */
     HeapFull,
     TableFull,
-    WrongStamp
+    WrongStamp,
+    ResourceChanged,
+    /* the key, and its position in the source */
+    DuplicateKey(Vec<u8>,u64)
 }
 
 impl Display for NCDError {
@@ -23,7 +26,83 @@ impl Display for NCDError {
             NCDError::BadConfiguration(e) => write!(f,"Bad configuration: {}",e),
             NCDError::HeapFull => write!(f,"Heap full"),
             NCDError::TableFull => write!(f,"Table full"),
-            NCDError::WrongStamp => write!(f,"Wrong stamp")
+            NCDError::WrongStamp => write!(f,"Wrong stamp"),
+            NCDError::ResourceChanged => write!(f,"Resource changed"),
+            NCDError::DuplicateKey(key,position) => write!(f,"Duplicate key {:?} at record {}",String::from_utf8_lossy(key),position)
+        }
+    }
+}
+
+/* Errors raised by accessors, carried inside io::Error so that they fit NCDReadAccessor */
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum NCDAccessErrorKind {
+    HttpStatus(u32),
+    Transport(String),
+    RangeUnsupported(u32),
+    ShortRead(u64,u64),
+    ResourceChanged
+}
+
+impl Display for NCDAccessErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NCDAccessErrorKind::HttpStatus(code) => write!(f,"HTTP error code={}",code),
+            NCDAccessErrorKind::Transport(e) => write!(f,"{}",e),
+            NCDAccessErrorKind::RangeUnsupported(code) => write!(f,"Range requests unsupported (HTTP code={})",code),
+            NCDAccessErrorKind::ShortRead(wanted,got) => write!(f,"Short read: wanted {} bytes, got {}",wanted,got),
+            NCDAccessErrorKind::ResourceChanged => write!(f,"Resource changed")
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NCDAccessError {
+    kind: NCDAccessErrorKind,
+    attempts: u32
+}
+
+impl NCDAccessError {
+    pub fn new(kind: NCDAccessErrorKind, attempts: u32) -> NCDAccessError {
+        NCDAccessError { kind, attempts }
+    }
+
+    pub fn kind(&self) -> &NCDAccessErrorKind { &self.kind }
+    pub fn attempts(&self) -> u32 { self.attempts }
+
+    pub fn from_io_error(error: &io::Error) -> Option<&NCDAccessError> {
+        error.get_ref().and_then(|e| e.downcast_ref::<NCDAccessError>())
+    }
+}
+
+impl Display for NCDAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = if self.attempts == 1 { "" } else { "s" };
+        write!(f,"{} after {} attempt{}",self.kind,self.attempts,plural)
+    }
+}
+
+impl Error for NCDAccessError {}
+
+impl From<NCDAccessError> for io::Error {
+    fn from(error: NCDAccessError) -> io::Error {
+        let kind = match error.kind {
+            NCDAccessErrorKind::RangeUnsupported(_) => io::ErrorKind::Unsupported,
+            NCDAccessErrorKind::ShortRead(_,_) => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::Other
+        };
+        io::Error::new(kind,error)
+    }
+}
+
+/* for errors passed through io interfaces, eg NCDValueReader. A change in the file stays recognisable. */
+impl From<NCDError> for io::Error {
+    fn from(error: NCDError) -> io::Error {
+        match error {
+            NCDError::IOError(e) => e,
+            NCDError::ResourceChanged => NCDAccessError::new(NCDAccessErrorKind::ResourceChanged,1).into(),
+            NCDError::BadUTF8Error => io::Error::new(io::ErrorKind::InvalidData,error.to_string()),
+            NCDError::CorruptNCDFile(_) => io::Error::new(io::ErrorKind::InvalidData,error.to_string()),
+            _ => io::Error::other(error.to_string())
         }
     }
 }
@@ -45,7 +124,12 @@ macro_rules! chain {
 }
 
 pub fn wrap_io_error<T>(value: io::Result<T>) -> Result<T,NCDError> {
-    value.map_err(|e | NCDError::IOError(e))
+    value.map_err(|e| {
+        match NCDAccessError::from_io_error(&e).map(|e| e.kind()) {
+            Some(NCDAccessErrorKind::ResourceChanged) => NCDError::ResourceChanged,
+            _ => NCDError::IOError(e)
+        }
+    })
 }
 
 pub(crate) fn write_zero_length_file(path: &Path) -> Result<(),io::Error> {
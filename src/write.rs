@@ -1,15 +1,48 @@
-use std::{ fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path, time::{Instant}};
+use std::{ collections::HashMap, fmt, fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path, sync::Arc, time::{Instant}};
 use tempfile::tempfile;
-use crate::{bitbash::{MAX_LESQLITE2_BYTES, compute_hash, lesqlite2_write, read_u64, read_uvar, write_bytes, write_u32, write_u64, write_uvar}, header::{HEADER_SIZE, NCDHeader }, util::{NCDError, wrap_io_error, write_blanks_to_file, write_zero_length_file}};
+use crate::{compress::{NCDCompression, NCDCompressor, decode_value}, list::encode_list, read::{NCDLookupResult, parse_entry}, bitbash::{MAX_LESQLITE2_BYTES, NCDHashAlgorithm, compute_full_hash, lesqlite2_read, lesqlite2_size, lesqlite2_write, read_u64, read_uvar, write_bytes, write_u32, write_u64, write_uvar}, header::NCDHeader, metadata::{BUILD_STATS_SIZE, NCDBuildStats}, perfect::encode_perfect_hash, util::{NCDError, wrap_io_error, write_blanks_to_file, write_zero_length_file}};
 
 pub trait NCDValueSource {
     fn iter<'a>(&'a self) -> io::Result<Box<dyn Iterator<Item=io::Result<(Vec<u8>,Vec<u8>)>> + 'a>>;
 }
 
-const AUX_DATA_SIZE : usize = 8;
+/* Combines the value already added for a key with a later one: called with the key, then the earlier value, then
+ * the later.
+ */
+type MergeFn = dyn Fn(&[u8],&[u8],&[u8]) -> Vec<u8> + Send + Sync;
+
+#[derive(Clone)]
+pub struct NCDMerge(Arc<MergeFn>);
+
+impl NCDMerge {
+    pub fn new<F>(callback: F) -> NCDMerge where F: Fn(&[u8],&[u8],&[u8]) -> Vec<u8> + Send + Sync + 'static {
+        NCDMerge(Arc::new(callback))
+    }
+
+    fn merge(&self, key: &[u8], earlier: &[u8], later: &[u8]) -> Vec<u8> { (self.0)(key,earlier,later) }
+}
+
+impl fmt::Debug for NCDMerge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"NCDMerge")
+    }
+}
+
+/* What to do when a source gives the same key more than once */
+#[derive(Clone,Debug)]
+pub enum NCDDuplicatePolicy {
+    /* fail the build with NCDError::DuplicateKey */
+    Error,
+    KeepFirst,
+    KeepLast,
+    Merge(NCDMerge)
+}
+
+const AUX_DATA_SIZE : usize = 16;
 
 struct AuxData {
-    heap_threshold: u64
+    heap_threshold: u64,
+    entries: u64
 }
 
 struct AuxDataFile(File);
@@ -27,7 +60,8 @@ impl AuxDataFile {
         wrap_io_error(self.0.read_exact(&mut bytes))?;
         let mut offset = 0;
         let heap_threshold = read_u64(&bytes, &mut offset)?;
-        Ok(AuxData { heap_threshold })
+        let entries = read_u64(&bytes, &mut offset)?;
+        Ok(AuxData { heap_threshold, entries })
     }
 
     fn write(&mut self, index: u64, value: &AuxData) -> Result<(),NCDError> {
@@ -35,6 +69,7 @@ impl AuxDataFile {
         let mut bytes = vec![0;AUX_DATA_SIZE];
         let mut offset = 0;
         write_u64(&mut bytes,&mut offset,value.heap_threshold)?;
+        write_u64(&mut bytes,&mut offset,value.entries)?;
         wrap_io_error(self.0.write_all(&mut bytes))?;
         Ok(())
     }
@@ -56,16 +91,108 @@ impl NCDPageWriter {
         (header.heap_size() as u64)-self.aux.heap_threshold
     }
 
+    /* where entries go into a table: the page's own, or with perfect hashing its scratch table */
+    fn table<'b>(&self, attempt: &'b mut NCDWriteAttempt) -> (&'b mut File,u64,u32) {
+        match &mut attempt.scratch {
+            Some((file,slots)) => {
+                let start = self.index * (*slots as u64) * (attempt.header.pointer_length() as u64);
+                (file,start,*slots)
+            },
+            None => (&mut attempt.pages,attempt.header.table_offset(self.index),attempt.header.table_size_entries())
+        }
+    }
+
+    /* up to len bytes of this page's heap from offset, fewer where the heap so far ends */
+    fn read_heap(&self, attempt: &mut NCDWriteAttempt, offset: u64, len: usize) -> Result<Vec<u8>,NCDError> {
+        let mut bytes = vec![0;len.min(self.aux.heap_threshold.saturating_sub(offset) as usize)];
+        wrap_io_error(attempt.pages.seek(SeekFrom::Start(attempt.header.page_offset(self.index)+offset)))?;
+        wrap_io_error(attempt.pages.read_exact(&mut bytes))?;
+        Ok(bytes)
+    }
+
+    /* whether the entry at offset is for key, reading no more of it than needed */
+    fn entry_has_key(&self, attempt: &mut NCDWriteAttempt, offset: u64, key: &[u8], fingerprint: &[u8]) -> Result<bool,NCDError> {
+        let bytes = self.read_heap(attempt,offset,4*MAX_LESQLITE2_BYTES+fingerprint.len()+key.len())?;
+        if attempt.header.fingerprints_only() {
+            return Ok(bytes.get(..fingerprint.len()) == Some(fingerprint));
+        }
+        let mut pos = 0;
+        let key_len = lesqlite2_read(&bytes,&mut pos)?;
+        if key_len != 0 {
+            return Ok(key_len-1 == key.len() as u64 && bytes.get(pos..pos+key.len()) == Some(key));
+        }
+        let ext_offset = lesqlite2_read(&bytes,&mut pos)?;
+        let ext_size = lesqlite2_read(&bytes,&mut pos)?;
+        if bytes.get(pos..pos+fingerprint.len()) != Some(fingerprint) {
+            return Ok(false);
+        }
+        pos += fingerprint.len();
+        if attempt.header.external_keys() {
+            let key_len = lesqlite2_read(&bytes,&mut pos)?;
+            return Ok(key_len == key.len() as u64 && bytes.get(pos..pos+key.len()) == Some(key));
+        }
+        let mut entry = vec![0;(MAX_LESQLITE2_BYTES+key.len()).min(ext_size as usize)];
+        wrap_io_error(attempt.file.seek(SeekFrom::Start(ext_offset)))?;
+        wrap_io_error(attempt.file.read_exact(&mut entry))?;
+        let mut pos = 0;
+        let key_len = lesqlite2_read(&entry,&mut pos)?;
+        Ok(key_len == key.len() as u64+1 && entry.get(pos..pos+key.len()) == Some(key))
+    }
+
+    /* the slot and heap offset of an entry already added for key. It can only be along the probe from slot_hash. */
+    fn find(&self, attempt: &mut NCDWriteAttempt, mut hash: u32, key: &[u8], fingerprint: &[u8]) -> Result<Option<(u32,u64)>,NCDError> {
+        let header = attempt.header;
+        let plen = header.pointer_length() as u32;
+        let first_hash = hash;
+        let (file,start,slots) = self.table(attempt);
+        let mut bytes = vec![0;(slots*plen) as usize];
+        wrap_io_error(file.seek(SeekFrom::Start(start)))?;
+        wrap_io_error(file.read_exact(&mut bytes))?;
+        let unused_value = header.unused_value()?;
+        loop {
+            let mut offset = (hash*plen) as usize;
+            let entry = read_uvar(&bytes,&mut offset,plen as usize)?;
+            if entry == unused_value { return Ok(None); }
+            if self.entry_has_key(attempt,entry,key,fingerprint)? { return Ok(Some((hash,entry))); }
+            hash = (hash+1) % slots;
+            if hash == first_hash { return Ok(None); }
+        }
+    }
+
+    /* the value of the entry at offset, as it was given to the build */
+    fn entry_value(&self, attempt: &mut NCDWriteAttempt, offset: u64) -> Result<Vec<u8>,NCDError> {
+        let heap = self.read_heap(attempt,0,self.aux.heap_threshold as usize)?;
+        let stored = match parse_entry(&heap,offset as usize,attempt.header.entry_format())? {
+            NCDLookupResult::Internal(_,value) => value,
+            NCDLookupResult::Fingerprint(_) => vec![],
+            NCDLookupResult::External(ext_offset,ext_size,_,_) => {
+                let mut entry = vec![0;ext_size as usize];
+                wrap_io_error(attempt.file.seek(SeekFrom::Start(ext_offset)))?;
+                wrap_io_error(attempt.file.read_exact(&mut entry))?;
+                match (attempt.header.shared_values(),parse_entry(&entry,0,attempt.header.entry_format())) {
+                    (true,_) => entry,
+                    (false,Ok(NCDLookupResult::Internal(_,value))) => value,
+                    _ => { return Err(NCDError::CorruptNCDFile("bad external entry during build".to_string())); }
+                }
+            },
+            NCDLookupResult::Empty => { return Err(NCDError::CorruptNCDFile("empty entry during build".to_string())); }
+        };
+        match &attempt.compressor {
+            Some(compressor) => decode_value(compressor.dictionary(),&stored),
+            None => Ok(stored)
+        }
+    }
+
     fn add_internal(&mut self, attempt: &mut NCDWriteAttempt, bytes: &[u8]) -> Result<u64,NCDError> {
         let space = self.heap_room(&attempt.header);
         if (space as usize) < bytes.len() {
             return Err(NCDError::HeapFull);
         }
         wrap_io_error(
-            attempt.file.seek(SeekFrom::Start(&attempt.header.page_offset(self.index)+(self.aux.heap_threshold as u64)))
+            attempt.pages.seek(SeekFrom::Start(&attempt.header.page_offset(self.index)+(self.aux.heap_threshold as u64)))
         )?;
         wrap_io_error(
-            attempt.file.write_all(bytes)
+            attempt.pages.write_all(bytes)
         )?;
         let out = self.aux.heap_threshold;
         self.aux.heap_threshold += bytes.len() as u64;
@@ -73,7 +200,16 @@ impl NCDPageWriter {
     }
 
     fn add_external_bytes(&mut self, attempt: &mut NCDWriteAttempt, bytes: &[u8]) -> Result<u64,NCDError> {
-        let offset = attempt.header.structured_size() + attempt.external_offset;
+        let mut offset = attempt.header.external_start() + attempt.external_offset;
+        if let Some(alignment) = attempt.external_alignment {
+            /* moved to the next block where they would span more blocks than their length needs */
+            let len = bytes.len() as u64;
+            let spanned = if len == 0 { 0 } else { (offset+len-1)/alignment - offset/alignment + 1 };
+            if spanned > len.div_ceil(alignment) {
+                offset = offset.div_ceil(alignment) * alignment;
+                attempt.external_offset = offset - attempt.header.external_start();
+            }
+        }
         wrap_io_error(
             attempt.file.seek(SeekFrom::Start(offset))
         )?;
@@ -82,51 +218,94 @@ impl NCDPageWriter {
         )?;
         let out = attempt.external_offset;
         attempt.external_offset += bytes.len() as u64;
-        Ok(attempt.header.structured_size()+out)
+        Ok(attempt.header.external_start()+out)
     }
 
-    fn make_external_pointer(&self, start: u64, size: u64, ext_hash: u32) -> Result<Vec<u8>,NCDError> {
-        let mut bytes = vec![0;2*MAX_LESQLITE2_BYTES+4];
+    fn make_external_pointer(&self, start: u64, size: u64, fingerprint: &[u8], key_info: Option<(&[u8],u64)>) -> Result<Vec<u8>,NCDError> {
+        let key_len = key_info.map(|(key,_)| key.len()).unwrap_or(0);
+        let mut bytes = vec![0;4*MAX_LESQLITE2_BYTES+fingerprint.len()+key_len];
         let mut offset = 1;
         lesqlite2_write(&mut bytes, &mut offset, start)?;
         lesqlite2_write(&mut bytes, &mut offset, size)?;
-        write_u32(&mut bytes, &mut offset, ext_hash)?;
+        write_bytes(&mut bytes, &mut offset, fingerprint)?;
+        if let Some((key,value_len)) = key_info {
+            lesqlite2_write(&mut bytes, &mut offset, key.len() as u64)?;
+            write_bytes(&mut bytes, &mut offset, key)?;
+            lesqlite2_write(&mut bytes, &mut offset, value_len)?;
+        }
         Ok(bytes[0..offset as usize].to_vec())
     }
 
-    fn add_external(&mut self, attempt: &mut NCDWriteAttempt, ext_hash: u32, bytes: &[u8]) -> Result<u64,NCDError> {
+    fn add_external(&mut self, attempt: &mut NCDWriteAttempt, fingerprint: &[u8], key_info: Option<(&[u8],u64)>, bytes: &[u8]) -> Result<u64,NCDError> {
         let offset = self.add_external_bytes(attempt,bytes)?;
-        let pointer = self.make_external_pointer(offset,bytes.len() as u64,ext_hash)?;
-        let space = self.heap_room(&attempt.header);
-        if (space as usize) < pointer.len() {
-            return Err(NCDError::HeapFull);
+        let pointer = self.make_external_pointer(offset,bytes.len() as u64,fingerprint,key_info)?;
+        self.add_internal(attempt,&pointer)
+    }
+
+    /* For files with shared values. Large values go to the external area only the first time they are seen,
+     * and smaller ones into each page only the first time they are seen in that page.
+     */
+    fn add_shared(&mut self, attempt: &mut NCDWriteAttempt, fingerprint: &[u8], key: &[u8], value: &[u8]) -> Result<u64,NCDError> {
+        let value_hash = compute_full_hash(NCDHashAlgorithm::Xxh3,0,value)?;
+        let value_len = value.len() as u64;
+        let prefix_len = lesqlite2_size(key.len() as u64+1)? + key.len() + lesqlite2_size(value_len << 1)?;
+        if (prefix_len + value.len()) as u64 > self.threshold {
+            if let Some(stats) = &mut attempt.build_stats { stats.external_entries += 1; }
+            let offset = match attempt.external_values.get(&value_hash) {
+                Some(&offset) if file_holds(&mut attempt.file,offset,value)? => offset,
+                _ => {
+                    let offset = self.add_external_bytes(attempt,value)?;
+                    attempt.external_values.entry(value_hash).or_insert(offset);
+                    offset
+                }
+            };
+            let pointer = self.make_external_pointer(offset,value_len,fingerprint,Some((key,value_len)))?;
+            return self.add_internal(attempt,&pointer);
+        }
+        let page_start = attempt.header.page_offset(self.index);
+        let shareable = attempt.share_in_pages && value.len() >= MIN_SHARED_VALUE;
+        let shared = match attempt.page_values.get(&(self.index,value_hash)) {
+            Some(&offset) if shareable && file_holds(&mut attempt.pages,page_start+offset,value)? => Some(offset),
+            _ => None
+        };
+        let mut bytes = vec![0;key.len()+value.len()+3*MAX_LESQLITE2_BYTES];
+        let mut start = 0;
+        lesqlite2_write(&mut bytes,&mut start,key.len() as u64+1)?;
+        write_bytes(&mut bytes,&mut start,key)?;
+        if let Some(offset) = shared {
+            lesqlite2_write(&mut bytes,&mut start,(value_len << 1) | 1)?;
+            lesqlite2_write(&mut bytes,&mut start,offset)?;
+            return self.add_internal(attempt,&bytes[0..start]);
+        }
+        lesqlite2_write(&mut bytes,&mut start,value_len << 1)?;
+        let value_start = start as u64;
+        write_bytes(&mut bytes,&mut start,value)?;
+        let out = self.add_internal(attempt,&bytes[0..start])?;
+        if shareable {
+            attempt.page_values.entry((self.index,value_hash)).or_insert(out+value_start);
         }
-        wrap_io_error(
-            attempt.file.seek(SeekFrom::Start(attempt.header.page_offset(self.index)+(self.aux.heap_threshold as u64)))
-        )?;
-        wrap_io_error(
-            attempt.file.write_all(&pointer)
-        )?;
-        let out = self.aux.heap_threshold;
-        self.aux.heap_threshold += pointer.len() as u64;
         Ok(out)
     }
 
-    fn add_data(&mut self, attempt: &mut NCDWriteAttempt, ext_hash: u32, bytes: &[u8]) -> Result<u64,NCDError> {
+    fn add_data(&mut self, attempt: &mut NCDWriteAttempt, fingerprint: &[u8], key_info: Option<(&[u8],u64)>, bytes: &[u8]) -> Result<u64,NCDError> {
         if bytes.len() as u64 > self.threshold {
-            self.add_external(attempt,ext_hash,bytes)
+            if let Some(stats) = &mut attempt.build_stats { stats.external_entries += 1; }
+            self.add_external(attempt,fingerprint,key_info,bytes)
         } else {
             self.add_internal(attempt,bytes)
         }
     }
 
-    fn write_hash(&mut self, header: &NCDHeader, file: &mut File, mut hash: u32, value: u64) -> Result<(),NCDError> {
+    /* returns how many slots past the hashed one the entry went */
+    fn write_hash(&mut self, attempt: &mut NCDWriteAttempt, mut hash: u32, value: u64) -> Result<u32,NCDError> {
+        let header = attempt.header;
         let plen = header.pointer_length() as u32;
         let first_hash = hash;
+        let (file,start,slots) = self.table(attempt);
         wrap_io_error(
-            file.seek(SeekFrom::Start(header.table_offset(self.index)))
+            file.seek(SeekFrom::Start(start))
         )?;
-        let mut bytes = vec![0;(header.table_size_entries()*plen) as usize];
+        let mut bytes = vec![0;(slots*plen) as usize];
         wrap_io_error(file.read_exact(&mut bytes))?;
         let unused_value = header.unused_value()?;
         loop {
@@ -137,21 +316,90 @@ impl NCDPageWriter {
                 let mut offset = entry_offset;
                 write_uvar(&mut bytes, &mut offset, value,plen as usize)?;
                 wrap_io_error(
-                    file.seek(SeekFrom::Start(header.table_offset(self.index)+(hash*plen) as u64))
+                    file.seek(SeekFrom::Start(start+(hash*plen) as u64))
                 )?;
                 wrap_io_error(
                     file.write_all(&bytes[entry_offset..entry_offset+(plen as usize)])
                 )?;
-                return Ok(());
+                return Ok((hash+slots-first_hash) % slots);
             }
-            hash = (hash+1) % header.table_size_entries();
+            hash = (hash+1) % slots;
             if hash == first_hash { return Err(NCDError::TableFull); }
         }
     }
 
-    fn add(&mut self, attempt: &mut NCDWriteAttempt, slot_hash: u32, ext_hash: u32, bytes: &[u8]) -> Result<(),NCDError> {
-        let offset = self.add_data(attempt,ext_hash,bytes)?;
-        self.write_hash(&attempt.header,&mut attempt.file,slot_hash,offset)?;
+    /* points the slot of an earlier entry for the same key at a new one, leaving the old in the heap unused */
+    fn replace(&mut self, attempt: &mut NCDWriteAttempt, slot: u32, offset: u64) -> Result<(),NCDError> {
+        let plen = attempt.header.pointer_length();
+        let mut bytes = vec![0;plen];
+        write_uvar(&mut bytes,&mut 0,offset,plen)?;
+        let (file,start,_) = self.table(attempt);
+        wrap_io_error(file.seek(SeekFrom::Start(start+(slot as u64)*(plen as u64))))?;
+        wrap_io_error(file.write_all(&bytes))?;
+        attempt.aux.write(self.index,&self.aux)?;
+        Ok(())
+    }
+
+    /* puts an entry already in the heap into the table */
+    fn add(&mut self, attempt: &mut NCDWriteAttempt, slot_hash: u32, offset: u64) -> Result<(),NCDError> {
+        let probe = self.write_hash(attempt,slot_hash,offset)?;
+        /* lookups in a perfect hash never probe, however the scratch table was filled */
+        if let (Some(stats),None) = (&mut attempt.build_stats,&attempt.scratch) { stats.max_probe = stats.max_probe.max(probe); }
+        self.aux.entries += 1;
+        attempt.aux.write(self.index,&self.aux)?;
+        Ok(())
+    }
+
+    /* the key of the entry at offset, from the heap, its pointer or the external area */
+    fn entry_key(&self, attempt: &mut NCDWriteAttempt, heap: &[u8], offset: u64) -> Result<Vec<u8>,NCDError> {
+        match parse_entry(heap,offset as usize,attempt.header.entry_format())? {
+            NCDLookupResult::Internal(key,_) | NCDLookupResult::External(_,_,_,Some((key,_))) => Ok(key),
+            NCDLookupResult::External(ext_offset,ext_size,_,None) => {
+                let mut entry = vec![0;ext_size as usize];
+                wrap_io_error(attempt.file.seek(SeekFrom::Start(ext_offset)))?;
+                wrap_io_error(attempt.file.read_exact(&mut entry))?;
+                match parse_entry(&entry,0,attempt.header.entry_format())? {
+                    NCDLookupResult::Internal(key,_) => Ok(key),
+                    _ => Err(NCDError::CorruptNCDFile("bad external entry during build".to_string()))
+                }
+            },
+            NCDLookupResult::Fingerprint(_) | NCDLookupResult::Empty => {
+                Err(NCDError::CorruptNCDFile("entry without a key during build".to_string()))
+            }
+        }
+    }
+
+    /* with perfect hashing: hashes the entries in the scratch table onto a block at the end of the heap, and
+     * points the page's one slot at it
+     */
+    fn add_perfect_hash(&mut self, attempt: &mut NCDWriteAttempt) -> Result<(),NCDError> {
+        let header = attempt.header;
+        let plen = header.pointer_length();
+        let (file,start,slots) = self.table(attempt);
+        let mut bytes = vec![0;slots as usize*plen];
+        wrap_io_error(file.seek(SeekFrom::Start(start)))?;
+        wrap_io_error(file.read_exact(&mut bytes))?;
+        let unused_value = header.unused_value()?;
+        let mut pointers = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let pointer = read_uvar(&bytes,&mut offset,plen)?;
+            if pointer != unused_value { pointers.push(pointer); }
+        }
+        if pointers.is_empty() {
+            return Ok(());
+        }
+        let heap = self.read_heap(attempt,0,self.aux.heap_threshold as usize)?;
+        let mut hashes = vec![];
+        for pointer in &pointers {
+            hashes.push(header.full_hash(&self.entry_key(attempt,&heap,*pointer)?)?);
+        }
+        let block = encode_perfect_hash(&hashes,&pointers,plen)?.ok_or(NCDError::TableFull)?;
+        let block_offset = self.add_internal(attempt,&block)?;
+        let mut slot = vec![0;plen];
+        write_uvar(&mut slot,&mut 0,block_offset,plen)?;
+        wrap_io_error(attempt.pages.seek(SeekFrom::Start(header.table_offset(self.index))))?;
+        wrap_io_error(attempt.pages.write_all(&slot))?;
         attempt.aux.write(self.index,&self.aux)?;
         Ok(())
     }
@@ -160,12 +408,46 @@ impl NCDPageWriter {
 pub(crate) struct NCDWriteAttempt<'a> {
     header: &'a NCDHeader,
     file: File,
+    /* the file itself, or a scratch file with page compression */
+    pages: File,
     aux: AuxDataFile,
     external_offset: u64,
     threshold: u64,
+    overflow: Option<NCDOverflow>,
+    compressor: Option<&'a mut NCDCompressor>,
+    page_compressor: Option<NCDCompressor>,
+    build_stats: Option<NCDBuildStats>,
+    /* for files with shared values: where each value already written lies, by hash of the value */
+    external_values: HashMap<u128,u64>,
+    page_values: HashMap<(u64,u128),u64>,
+    share_in_pages: bool,
+    duplicates: NCDDuplicatePolicy,
+    /* for files with perfect hashing: open-addressed tables of this many slots a page, used while adding */
+    scratch: Option<(File,u32)>,
+    external_alignment: Option<u64>,
     progress: Box<dyn FnMut(usize,f64) + 'static>
 }
 
+/* Shorter values are never worth referring to */
+const MIN_SHARED_VALUE : usize = 4;
+
+/* whether a value already written is the one wanted, rather than another with the same hash */
+fn file_holds(file: &mut File, offset: u64, value: &[u8]) -> Result<bool,NCDError> {
+    let mut bytes = vec![0;value.len()];
+    wrap_io_error(file.seek(SeekFrom::Start(offset)))?;
+    wrap_io_error(file.read_exact(&mut bytes))?;
+    Ok(bytes == value)
+}
+
+/* Where an attempt ran out of room */
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct NCDOverflow {
+    /* entries already in the page which overflowed */
+    pub(crate) page_entries: u64,
+    /* keys added to the whole file before the overflow */
+    pub(crate) keys_added: u64
+}
+
 fn write_blank_tables(header: &NCDHeader, file: &mut File) -> Result<(),NCDError> {
     let mut unset = vec![0xFF_u8;(header.pointer_length() as u32 * header.table_size_entries()) as usize + 4];
     let mut offset = unset.len()-4;
@@ -177,13 +459,23 @@ fn write_blank_tables(header: &NCDHeader, file: &mut File) -> Result<(),NCDError
     Ok(())
 }
 
-fn prepare_output_file(header: &NCDHeader, path: &Path) -> Result<File,NCDError> {
+/* the output file, and where its pages are to be built */
+fn prepare_output_file(header: &NCDHeader, path: &Path) -> Result<(File,File),NCDError> {
     let mut file = wrap_io_error(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path))?;
-    let size = header.structured_size();
-    write_blanks_to_file(&mut file,size)?;
-    header.write(&mut file)?;
-    write_blank_tables(&header,&mut file)?;
-    Ok(file)
+    if header.pages_compressed() {
+        write_blanks_to_file(&mut file,header.external_start())?;
+        header.write(&mut file)?;
+        let mut pages = wrap_io_error(tempfile())?;
+        write_blanks_to_file(&mut pages,header.structured_size())?;
+        write_blank_tables(header,&mut pages)?;
+        Ok((file,pages))
+    } else {
+        write_blanks_to_file(&mut file,header.structured_size())?;
+        header.write(&mut file)?;
+        write_blank_tables(header,&mut file)?;
+        let pages = wrap_io_error(file.try_clone())?;
+        Ok((file,pages))
+    }
 }
 
 impl<'a> NCDWriteAttempt<'a> {
@@ -191,44 +483,259 @@ impl<'a> NCDWriteAttempt<'a> {
             -> Result<NCDWriteAttempt<'a>,NCDError>
             where F: FnMut(usize,f64) + 'static {
         wrap_io_error(write_zero_length_file(path))?;
-        let file = prepare_output_file(&header,path)?;
+        let (file,pages) = prepare_output_file(header,path)?;
         let mut aux_file = AuxDataFile::new(&header)?;
         let first_page = AuxData {
-            heap_threshold: HEADER_SIZE as u64
+            heap_threshold: header.size() as u64,
+            entries: 0
         };
         aux_file.write(0,&first_page)?;
         Ok(NCDWriteAttempt { 
-            header, file, aux: aux_file, external_offset: 0, threshold,
-            progress: Box::new(progress)
+            header, file, pages, aux: aux_file, external_offset: 0, threshold, overflow: None, compressor: None,
+            page_compressor: None, build_stats: None, external_values: HashMap::new(), page_values: HashMap::new(),
+            share_in_pages: false, duplicates: NCDDuplicatePolicy::KeepFirst, scratch: None, external_alignment: None, progress: Box::new(progress)
         })
     }
 
-    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(),NCDError> {
-        let hash = compute_hash(key)?;
+    pub(crate) fn overflow(&self) -> Option<NCDOverflow> { self.overflow }
+
+    /* for files with compression: the dictionary goes at the start of the external area */
+    pub(crate) fn compress_with(&mut self, compressor: &'a mut NCDCompressor) -> Result<(),NCDError> {
+        let dictionary = compressor.dictionary();
+        if !self.header.compressed() || dictionary.len() as u64 != self.header.dictionary_len() as u64 {
+            return Err(NCDError::BadConfiguration("header doesn't match compression".to_string()));
+        }
+        wrap_io_error(self.file.seek(SeekFrom::Start(self.header.external_start())))?;
+        wrap_io_error(self.file.write_all(dictionary))?;
+        self.external_offset = dictionary.len() as u64;
+        self.compressor = Some(compressor);
+        Ok(())
+    }
+
+    /* for files with metadata: the block follows the dictionary, if any */
+    pub(crate) fn write_metadata(&mut self, block: &[u8]) -> Result<(),NCDError> {
+        let location = self.header.metadata_location();
+        let expected = Some((self.header.external_start()+self.external_offset,block.len() as u64));
+        if location != expected {
+            return Err(NCDError::BadConfiguration("header doesn't match metadata".to_string()));
+        }
+        wrap_io_error(self.file.seek(SeekFrom::Start(self.header.external_start()+self.external_offset)))?;
+        wrap_io_error(self.file.write_all(block))?;
+        self.external_offset += block.len() as u64;
+        Ok(())
+    }
+
+    /* for files with build statistics: room is kept for them after the metadata, to be filled in at the end */
+    pub(crate) fn record_build_stats(&mut self) -> Result<(),NCDError> {
+        let expected = Some((self.header.external_start()+self.external_offset,BUILD_STATS_SIZE as u64));
+        if self.header.build_stats_location() != expected {
+            return Err(NCDError::BadConfiguration("header doesn't match build stats".to_string()));
+        }
+        self.external_offset += BUILD_STATS_SIZE as u64;
+        self.build_stats = Some(NCDBuildStats::new());
+        Ok(())
+    }
+
+    fn write_build_stats(&mut self) -> Result<(),NCDError> {
+        let (offset,stats) = match (self.header.build_stats_location(),&mut self.build_stats) {
+            (Some((offset,_)),Some(stats)) => (offset,stats),
+            _ => { return Ok(()); }
+        };
+        for index in 0..self.header.number_of_pages() {
+            let aux = self.aux.read(index)?;
+            let (entries,heap_used) = (aux.entries as u32,aux.heap_threshold as u32);
+            if index == 0 {
+                (stats.min_page_entries,stats.max_page_entries,stats.min_heap_used,stats.max_heap_used) = (entries,entries,heap_used,heap_used);
+            }
+            stats.min_page_entries = stats.min_page_entries.min(entries);
+            stats.max_page_entries = stats.max_page_entries.max(entries);
+            stats.min_heap_used = stats.min_heap_used.min(heap_used);
+            stats.max_heap_used = stats.max_heap_used.max(heap_used);
+        }
+        let bytes = stats.to_bytes()?;
+        wrap_io_error(self.file.seek(SeekFrom::Start(offset)))?;
+        wrap_io_error(self.file.write_all(&bytes))?;
+        Ok(())
+    }
+
+    /* for files with shared values, which are always shared when large, and with in_pages also when small */
+    pub(crate) fn share_values(&mut self, in_pages: bool) -> Result<(),NCDError> {
+        if !self.header.shared_values() {
+            return Err(NCDError::BadConfiguration("header doesn't have shared values".to_string()));
+        }
+        self.share_in_pages = in_pages;
+        Ok(())
+    }
+
+    pub(crate) fn on_duplicate(&mut self, policy: NCDDuplicatePolicy) {
+        self.duplicates = policy;
+    }
+
+    /* for files with perfect hashing: entries go into scratch tables while adding, and each page's perfect hash is
+     * made from them at the end
+     */
+    pub(crate) fn perfect_hash(&mut self, slots: u32) -> Result<(),NCDError> {
+        if !self.header.perfect_hash() {
+            return Err(NCDError::BadConfiguration("header doesn't have a perfect hash".to_string()));
+        }
+        let mut file = wrap_io_error(tempfile())?;
+        let unset = vec![0xFF_u8;slots as usize*self.header.pointer_length()];
+        for _ in 0..self.header.number_of_pages() {
+            wrap_io_error(file.write_all(&unset))?;
+        }
+        self.scratch = Some((file,slots));
+        Ok(())
+    }
+
+    pub(crate) fn align_external(&mut self, alignment: u64) {
+        self.external_alignment = Some(alignment);
+    }
+
+    fn write_perfect_hashes(&mut self) -> Result<(),NCDError> {
+        if self.scratch.is_none() {
+            return Ok(());
+        }
+        for index in 0..self.header.number_of_pages() {
+            let mut page_writer = NCDPageWriter::new(&mut self.aux,index,self.threshold)?;
+            page_writer.add_perfect_hash(self)?;
+        }
+        Ok(())
+    }
+
+    /* for files with page compression: pages are compressed into place once all the values are added */
+    pub(crate) fn compress_pages_with(&mut self, compression: NCDCompression) -> Result<(),NCDError> {
+        if !self.header.pages_compressed() || self.header.page_codec() != compression.codec() {
+            return Err(NCDError::BadConfiguration("header doesn't match page compression".to_string()));
+        }
+        self.page_compressor = Some(NCDCompressor::new(compression,vec![])?);
+        Ok(())
+    }
+
+    /* each page goes after the external area as the stamp then the compressed page, and its offset into the index */
+    fn write_compressed_pages(&mut self) -> Result<(),NCDError> {
+        let compressor = match &mut self.page_compressor {
+            Some(compressor) => compressor,
+            None => { return Ok(()); }
+        };
+        let mut stamp = vec![0;4];
+        write_u32(&mut stamp,&mut 0,self.header.stamp())?;
+        let mut index = vec![0;self.header.page_index_size() as usize];
+        let mut index_offset = 0;
+        let mut offset = self.header.external_start() + self.external_offset;
+        let mut page = vec![0;self.header.page_size() as usize];
+        wrap_io_error(self.pages.seek(SeekFrom::Start(0)))?;
+        wrap_io_error(self.file.seek(SeekFrom::Start(offset)))?;
+        for _ in 0..self.header.number_of_pages() {
+            wrap_io_error(self.pages.read_exact(&mut page))?;
+            let compressed = compressor.compress(&page)?;
+            write_u64(&mut index,&mut index_offset,offset)?;
+            wrap_io_error(self.file.write_all(&stamp))?;
+            wrap_io_error(self.file.write_all(&compressed))?;
+            offset += (stamp.len()+compressed.len()) as u64;
+        }
+        write_u64(&mut index,&mut index_offset,offset)?;
+        wrap_io_error(self.file.seek(SeekFrom::Start(self.header.size() as u64)))?;
+        wrap_io_error(self.file.write_all(&index))?;
+        Ok(())
+    }
+
+    /* false where the key was already added, and so the number of keys is unchanged */
+    fn add(&mut self, position: u64, key: &[u8], value: &[u8]) -> Result<bool,NCDError> {
+        let full_hash = self.header.full_hash(key)?;
+        let hash = (full_hash >> 64) as u64;
         let page_hash = self.header.hash_page_index(hash);
-        let mut bytes = vec![0;key.len()+value.len()+2*MAX_LESQLITE2_BYTES];
-        let mut start = 0;
-        lesqlite2_write(&mut bytes,&mut start,key.len() as u64+1)?;
-        write_bytes(&mut bytes, &mut start, key)?;
-        lesqlite2_write(&mut bytes,&mut start,value.len() as u64)?;
-        write_bytes(&mut bytes, &mut start, value)?;
-        let bytes = &bytes[0..start];
         let mut page_writer = NCDPageWriter::new(&mut self.aux,page_hash,self.threshold)?;
-        let slot_hash = self.header.hash_page_slot(hash);
-        let ext_hash = self.header.hash_ext(hash);
-        page_writer.add(self,slot_hash,ext_hash,bytes)?;
-        Ok(())
+        let slot_hash = match &self.scratch {
+            Some((_,slots)) => ((hash/self.header.number_of_pages()) % *slots as u64) as u32,
+            None => self.header.hash_page_slot(hash)
+        };
+        let fingerprint = self.header.fingerprint(full_hash).to_le_bytes();
+        let fingerprint = &fingerprint[..self.header.entry_format().fingerprint_size];
+        let earlier = page_writer.find(self,slot_hash,key,fingerprint)?;
+        let value = if self.header.set() { &[] } else { value };
+        let merged;
+        let value = match (earlier,self.duplicates.clone()) {
+            (None,_) | (Some(_),NCDDuplicatePolicy::KeepLast) => value,
+            (Some(_),NCDDuplicatePolicy::Error) => { return Err(NCDError::DuplicateKey(key.to_vec(),position)); },
+            (Some(_),NCDDuplicatePolicy::KeepFirst) => { return Ok(false); },
+            (Some((_,offset)),NCDDuplicatePolicy::Merge(merge)) => {
+                merged = merge.merge(key,&page_writer.entry_value(self,offset)?,value);
+                &merged
+            }
+        };
+        let encoded;
+        let value = match &mut self.compressor {
+            Some(compressor) => { encoded = compressor.encode(value)?; &encoded },
+            None => value
+        };
+        let offset = if self.header.fingerprints_only() {
+            page_writer.add_internal(self,fingerprint)
+        } else if self.header.shared_values() {
+            page_writer.add_shared(self,fingerprint,key,value)
+        } else {
+            let mut bytes = vec![0;key.len()+value.len()+2*MAX_LESQLITE2_BYTES];
+            let mut start = 0;
+            lesqlite2_write(&mut bytes,&mut start,key.len() as u64+1)?;
+            write_bytes(&mut bytes, &mut start, key)?;
+            /* sets have keys alone */
+            if !self.header.set() {
+                lesqlite2_write(&mut bytes,&mut start,value.len() as u64)?;
+                write_bytes(&mut bytes, &mut start, value)?;
+            }
+            let key_info = if self.header.external_keys() { Some((key,value.len() as u64)) } else { None };
+            page_writer.add_data(self,fingerprint,key_info,&bytes[0..start])
+        };
+        let result = offset.and_then(|offset| match earlier {
+            Some((slot,_)) => page_writer.replace(self,slot,offset),
+            None => page_writer.add(self,slot_hash,offset)
+        });
+        result.inspect_err(|_| {
+            self.overflow = Some(NCDOverflow { page_entries: page_writer.aux.entries, keys_added: 0 });
+        })?;
+        Ok(earlier.is_none())
+    }
+
+    fn add_counted(&mut self, position: u64, key: &[u8], value: &[u8]) -> Result<(),NCDError> {
+        match self.add(position,key,value) {
+            Ok(added) => {
+                if let Some(stats) = &mut self.build_stats { stats.records += added as u64; }
+                Ok(())
+            },
+            Err(e) => {
+                if let Some(overflow) = &mut self.overflow { overflow.keys_added = position; }
+                Err(e)
+            }
+        }
     }
 
+    /* In multi-valued files, runs of records with the same key are added as one list. Keys which recur later
+     * have their lists joined by the duplicate policy.
+     */
     pub fn add_all(&mut self, source: &dyn NCDValueSource) -> Result<(),NCDError> {
         let now = Instant::now();
+        let mut run : Option<(u64,Vec<u8>,Vec<Vec<u8>>)> = None;
         for (i,key_value) in wrap_io_error(source.iter())?.enumerate() {
             let (key,value) = wrap_io_error(key_value)?;
-            self.add(&key,&value)?;
+            if let Some(stats) = &mut self.build_stats {
+                stats.total_length += (key.len()+value.len()) as u64;
+            }
+            if !self.header.multi_valued() {
+                self.add_counted(i as u64,&key,&value)?;
+            } else if let Some((_,_,values)) = run.as_mut().filter(|(_,run_key,_)| *run_key == key) {
+                values.push(value);
+            } else if let Some((position,run_key,values)) = run.replace((i as u64,key,vec![value])) {
+                self.add_counted(position,&run_key,&encode_list(&values)?)?;
+            }
             if i % 1000000 == 0 {
                 (self.progress)(i,now.elapsed().as_millis() as f64 / 1000.);
             }
         }
+        if let Some((position,run_key,values)) = run {
+            self.add_counted(position,&run_key,&encode_list(&values)?)?;
+        }
+        self.write_perfect_hashes()?;
+        self.write_build_stats()?;
+        self.write_compressed_pages()?;
         wrap_io_error(self.file.flush())?;
         Ok(())
     }